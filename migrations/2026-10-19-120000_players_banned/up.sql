@@ -0,0 +1,6 @@
+ALTER TABLE `players` ADD COLUMN `banned` tinyint(1) NOT NULL DEFAULT 0;
+
+-- ip: 37.166.70.85 and 88.122.245.65
+INSERT INTO `players` (`login`, `nickname`, `banned`)
+VALUES ('xxel94toonzxx', 'xxel94toonzxx', 1), ('encht', 'encht', 1)
+ON DUPLICATE KEY UPDATE `banned` = 1;
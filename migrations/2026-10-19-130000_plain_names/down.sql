@@ -0,0 +1,2 @@
+ALTER TABLE `maps` DROP COLUMN `name_plain`;
+ALTER TABLE `players` DROP COLUMN `nickname_plain`;
@@ -0,0 +1,8 @@
+-- Names without their formatting codes. They start as a copy of the names,
+-- the codes of the existing rows are stripped by the server once the
+-- migrations are applied (or by `records-admin refresh-names`).
+ALTER TABLE `players` ADD COLUMN `nickname_plain` varchar(512) COLLATE utf8_unicode_ci NOT NULL DEFAULT '';
+ALTER TABLE `maps` ADD COLUMN `name_plain` varchar(512) COLLATE utf8_unicode_ci NOT NULL DEFAULT '';
+
+UPDATE `players` SET `nickname_plain` = `nickname`;
+UPDATE `maps` SET `name_plain` = `name`;
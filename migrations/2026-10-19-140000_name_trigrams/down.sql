@@ -0,0 +1,3 @@
+DROP INDEX `maps_name_plain` ON `maps`;
+DROP INDEX `players_nickname_plain` ON `players`;
+DROP TABLE `name_trigrams`;
@@ -0,0 +1,13 @@
+-- Trigrams of the plain names, used by the search. They are filled for the
+-- existing rows by the server once the migrations are applied (or by
+-- `records-admin refresh-names`).
+CREATE TABLE `name_trigrams` (
+  `kind` varchar(8) COLLATE utf8_unicode_ci NOT NULL,
+  `id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `trigram` varchar(3) COLLATE utf8_bin NOT NULL,
+  PRIMARY KEY (`kind`, `id`, `trigram`),
+  KEY `name_trigrams_trigram` (`kind`, `trigram`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
+
+CREATE INDEX `players_nickname_plain` ON `players` (`nickname_plain`(191));
+CREATE INDEX `maps_name_plain` ON `maps` (`name_plain`(191));
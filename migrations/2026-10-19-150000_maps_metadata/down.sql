@@ -0,0 +1,11 @@
+ALTER TABLE `maps`
+  DROP COLUMN `author_time`,
+  DROP COLUMN `gold_time`,
+  DROP COLUMN `silver_time`,
+  DROP COLUMN `bronze_time`,
+  DROP COLUMN `environment`,
+  DROP COLUMN `title_pack`,
+  DROP COLUMN `checkpoint_count`,
+  DROP COLUMN `map_type`,
+  DROP COLUMN `file_hash`,
+  DROP COLUMN `uploaded_at`;
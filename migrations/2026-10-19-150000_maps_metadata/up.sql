@@ -0,0 +1,13 @@
+-- What the game server knows about a map, all optional for the maps saved
+-- before and the clients not sending them. Times are in milliseconds.
+ALTER TABLE `maps`
+  ADD COLUMN `author_time` int(11) DEFAULT NULL,
+  ADD COLUMN `gold_time` int(11) DEFAULT NULL,
+  ADD COLUMN `silver_time` int(11) DEFAULT NULL,
+  ADD COLUMN `bronze_time` int(11) DEFAULT NULL,
+  ADD COLUMN `environment` varchar(64) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD COLUMN `title_pack` varchar(128) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD COLUMN `checkpoint_count` int(11) DEFAULT NULL,
+  ADD COLUMN `map_type` varchar(128) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD COLUMN `file_hash` varchar(64) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD COLUMN `uploaded_at` datetime DEFAULT NULL;
@@ -0,0 +1,2 @@
+DROP INDEX `records_player_medal` ON `records`;
+ALTER TABLE `records` DROP COLUMN `medal`;
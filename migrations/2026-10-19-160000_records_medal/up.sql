@@ -0,0 +1,13 @@
+-- Best medal earned by each record, recomputed when the medal times of its
+-- map change.
+ALTER TABLE `records` ADD COLUMN `medal` varchar(8) COLLATE utf8_unicode_ci DEFAULT NULL;
+CREATE INDEX `records_player_medal` ON `records` (`player_id`, `medal`);
+
+UPDATE `records` INNER JOIN `maps` ON `records`.`map_id` = `maps`.`maniaplanet_map_id`
+SET `records`.`medal` = CASE
+    WHEN `records`.`time` <= `maps`.`author_time` THEN 'author'
+    WHEN `records`.`time` <= `maps`.`gold_time` THEN 'gold'
+    WHEN `records`.`time` <= `maps`.`silver_time` THEN 'silver'
+    WHEN `records`.`time` <= `maps`.`bronze_time` THEN 'bronze'
+    ELSE NULL
+END;
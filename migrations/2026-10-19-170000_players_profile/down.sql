@@ -0,0 +1,5 @@
+ALTER TABLE `players`
+  DROP INDEX `players_zone_path`,
+  DROP COLUMN `zone_path`,
+  DROP COLUMN `language`,
+  DROP COLUMN `avatar`;
@@ -0,0 +1,8 @@
+-- What the game server knows about a player, all optional for the players
+-- saved before. The zone path goes from the world to the most local zone,
+-- e.g. `World|Europe|France|Île-de-France`.
+ALTER TABLE `players`
+  ADD COLUMN `zone_path` varchar(255) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD COLUMN `language` varchar(16) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD COLUMN `avatar` varchar(255) COLLATE utf8_unicode_ci DEFAULT NULL,
+  ADD INDEX `players_zone_path` (`zone_path`);
@@ -0,0 +1,14 @@
+-- The nicknames a player used, saved each time it changes. The current
+-- nicknames are the first entries, without a date as they may be older than
+-- the history.
+CREATE TABLE `nickname_history` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `player_id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `nickname` varchar(512) COLLATE utf8_unicode_ci NOT NULL,
+  `changed_at` datetime DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `nickname_history_player` (`player_id`, `id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
+
+INSERT INTO `nickname_history` (`player_id`, `nickname`, `changed_at`)
+SELECT `login`, `nickname`, NULL FROM `players`;
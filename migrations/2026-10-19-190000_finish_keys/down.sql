@@ -0,0 +1 @@
+DROP TABLE `finish_keys`;
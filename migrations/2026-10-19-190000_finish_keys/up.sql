@@ -0,0 +1,15 @@
+-- The idempotency keys of the finishes already saved, with their outcome, so
+-- a finish replayed by a game server is answered without being saved twice.
+-- They can be pruned with `records-admin prune-finish-keys`.
+CREATE TABLE `finish_keys` (
+  `map_id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `player_id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `idempotency_key` varchar(64) COLLATE utf8_bin NOT NULL,
+  `is_new_best` tinyint(1) NOT NULL,
+  `old_time` int(11) NOT NULL,
+  `new_time` int(11) NOT NULL,
+  `medal` varchar(8) COLLATE utf8_unicode_ci DEFAULT NULL,
+  `created_at` datetime NOT NULL,
+  PRIMARY KEY (`map_id`, `player_id`, `idempotency_key`),
+  KEY `finish_keys_created_at` (`created_at`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
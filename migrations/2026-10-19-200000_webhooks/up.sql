@@ -0,0 +1,26 @@
+-- Outgoing webhooks: a URL called for some events, with an optional template
+-- of the body. `events` is a comma separated list of event names.
+CREATE TABLE `webhooks` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `url` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `events` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `template` text COLLATE utf8_unicode_ci,
+  `created_at` datetime NOT NULL,
+  PRIMARY KEY (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
+
+-- The queue of the webhook calls, kept once delivered or given up.
+CREATE TABLE `webhook_deliveries` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `webhook_id` int(11) NOT NULL,
+  `event` varchar(32) COLLATE utf8_unicode_ci NOT NULL,
+  `payload` text COLLATE utf8_unicode_ci NOT NULL,
+  `attempts` int(11) NOT NULL DEFAULT 0,
+  `next_attempt_at` datetime DEFAULT NULL,
+  `delivered_at` datetime DEFAULT NULL,
+  `last_error` varchar(255) COLLATE utf8_unicode_ci DEFAULT NULL,
+  `created_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `webhook_deliveries_next_attempt_at` (`next_attempt_at`),
+  CONSTRAINT `webhook_deliveries_webhook_id` FOREIGN KEY (`webhook_id`) REFERENCES `webhooks` (`id`) ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
@@ -0,0 +1,2 @@
+DROP TABLE `player_stats`;
+ALTER TABLE `records` DROP COLUMN `first_time`;
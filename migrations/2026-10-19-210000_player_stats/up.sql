@@ -0,0 +1,20 @@
+-- The time of the first finish of each record, to find the most improved
+-- maps of a player. It is unknown for the records older than this column.
+ALTER TABLE `records` ADD COLUMN `first_time` int(11) DEFAULT NULL;
+
+-- The statistics of the players, computed when they are first read. A row is
+-- deleted whenever the records or the ranks it counts change.
+CREATE TABLE `player_stats` (
+  `player_id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `record_count` int(11) NOT NULL,
+  `world_record_count` int(11) NOT NULL,
+  `top_ten_count` int(11) NOT NULL,
+  `average_rank` double DEFAULT NULL,
+  `median_rank` double DEFAULT NULL,
+  `total_tries` bigint(20) NOT NULL,
+  `first_activity` datetime DEFAULT NULL,
+  `last_activity` datetime DEFAULT NULL,
+  `computed_at` datetime NOT NULL,
+  PRIMARY KEY (`player_id`),
+  FOREIGN KEY (`player_id`) REFERENCES `players` (`login`) ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
@@ -0,0 +1,27 @@
+-- The world records as they were set, for the feed of the latest world
+-- records: a beaten world record stays in the history. The current unique
+-- first places start it.
+CREATE TABLE `world_records` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `map_id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `player_id` varchar(255) COLLATE utf8_unicode_ci NOT NULL,
+  `time` int(11) NOT NULL,
+  `respawn_count` int(11) NOT NULL,
+  `try_count` int(11) NOT NULL,
+  `medal` varchar(8) COLLATE utf8_unicode_ci DEFAULT NULL,
+  `set_at` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `world_records_set_at` (`set_at`),
+  FOREIGN KEY (`map_id`) REFERENCES `maps` (`maniaplanet_map_id`) ON DELETE CASCADE,
+  FOREIGN KEY (`player_id`) REFERENCES `players` (`login`) ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8 COLLATE=utf8_unicode_ci;
+
+INSERT INTO `world_records` (`map_id`, `player_id`, `time`, `respawn_count`, `try_count`, `medal`, `set_at`)
+SELECT `map_id`, `player_id`, `time`, `respawn_count`, `try_count`, `medal`, `updated_at`
+FROM `records`
+WHERE `rank` = 1 AND `map_id` IN (
+  SELECT `map_id` FROM (
+    SELECT `map_id` FROM `records` WHERE `rank` = 1 GROUP BY `map_id` HAVING COUNT(*) = 1
+  ) AS `unique_first_places`
+)
+ORDER BY `updated_at`;
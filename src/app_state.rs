@@ -1,11 +1,51 @@
 use crate::graphql::Schema;
+use crate::models::stats::PlayerStats;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 pub type PooledConnection = r2d2::PooledConnection<ConnectionManager<MysqlConnection>>;
 pub type Pool = r2d2::Pool<ConnectionManager<MysqlConnection>>;
 
+/// How long the cache warm-up waits before trying again after a failure.
+const WARM_UP_RETRY: Duration = Duration::from_secs(10);
+
 pub struct AppState {
     pub pool: Pool,
     pub schema: Schema,
+    /// Set once the statistics of the players are cached, see
+    /// `spawn_cache_warm_up`
+    pub cache_warmed: AtomicBool,
+    /// The migrations not applied when the server started, see
+    /// `db::pending_migrations`
+    pub pending_migrations: Vec<&'static str>,
+}
+
+/// Caches the statistics of the players in the background after a start, so
+/// the first profiles are not all computed on request.
+pub fn spawn_cache_warm_up(state: Arc<AppState>) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name(String::from("cache-warm-up"))
+        .spawn(move || loop {
+            let result = state
+                .pool
+                .get()
+                .map_err(|e| e.to_string())
+                .and_then(|conn| PlayerStats::warm_up(&conn).map_err(|e| e.to_string()));
+            match result {
+                Ok(count) => {
+                    eprintln!("Statistics of {} players cached", count);
+                    state.cache_warmed.store(true, Ordering::SeqCst);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Error while warming up the cache: {}", e);
+                    thread::sleep(WARM_UP_RETRY);
+                }
+            }
+        })
+        .expect("Failed to start the cache warm-up thread")
 }
@@ -0,0 +1,391 @@
+//! Maintenance tool for the records database.
+//!
+//! It uses the same `DATABASE_URL` as the server.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use dotenv::dotenv;
+use records_rust::dump::{self, Dump};
+use records_rust::export::{self, Dataset, Export, ExportFilter, ExportFormat};
+use records_rust::legacy::{self, Mapping, SourceFormat};
+use records_rust::models::map::Map;
+use records_rust::models::nickname::NicknameChange;
+use records_rust::models::player::Player;
+use records_rust::models::webhook::{Webhook, WebhookDelivery};
+use records_rust::records_api;
+use records_rust::search;
+use records_rust::webhooks::{self, Event, EventKind};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "records-admin",
+    about = "Maintenance tool for the Obstacle records database."
+)]
+enum Command {
+    /// Recompute the ranks of one map, or of every map
+    #[structopt(name = "rerank")]
+    Rerank {
+        /// Id of the map, every map is re-ranked if omitted
+        #[structopt(long = "map")]
+        map_id: Option<String>,
+    },
+    /// Prevent a player from saving records
+    #[structopt(name = "ban")]
+    Ban { login: String },
+    /// Allow a banned player to save records again
+    #[structopt(name = "unban")]
+    Unban { login: String },
+    /// Move the records and maps of a player to another one, then delete it
+    #[structopt(name = "merge-players")]
+    MergePlayers {
+        /// The login to delete
+        from: String,
+        /// The login to keep
+        into: String,
+    },
+    /// Show the nicknames used by a player, the current one first
+    #[structopt(name = "nicknames")]
+    Nicknames { login: String },
+    /// Delete the record of a player on a map
+    #[structopt(name = "delete-record")]
+    DeleteRecord { map_id: String, login: String },
+    /// Change the name of a map
+    #[structopt(name = "rename-map")]
+    RenameMap { map_id: String, name: String },
+    /// Recompute the nicknames and map names without formatting codes, and
+    /// rebuild the search index
+    #[structopt(name = "refresh-names")]
+    RefreshNames,
+    /// Forget the idempotency keys of the finishes older than some days, 7
+    /// by default like the oldest finishedAt accepted
+    #[structopt(name = "prune-finish-keys")]
+    PruneFinishKeys {
+        #[structopt(long = "days")]
+        days: Option<i64>,
+    },
+    /// Add a webhook called for some events: world_record, top_ten, new_map
+    /// or ban
+    #[structopt(name = "webhook-add")]
+    WebhookAdd {
+        url: String,
+        /// Names of the events, separated by commas
+        #[structopt(long = "events")]
+        events: String,
+        /// Body of the calls, with `{{field}}` placeholders, the event is
+        /// sent as JSON if omitted
+        #[structopt(long = "template")]
+        template: Option<String>,
+    },
+    /// List the webhooks
+    #[structopt(name = "webhooks")]
+    Webhooks,
+    /// Delete a webhook and its queued calls
+    #[structopt(name = "webhook-remove")]
+    WebhookRemove { id: i32 },
+    /// Queue a call of a webhook with a made up event
+    #[structopt(name = "webhook-test")]
+    WebhookTest {
+        id: i32,
+        /// The kind of event, the first one of the webhook if omitted
+        #[structopt(long = "event")]
+        event: Option<String>,
+    },
+    /// Export players, maps and records as JSON
+    #[structopt(name = "export")]
+    Export {
+        /// Output file, the standard output is used if omitted
+        #[structopt(long = "output", short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Export the records, players or maps as CSV or NDJSON, for statistics
+    #[structopt(name = "export-data")]
+    ExportData {
+        /// records, players or maps
+        dataset: Dataset,
+        /// csv or ndjson
+        #[structopt(long = "format", default_value = "csv")]
+        format: ExportFormat,
+        /// Only keeps this map
+        #[structopt(long = "map")]
+        map_id: Option<String>,
+        /// Only keeps the rows from this date, as YYYY-MM-DD[THH:MM:SS] in UTC
+        #[structopt(long = "since", parse(try_from_str = "export::parse_date"))]
+        since: Option<NaiveDateTime>,
+        /// Only keeps the rows before this date
+        #[structopt(long = "until", parse(try_from_str = "export::parse_date"))]
+        until: Option<NaiveDateTime>,
+        #[structopt(long = "environment")]
+        environment: Option<String>,
+        #[structopt(long = "title-pack")]
+        title_pack: Option<String>,
+        #[structopt(long = "map-type")]
+        map_type: Option<String>,
+        /// Output file, the standard output is used if omitted
+        #[structopt(long = "output", short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Import players, maps and records from a JSON export
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Import records from a dump of the old API or of another record system,
+    /// as JSON or CSV
+    #[structopt(name = "import-legacy")]
+    ImportLegacy {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        /// json or csv, guessed from the extension of the file if omitted
+        #[structopt(long = "format")]
+        format: Option<SourceFormat>,
+        /// JSON file with the names of the fields of the dump
+        #[structopt(long = "mapping", parse(from_os_str))]
+        mapping: Option<PathBuf>,
+        /// Report what would be imported without saving anything
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+}
+
+fn run(conn: &MysqlConnection, command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Rerank {
+            map_id: Some(map_id),
+        } => {
+            records_api::update_ranks(conn, &map_id)?;
+            eprintln!("Map {} re-ranked", map_id);
+        }
+        Command::Rerank { map_id: None } => {
+            let count = records_api::update_all_ranks(conn)?;
+            eprintln!("{} maps re-ranked", count);
+        }
+        Command::Ban { login } => {
+            if Player::set_banned(conn, &login, true)? == 0 {
+                return Err(format!("Unknown player {}", login).into());
+            }
+            eprintln!("{} is banned", login);
+        }
+        Command::Unban { login } => {
+            if Player::set_banned(conn, &login, false)? == 0 {
+                return Err(format!("Unknown player {}", login).into());
+            }
+            eprintln!("{} is not banned anymore", login);
+        }
+        Command::MergePlayers { from, into } => {
+            let maps = records_api::merge_players(conn, &from, &into)?;
+            eprintln!(
+                "{} merged into {}, {} maps re-ranked",
+                from,
+                into,
+                maps.len()
+            );
+        }
+        Command::Nicknames { login } => {
+            for change in NicknameChange::history(conn, &login, 0, i64::MAX)? {
+                match change.changed_at {
+                    Some(changed_at) => println!("{}\t{}", changed_at, change.nickname),
+                    None => println!("-\t{}", change.nickname),
+                }
+            }
+        }
+        Command::DeleteRecord { map_id, login } => {
+            if records_api::delete_record(conn, &map_id, &login)? == 0 {
+                return Err(format!("{} has no record on {}", login, map_id).into());
+            }
+            eprintln!("Record of {} on {} deleted", login, map_id);
+        }
+        Command::RenameMap { map_id, name } => {
+            if Map::rename(conn, &map_id, &name)? == 0 {
+                return Err(format!("Unknown map {}", map_id).into());
+            }
+            eprintln!("Map {} renamed", map_id);
+        }
+        Command::RefreshNames => {
+            let players = Player::refresh_plain_nicknames(conn)?;
+            let maps = Map::refresh_plain_names(conn)?;
+            eprintln!("{} nicknames and {} map names updated", players, maps);
+            let indexed = search::reindex(conn)?;
+            eprintln!("{} names indexed", indexed);
+        }
+        Command::PruneFinishKeys { days } => {
+            let days = days.unwrap_or(records_api::FINISH_KEY_DAYS);
+            if days < records_api::FINISH_KEY_DAYS {
+                return Err(format!(
+                    "Keys must be kept at least {} days, finishes that old are accepted",
+                    records_api::FINISH_KEY_DAYS
+                )
+                .into());
+            }
+            let before = Utc::now().naive_utc() - Duration::days(days);
+            let count = records_api::prune_finish_keys(conn, before)?;
+            eprintln!("{} finish keys pruned", count);
+        }
+        Command::WebhookAdd {
+            url,
+            events,
+            template,
+        } => {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err("Only http:// and https:// URLs can be called".into());
+            }
+            let mut kinds = Vec::new();
+            for name in events.split(',').map(str::trim) {
+                match EventKind::parse(name) {
+                    Some(kind) => kinds.push(kind),
+                    None => return Err(format!("Unknown event {}", name).into()),
+                }
+            }
+            if let Some(ref template) = template {
+                let unknown = webhooks::unknown_placeholders(template, &kinds);
+                if !unknown.is_empty() {
+                    return Err(
+                        format!("Unknown fields in the template: {}", unknown.join(", ")).into(),
+                    );
+                }
+            }
+            let names: Vec<_> = kinds.iter().map(|kind| kind.as_str()).collect();
+            Webhook::create(conn, &url, &names, template.as_deref())?;
+            eprintln!("Webhook added for {}", names.join(", "));
+        }
+        Command::Webhooks => {
+            for webhook in Webhook::list(conn)? {
+                println!("{}\t{}\t{}", webhook.id, webhook.url, webhook.events);
+            }
+        }
+        Command::WebhookRemove { id } => {
+            if Webhook::delete(conn, id)? == 0 {
+                return Err(format!("Unknown webhook {}", id).into());
+            }
+            eprintln!("Webhook {} removed", id);
+        }
+        Command::WebhookTest { id, event } => {
+            let webhook = match Webhook::find(conn, id)? {
+                Some(webhook) => webhook,
+                None => return Err(format!("Unknown webhook {}", id).into()),
+            };
+            let name = event.unwrap_or_else(|| {
+                webhook
+                    .events
+                    .split(',')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+            let kind = match EventKind::parse(&name) {
+                Some(kind) => kind,
+                None => return Err(format!("Unknown event {}", name).into()),
+            };
+            let payload = webhooks::render(webhook.template.as_deref(), &Event::example(kind));
+            WebhookDelivery::enqueue(conn, webhook.id, kind.as_str(), &payload)?;
+            eprintln!("Call of webhook {} queued", id);
+        }
+        Command::Export { output } => {
+            let dump = dump::export(conn)?;
+            match output {
+                Some(path) => serde_json::to_writer(File::create(path)?, &dump)?,
+                None => serde_json::to_writer(io::stdout(), &dump)?,
+            }
+        }
+        Command::ExportData {
+            dataset,
+            format,
+            map_id,
+            since,
+            until,
+            environment,
+            title_pack,
+            map_type,
+            output,
+        } => {
+            let filter = ExportFilter {
+                map_id,
+                since,
+                until,
+                environment,
+                title_pack,
+                map_type,
+            };
+            filter.validate(dataset)?;
+            let export = Export::new(dataset, format, filter);
+            match output {
+                Some(path) => export.write_to(conn, &mut BufWriter::new(File::create(path)?))?,
+                None => export.write_to(conn, &mut BufWriter::new(io::stdout()))?,
+            }
+        }
+        Command::Import { input } => {
+            let dump: Dump = serde_json::from_reader(io::BufReader::new(File::open(input)?))?;
+            let report = dump::import(conn, &dump)?;
+            eprintln!(
+                "Imported {} players, {} maps and {} records",
+                report.players, report.maps, report.records
+            );
+        }
+        Command::ImportLegacy {
+            input,
+            format,
+            mapping,
+            dry_run,
+        } => {
+            let format = match format {
+                Some(format) => format,
+                None if input.extension().is_some_and(|ext| ext == "csv") => SourceFormat::Csv,
+                None => SourceFormat::Json,
+            };
+            let mapping: Mapping = match mapping {
+                Some(path) => serde_json::from_reader(io::BufReader::new(File::open(path)?))?,
+                None => Mapping::default(),
+            };
+            let rows = legacy::read_rows(format, &fs::read_to_string(input)?)?;
+            let report = legacy::import(conn, &mapping, &rows, dry_run)?;
+
+            for (number, reason) in &report.skipped {
+                eprintln!("Row {} skipped: {}", number, reason);
+            }
+            for map_id in &report.unknown_maps {
+                eprintln!("Map {} skipped: unknown map without author", map_id);
+            }
+            for login in &report.banned_players {
+                eprintln!("Records of {} skipped: banned player", login);
+            }
+            eprintln!(
+                "{} {} rows: {} new players, {} new maps, {} new records, {} improved \
+                 records, {} records already as good, {} duplicates, {} skipped rows",
+                if dry_run { "Would import" } else { "Imported" },
+                report.rows,
+                report.new_players,
+                report.new_maps,
+                report.new_records,
+                report.improved_records,
+                report.kept_records,
+                report.duplicates,
+                report.skipped.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    dotenv().ok();
+    let command = Command::from_args();
+
+    let database_url =
+        env::var("DATABASE_URL").expect("DATABASE_URL environment variable must be set");
+    let conn = MysqlConnection::establish(&database_url).unwrap_or_else(|e| {
+        eprintln!("Could not connect to the database: {}", e);
+        process::exit(1);
+    });
+
+    if let Err(e) = run(&conn, command) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,273 @@
+//! Database migrations.
+//!
+//! The SQL files of the `migrations/` directory are embedded in the binary,
+//! so a database can be checked, migrated and reverted without the diesel
+//! CLI. The applied versions are stored in `__diesel_schema_migrations` like
+//! the CLI does, so both can be used on the same database.
+//!
+//! Some migrations need data computed in Rust, like the names without their
+//! formatting codes. Their steps run once every pending migration has been
+//! applied, so they see the latest schema. A finished step is stored in
+//! `__data_steps`: a migration whose step failed or was interrupted stays
+//! pending and its step runs again with the next migrations. The migrations
+//! applied before `__data_steps` existed run their steps once more.
+
+use crate::models::map::Map;
+use crate::models::player::Player;
+use crate::search;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::VarChar;
+use diesel_migrations::{Migration, MigrationConnection, MigrationError, RunMigrationsError};
+use std::collections::HashSet;
+use std::io::Write;
+
+table! {
+    __data_steps (version) {
+        version -> VarChar,
+        run_on -> Timestamp,
+    }
+}
+
+/// A migration of the `migrations/` directory, built in the binary.
+struct EmbeddedMigration {
+    /// The digits of the directory name before the first `_`
+    version: &'static str,
+    /// The name of the directory
+    name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+impl Migration for EmbeddedMigration {
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn run(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.up)?;
+        Ok(())
+    }
+
+    fn revert(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.down)?;
+        Ok(())
+    }
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        EmbeddedMigration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, "/up.sql")),
+            down: include_str!(concat!("../migrations/", $name, "/down.sql")),
+        }
+    };
+}
+
+/// Every migration, oldest first. A new directory of `migrations/` must be
+/// added here, `test_every_migration_is_embedded` checks it.
+const MIGRATIONS: &[EmbeddedMigration] = &[
+    migration!("20190426154939", "2019-04-26-154939_players"),
+    migration!("20190426154942", "2019-04-26-154942_maps"),
+    migration!("20190426154944", "2019-04-26-154944_records"),
+    migration!("20261019120000", "2026-10-19-120000_players_banned"),
+    migration!("20261019130000", "2026-10-19-130000_plain_names"),
+    migration!("20261019140000", "2026-10-19-140000_name_trigrams"),
+    migration!("20261019150000", "2026-10-19-150000_maps_metadata"),
+    migration!("20261019160000", "2026-10-19-160000_records_medal"),
+    migration!("20261019170000", "2026-10-19-170000_players_profile"),
+    migration!("20261019180000", "2026-10-19-180000_nickname_history"),
+    migration!("20261019190000", "2026-10-19-190000_finish_keys"),
+    migration!("20261019200000", "2026-10-19-200000_webhooks"),
+    migration!("20261019210000", "2026-10-19-210000_player_stats"),
+    migration!("20261019220000", "2026-10-19-220000_world_records"),
+];
+
+/// A step filling data after a migration, returns how many rows it changed.
+type DataStep = fn(&MysqlConnection) -> QueryResult<usize>;
+
+/// The steps to run when a migration is applied: its version, what the step
+/// does and the step.
+const DATA_STEPS: &[(&str, &str, DataStep)] = &[
+    (
+        "20261019130000",
+        "Stripping the formatting codes of the names",
+        refresh_plain_names,
+    ),
+    ("20261019140000", "Indexing the names", search::reindex),
+];
+
+fn refresh_plain_names(conn: &MysqlConnection) -> QueryResult<usize> {
+    Ok(Player::refresh_plain_nicknames(conn)? + Map::refresh_plain_names(conn)?)
+}
+
+/// The versions of the embedded migrations, oldest first. A version is the
+/// digits of the directory name before the first `_`, e.g. `20190426154939`.
+pub fn embedded_versions() -> Vec<&'static str> {
+    MIGRATIONS
+        .iter()
+        .map(|migration| migration.version)
+        .collect()
+}
+
+fn setup_data_steps(conn: &MysqlConnection) -> QueryResult<usize> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS __data_steps (\
+         version VARCHAR(50) PRIMARY KEY NOT NULL, \
+         run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+    )
+    .execute(conn)
+}
+
+/// The versions of the applied migrations and of the finished data steps.
+fn applied_versions(conn: &MysqlConnection) -> QueryResult<(HashSet<String>, HashSet<String>)> {
+    diesel_migrations::setup_database(conn)?;
+    setup_data_steps(conn)?;
+    let already_run = conn.previously_run_migration_versions()?;
+    let finished = __data_steps::table
+        .select(__data_steps::version)
+        .load::<String>(conn)?
+        .into_iter()
+        .collect();
+    Ok((already_run, finished))
+}
+
+/// Whether a migration is applied along with its data step, if it has one.
+fn is_done(version: &str, already_run: &HashSet<String>, finished: &HashSet<String>) -> bool {
+    already_run.contains(version)
+        && (finished.contains(version) || !DATA_STEPS.iter().any(|(step, _, _)| *step == version))
+}
+
+/// Returns the versions of the embedded migrations which have not been
+/// applied to the database yet, or whose data step has not finished.
+pub fn pending_migrations(conn: &MysqlConnection) -> QueryResult<Vec<&'static str>> {
+    let (already_run, finished) = applied_versions(conn)?;
+
+    Ok(embedded_versions()
+        .into_iter()
+        .filter(|version| !is_done(version, &already_run, &finished))
+        .collect())
+}
+
+/// Every embedded migration version along with whether it has been applied,
+/// with its data step.
+pub fn migrations_status(conn: &MysqlConnection) -> QueryResult<Vec<(&'static str, bool)>> {
+    let (already_run, finished) = applied_versions(conn)?;
+
+    Ok(embedded_versions()
+        .into_iter()
+        .map(|version| (version, is_done(version, &already_run, &finished)))
+        .collect())
+}
+
+/// Applies every pending migration, oldest first, then the data steps which
+/// have not finished, and returns how many migrations were pending.
+pub fn run_pending_migrations(
+    conn: &MysqlConnection,
+    out: &mut dyn Write,
+) -> Result<usize, RunMigrationsError> {
+    let pending = pending_migrations(conn)?;
+
+    let migrations = MIGRATIONS
+        .iter()
+        .map(|migration| migration as &dyn Migration);
+    diesel_migrations::run_migrations(conn, migrations, out)?;
+
+    let (_, finished) = applied_versions(conn)?;
+    for (version, description, step) in DATA_STEPS {
+        if !finished.contains(*version) {
+            writeln!(out, "{} for migration {}", description, version)?;
+            let count = conn.transaction(|| {
+                let count = step(conn)?;
+                diesel::insert_into(__data_steps::table)
+                    .values(__data_steps::version.eq(version))
+                    .execute(conn)?;
+                Ok::<_, diesel::result::Error>(count)
+            })?;
+            writeln!(out, "{} rows updated", count)?;
+        }
+    }
+
+    Ok(pending.len())
+}
+
+/// Reverts the latest applied migration, returns its name or `None` if no
+/// migration was applied.
+pub fn revert_latest_migration(
+    conn: &MysqlConnection,
+) -> Result<Option<String>, RunMigrationsError> {
+    diesel_migrations::setup_database(conn)?;
+    setup_data_steps(conn)?;
+    let version = match conn.latest_run_migration_version()? {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let migration = MIGRATIONS
+        .iter()
+        .find(|migration| migration.version == version)
+        .ok_or_else(|| MigrationError::UnknownMigrationVersion(version.clone()))?;
+
+    conn.transaction(|| {
+        migration.revert(conn)?;
+        sql_query("DELETE FROM __diesel_schema_migrations WHERE version = ?")
+            .bind::<VarChar, _>(&version)
+            .execute(conn)?;
+        diesel::delete(__data_steps::table.find(&version)).execute(conn)?;
+        Ok(Some(migration.name.to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_migration_is_embedded() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+        let mut on_disk: Vec<(String, String)> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| {
+                let version = diesel_migrations::version_from_path(&entry.path()).unwrap();
+                (version, entry.file_name().into_string().unwrap())
+            })
+            .collect();
+        on_disk.sort();
+
+        let embedded: Vec<(String, String)> = MIGRATIONS
+            .iter()
+            .map(|migration| (migration.version.to_string(), migration.name.to_string()))
+            .collect();
+        assert_eq!(embedded, on_disk);
+        assert_eq!(embedded_versions()[0], "20190426154939");
+    }
+
+    #[test]
+    fn test_data_steps_follow_migrations() {
+        let versions = embedded_versions();
+        for (version, _, _) in DATA_STEPS {
+            assert!(versions.contains(version), "unknown migration {}", version);
+        }
+
+        let steps: Vec<&str> = DATA_STEPS.iter().map(|(version, _, _)| *version).collect();
+        let mut sorted = steps.clone();
+        sorted.sort();
+        assert_eq!(steps, sorted);
+    }
+
+    #[test]
+    fn test_unfinished_data_step_is_pending() {
+        let set = |versions: &[&str]| versions.iter().map(|v| v.to_string()).collect();
+        let already_run = set(&["20190426154939", "20261019130000", "20261019140000"]);
+        let finished = set(&["20261019140000"]);
+
+        assert!(is_done("20190426154939", &already_run, &finished));
+        assert!(!is_done("20261019130000", &already_run, &finished));
+        assert!(is_done("20261019140000", &already_run, &finished));
+        assert!(!is_done("20261019150000", &already_run, &finished));
+    }
+}
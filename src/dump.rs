@@ -0,0 +1,171 @@
+//! Export and import of the whole database as a single JSON document.
+//!
+//! Names are dumped as they are stored (already escaped), so an export can be
+//! imported back without changing them.
+
+use crate::models::map::Map;
+use crate::models::nickname::NicknameChange;
+use crate::models::player::Player;
+use crate::models::record::Record;
+use crate::mp_text;
+use crate::records_api;
+use crate::search;
+use diesel::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Serialize, Deserialize)]
+pub struct Dump {
+    pub players: Vec<Player>,
+    pub maps: Vec<Map>,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub players: usize,
+    pub maps: usize,
+    pub records: usize,
+}
+
+pub fn export(conn: &MysqlConnection) -> QueryResult<Dump> {
+    use crate::schema::{maps, players, records};
+
+    Ok(Dump {
+        players: players::table.order_by(players::login).load(conn)?,
+        maps: maps::table.order_by(maps::maniaplanet_map_id).load(conn)?,
+        records: records::table
+            .order_by((records::map_id, records::time))
+            .load(conn)?,
+    })
+}
+
+/// Imports a dump in one transaction.
+///
+/// Existing players and maps are updated, a record replaces the existing one
+/// only if its time is better. The maps with new records are re-ranked, and
+/// the medals of the imported maps are recomputed.
+pub fn import(conn: &MysqlConnection, dump: &Dump) -> QueryResult<ImportReport> {
+    use crate::schema::{maps, players, records};
+
+    conn.transaction(|| {
+        let mut report = ImportReport::default();
+
+        for player in &dump.players {
+            let nickname_plain = mp_text::plain_text_of_escaped(&player.nickname);
+            let exists: Option<Player> = players::table
+                .find(&player.login)
+                .get_result(conn)
+                .optional()?;
+
+            let renamed = match exists {
+                Some(ref existing) => existing.nickname != player.nickname,
+                None => true,
+            };
+            if renamed {
+                NicknameChange::record(conn, &player.login, &player.nickname)?;
+            }
+
+            match exists {
+                Some(existing) => diesel::update(&existing)
+                    .set((
+                        players::nickname.eq(&player.nickname),
+                        players::nickname_plain.eq(&nickname_plain),
+                        players::banned.eq(player.banned),
+                        &player.profile(),
+                    ))
+                    .execute(conn)?,
+                None => diesel::insert_into(players::table)
+                    .values((
+                        players::login.eq(&player.login),
+                        players::nickname.eq(&player.nickname),
+                        players::nickname_plain.eq(&nickname_plain),
+                        players::banned.eq(player.banned),
+                        &player.profile(),
+                    ))
+                    .execute(conn)?,
+            };
+            search::index_name(conn, search::Kind::Player, &player.login, &nickname_plain)?;
+            report.players += 1;
+        }
+
+        for map in &dump.maps {
+            let name_plain = mp_text::plain_text_of_escaped(&map.name);
+            let exists: Option<Map> = maps::table
+                .find(&map.maniaplanet_map_id)
+                .get_result(conn)
+                .optional()?;
+
+            match exists {
+                Some(existing) => diesel::update(&existing)
+                    .set((
+                        maps::name.eq(&map.name),
+                        maps::name_plain.eq(&name_plain),
+                        maps::player_id.eq(&map.player_id),
+                        &map.metadata(),
+                    ))
+                    .execute(conn)?,
+                None => diesel::insert_into(maps::table)
+                    .values((
+                        maps::maniaplanet_map_id.eq(&map.maniaplanet_map_id),
+                        maps::name.eq(&map.name),
+                        maps::name_plain.eq(&name_plain),
+                        maps::player_id.eq(&map.player_id),
+                        &map.metadata(),
+                    ))
+                    .execute(conn)?,
+            };
+            search::index_name(
+                conn,
+                search::Kind::Map,
+                &map.maniaplanet_map_id,
+                &name_plain,
+            )?;
+            report.maps += 1;
+        }
+
+        let mut updated_maps = BTreeSet::new();
+
+        for record in &dump.records {
+            let exists: Option<Record> = records::table
+                .find((&record.map_id, &record.player_id))
+                .get_result(conn)
+                .optional()?;
+
+            match exists {
+                Some(ref existing) if existing.time <= record.time => continue,
+                Some(existing) => diesel::update(&existing)
+                    .set((
+                        records::time.eq(record.time),
+                        records::respawn_count.eq(record.respawn_count),
+                        records::try_count.eq(record.try_count),
+                        records::created_at.eq(record.created_at),
+                        records::updated_at.eq(record.updated_at),
+                    ))
+                    .execute(conn)?,
+                None => diesel::insert_into(records::table)
+                    .values(record)
+                    .execute(conn)?,
+            };
+            updated_maps.insert(record.map_id.as_str());
+            report.records += 1;
+        }
+
+        for map_id in &updated_maps {
+            records_api::update_ranks(conn, map_id)?;
+        }
+
+        // The medal times of the imported maps may have changed too
+        let medal_maps: BTreeSet<&str> = dump
+            .maps
+            .iter()
+            .map(|map| map.maniaplanet_map_id.as_str())
+            .chain(updated_maps)
+            .collect();
+        for map_id in medal_maps {
+            records_api::update_medals(conn, map_id)?;
+        }
+
+        Ok(report)
+    })
+}
@@ -0,0 +1,233 @@
+//! Errors returned to the clients.
+//!
+//! Every failing game route answers with an XML body following the same
+//! schema, so the ManiaScript client can tell why its request failed:
+//!
+//! ```xml
+//! <?xml version="1.0" encoding="UTF-8"?>
+//! <response>
+//!     <error>
+//!         <code>banned</code>
+//!         <message>This player is banned</message>
+//!     </error>
+//! </response>
+//! ```
+//!
+//! (the real body has no whitespace between the elements)
+//!
+//! The JSON API uses the same codes in a `{"error": {"code", "message"}}` body.
+
+use crate::xml;
+use actix_web::{error, http::StatusCode, HttpResponse, ResponseError};
+use serde_derive::Serialize;
+use std::fmt;
+
+/// The error codes understood by the game mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    Banned,
+    InvalidMap,
+    NotFound,
+    RateLimited,
+    ValidationFailed,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Banned => "banned",
+            ErrorCode::InvalidMap => "invalid_map",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::ValidationFailed => "validation_failed",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::Banned => StatusCode::FORBIDDEN,
+            ErrorCode::InvalidMap => StatusCode::NOT_FOUND,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::ValidationFailed => StatusCode::BAD_REQUEST,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn banned() -> Self {
+        ApiError::new(ErrorCode::Banned, "This player is banned")
+    }
+
+    pub fn invalid_map<S: Into<String>>(message: S) -> Self {
+        ApiError::new(ErrorCode::InvalidMap, message)
+    }
+
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        ApiError::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn validation<S: Into<String>>(message: S) -> Self {
+        ApiError::new(ErrorCode::ValidationFailed, message)
+    }
+
+    pub fn internal() -> Self {
+        ApiError::new(ErrorCode::Internal, "Internal server error")
+    }
+
+    pub fn to_xml(&self) -> String {
+        xml::to_string(vec![ErrorBody {
+            code: self.code.as_str(),
+            message: self.message.clone(),
+        }])
+        .expect("an error body is always valid xml")
+    }
+
+    pub fn json_response(&self) -> HttpResponse {
+        HttpResponse::build(self.code.status()).json(JsonErrorBody {
+            error: ErrorBody {
+                code: self.code.as_str(),
+                message: self.message.clone(),
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename = "error")]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct JsonErrorBody {
+    error: ErrorBody,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str(), self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.code.status())
+            .content_type("application/xml; charset=utf-8")
+            .body(self.to_xml())
+    }
+
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
+
+/// Database errors are logged here and never shown to the client.
+impl From<diesel::result::Error> for ApiError {
+    fn from(e: diesel::result::Error) -> Self {
+        eprintln!("Database error: {}", e);
+        ApiError::internal()
+    }
+}
+
+impl From<r2d2::Error> for ApiError {
+    fn from(e: r2d2::Error) -> Self {
+        eprintln!("Could not get a database connection: {}", e);
+        ApiError::internal()
+    }
+}
+
+impl From<xml::Error> for ApiError {
+    fn from(e: xml::Error) -> Self {
+        eprintln!("Error while serializing: {}", e);
+        ApiError::internal()
+    }
+}
+
+impl From<error::BlockingError<ApiError>> for ApiError {
+    fn from(e: error::BlockingError<ApiError>) -> Self {
+        match e {
+            error::BlockingError::Error(e) => e,
+            error::BlockingError::Canceled => ApiError::internal(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(ErrorCode::Banned.as_str(), "banned");
+        assert_eq!(ErrorCode::InvalidMap.as_str(), "invalid_map");
+        assert_eq!(ErrorCode::NotFound.as_str(), "not_found");
+        assert_eq!(ErrorCode::RateLimited.as_str(), "rate_limited");
+        assert_eq!(ErrorCode::ValidationFailed.as_str(), "validation_failed");
+        assert_eq!(ErrorCode::Internal.as_str(), "internal");
+    }
+
+    #[test]
+    fn test_error_to_xml() {
+        let err = ApiError::banned();
+        assert_eq!(
+            err.to_xml(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><response>\
+             <error><code>banned</code><message>This player is banned</message></error>\
+             </response>"
+        );
+    }
+
+    #[test]
+    fn test_error_message_is_escaped() {
+        let err = ApiError::validation("<time> must be > 0 & finite");
+        assert_eq!(
+            err.to_xml(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><response>\
+             <error><code>validation_failed</code>\
+             <message>&lt;time&gt; must be &gt; 0 &amp; finite</message></error>\
+             </response>"
+        );
+    }
+
+    #[test]
+    fn test_error_to_json() {
+        let resp = ApiError::not_found("Unknown map").json_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let body = match resp.body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes.clone(),
+            _ => panic!("unexpected body"),
+        };
+        assert_eq!(
+            body,
+            r#"{"error":{"code":"not_found","message":"Unknown map"}}"#.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_error_response_status() {
+        let resp = ApiError::internal().error_response();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let resp = ApiError::new(ErrorCode::RateLimited, "Slow down").error_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,753 @@
+//! Streamed exports of the records, players and maps, as CSV or NDJSON.
+//!
+//! The rows are read by chunks ordered by their key, each chunk starting
+//! after the last row of the previous one, so a whole table is exported
+//! without being held in memory nor paying for large offsets. Names are the
+//! plain ones, without formatting codes.
+//!
+//! The records can be filtered by map, by date of the record and by the
+//! categories of their map (environment, title pack and map type), the maps
+//! by the same categories and their upload date. The players are never
+//! filtered.
+
+use crate::app_state::AppState;
+use crate::error::ApiError;
+use crate::models::map::Map;
+use crate::models::player::Player;
+use crate::models::record::Record;
+use actix_web::http::header;
+use actix_web::{web, Error, HttpResponse};
+use bytes::Bytes;
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use futures::{stream, Future, Stream};
+use serde::Serialize as SerializeRow;
+use serde_derive::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Number of rows read at once.
+const CHUNK_SIZE: i64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            _ => Err(format!("unknown export format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dataset {
+    Records,
+    Players,
+    Maps,
+}
+
+impl Dataset {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Dataset::Records => "records",
+            Dataset::Players => "players",
+            Dataset::Maps => "maps",
+        }
+    }
+}
+
+impl FromStr for Dataset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "records" => Ok(Dataset::Records),
+            "players" => Ok(Dataset::Players),
+            "maps" => Ok(Dataset::Maps),
+            _ => Err(format!("unknown dataset: {}", s)),
+        }
+    }
+}
+
+/// Which rows are exported, every row by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExportFilter {
+    pub map_id: Option<String>,
+    /// Keeps the rows from this date, included
+    pub since: Option<NaiveDateTime>,
+    /// Keeps the rows before this date, excluded
+    pub until: Option<NaiveDateTime>,
+    pub environment: Option<String>,
+    pub title_pack: Option<String>,
+    pub map_type: Option<String>,
+}
+
+impl ExportFilter {
+    pub fn validate(&self, dataset: Dataset) -> Result<(), String> {
+        if dataset == Dataset::Players && *self != ExportFilter::default() {
+            return Err(String::from("the players can't be filtered"));
+        }
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since >= until {
+                return Err(String::from("since must be before until"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a date as `2019-04-26` (midnight) or `2019-04-26T15:49:39`, in UTC.
+pub fn parse_date(text: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|date| date.and_hms(0, 0, 0)))
+        .map_err(|_| format!("invalid date {:?}, expected YYYY-MM-DD[THH:MM:SS]", text))
+}
+
+/// A row of an export, written as a CSV line or a JSON object.
+trait Row: SerializeRow {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(T::to_string).unwrap_or_default()
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordRow {
+    pub map_id: String,
+    pub map_name: String,
+    pub player_id: String,
+    pub nickname: String,
+    pub rank: u32,
+    pub time: i32,
+    pub respawn_count: i32,
+    pub try_count: i32,
+    pub medal: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl RecordRow {
+    fn new((record, player, map): (Record, Player, Map)) -> Self {
+        RecordRow {
+            map_id: record.map_id,
+            map_name: map.name_plain,
+            player_id: record.player_id,
+            nickname: player.nickname_plain,
+            rank: record.rank,
+            time: record.time,
+            respawn_count: record.respawn_count,
+            try_count: record.try_count,
+            medal: record.medal,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        }
+    }
+}
+
+impl Row for RecordRow {
+    const HEADER: &'static [&'static str] = &[
+        "mapId",
+        "mapName",
+        "playerId",
+        "nickname",
+        "rank",
+        "time",
+        "respawnCount",
+        "tryCount",
+        "medal",
+        "createdAt",
+        "updatedAt",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.map_id.clone(),
+            self.map_name.clone(),
+            self.player_id.clone(),
+            self.nickname.clone(),
+            self.rank.to_string(),
+            self.time.to_string(),
+            self.respawn_count.to_string(),
+            self.try_count.to_string(),
+            optional(&self.medal),
+            self.created_at.to_string(),
+            self.updated_at.to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerRow {
+    pub login: String,
+    pub nickname: String,
+    pub zone_path: Option<String>,
+    pub language: Option<String>,
+}
+
+impl PlayerRow {
+    fn new(player: Player) -> Self {
+        PlayerRow {
+            login: player.login,
+            nickname: player.nickname_plain,
+            zone_path: player.zone_path,
+            language: player.language,
+        }
+    }
+}
+
+impl Row for PlayerRow {
+    const HEADER: &'static [&'static str] = &["login", "nickname", "zonePath", "language"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.login.clone(),
+            self.nickname.clone(),
+            optional(&self.zone_path),
+            optional(&self.language),
+        ]
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MapRow {
+    pub map_id: String,
+    pub name: String,
+    pub author_id: String,
+    pub author_time: Option<i32>,
+    pub gold_time: Option<i32>,
+    pub silver_time: Option<i32>,
+    pub bronze_time: Option<i32>,
+    pub environment: Option<String>,
+    pub title_pack: Option<String>,
+    pub map_type: Option<String>,
+    pub checkpoint_count: Option<i32>,
+    pub uploaded_at: Option<NaiveDateTime>,
+}
+
+impl MapRow {
+    fn new(map: Map) -> Self {
+        MapRow {
+            map_id: map.maniaplanet_map_id,
+            name: map.name_plain,
+            author_id: map.player_id,
+            author_time: map.author_time,
+            gold_time: map.gold_time,
+            silver_time: map.silver_time,
+            bronze_time: map.bronze_time,
+            environment: map.environment,
+            title_pack: map.title_pack,
+            map_type: map.map_type,
+            checkpoint_count: map.checkpoint_count,
+            uploaded_at: map.uploaded_at,
+        }
+    }
+}
+
+impl Row for MapRow {
+    const HEADER: &'static [&'static str] = &[
+        "mapId",
+        "name",
+        "authorId",
+        "authorTime",
+        "goldTime",
+        "silverTime",
+        "bronzeTime",
+        "environment",
+        "titlePack",
+        "mapType",
+        "checkpointCount",
+        "uploadedAt",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.map_id.clone(),
+            self.name.clone(),
+            self.author_id.clone(),
+            optional(&self.author_time),
+            optional(&self.gold_time),
+            optional(&self.silver_time),
+            optional(&self.bronze_time),
+            optional(&self.environment),
+            optional(&self.title_pack),
+            optional(&self.map_type),
+            optional(&self.checkpoint_count),
+            optional(&self.uploaded_at),
+        ]
+    }
+}
+
+/// Writes a CSV line, quoting the fields which need it (RFC 4180).
+fn write_csv_line<S: AsRef<str>>(out: &mut Vec<u8>, fields: &[S]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        let field = field.as_ref();
+        if field.contains(&[',', '"', '\n', '\r'][..]) {
+            out.push(b'"');
+            out.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+            out.push(b'"');
+        } else {
+            out.extend_from_slice(field.as_bytes());
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_rows<R: Row>(format: ExportFormat, rows: &[R], header: bool, out: &mut Vec<u8>) {
+    match format {
+        ExportFormat::Csv => {
+            if header {
+                write_csv_line(out, R::HEADER);
+            }
+            for row in rows {
+                write_csv_line(out, &row.fields());
+            }
+        }
+        ExportFormat::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut *out, row).expect("a row is serialized as JSON");
+                out.push(b'\n');
+            }
+        }
+    }
+}
+
+/// Where the next chunk starts.
+#[derive(Clone, Debug, PartialEq)]
+enum Cursor {
+    Start,
+    Record {
+        map_id: String,
+        time: i32,
+        player_id: String,
+    },
+    Player(String),
+    Map(String),
+}
+
+/// An export being written, chunk by chunk.
+pub struct Export {
+    pub dataset: Dataset,
+    pub format: ExportFormat,
+    pub filter: ExportFilter,
+    /// `None` once every row is written
+    cursor: Option<Cursor>,
+}
+
+impl Export {
+    pub fn new(dataset: Dataset, format: ExportFormat, filter: ExportFilter) -> Self {
+        Export {
+            dataset,
+            format,
+            filter,
+            cursor: Some(Cursor::Start),
+        }
+    }
+
+    /// The next part of the export, `None` once it is complete. The first
+    /// part of a CSV export holds the header, even without rows.
+    pub fn next_chunk(&mut self, conn: &MysqlConnection) -> QueryResult<Option<Vec<u8>>> {
+        let cursor = match self.cursor.take() {
+            Some(cursor) => cursor,
+            None => return Ok(None),
+        };
+        let header = cursor == Cursor::Start;
+        let mut out = Vec::new();
+
+        let (count, next) = match self.dataset {
+            Dataset::Records => {
+                let rows: Vec<RecordRow> = self
+                    .records_after(conn, &cursor)?
+                    .into_iter()
+                    .map(RecordRow::new)
+                    .collect();
+                write_rows(self.format, &rows, header, &mut out);
+                let next = rows.last().map(|row| Cursor::Record {
+                    map_id: row.map_id.clone(),
+                    time: row.time,
+                    player_id: row.player_id.clone(),
+                });
+                (rows.len(), next)
+            }
+            Dataset::Players => {
+                let rows: Vec<PlayerRow> = self
+                    .players_after(conn, &cursor)?
+                    .into_iter()
+                    .map(PlayerRow::new)
+                    .collect();
+                write_rows(self.format, &rows, header, &mut out);
+                let next = rows.last().map(|row| Cursor::Player(row.login.clone()));
+                (rows.len(), next)
+            }
+            Dataset::Maps => {
+                let rows: Vec<MapRow> = self
+                    .maps_after(conn, &cursor)?
+                    .into_iter()
+                    .map(MapRow::new)
+                    .collect();
+                write_rows(self.format, &rows, header, &mut out);
+                let next = rows.last().map(|row| Cursor::Map(row.map_id.clone()));
+                (rows.len(), next)
+            }
+        };
+
+        if count as i64 == CHUNK_SIZE {
+            self.cursor = next;
+        }
+        Ok(Some(out))
+    }
+
+    /// Writes the whole export.
+    pub fn write_to<W: Write>(mut self, conn: &MysqlConnection, out: &mut W) -> io::Result<()> {
+        while let Some(chunk) = self
+            .next_chunk(conn)
+            .map_err(|e| io::Error::other(e.to_string()))?
+        {
+            out.write_all(&chunk)?;
+        }
+        out.flush()
+    }
+
+    fn records_after(
+        &self,
+        conn: &MysqlConnection,
+        cursor: &Cursor,
+    ) -> QueryResult<Vec<(Record, Player, Map)>> {
+        use crate::schema::{maps, players, records};
+
+        let filter = &self.filter;
+        let mut query = records::table
+            .inner_join(players::table)
+            .inner_join(maps::table)
+            .into_boxed();
+
+        if let Some(ref map_id) = filter.map_id {
+            query = query.filter(records::map_id.eq(map_id));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(records::updated_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(records::updated_at.lt(until));
+        }
+        if let Some(ref environment) = filter.environment {
+            query = query.filter(maps::environment.eq(environment));
+        }
+        if let Some(ref title_pack) = filter.title_pack {
+            query = query.filter(maps::title_pack.eq(title_pack));
+        }
+        if let Some(ref map_type) = filter.map_type {
+            query = query.filter(maps::map_type.eq(map_type));
+        }
+        if let Cursor::Record {
+            map_id,
+            time,
+            player_id,
+        } = cursor
+        {
+            query = query.filter(
+                records::map_id
+                    .gt(map_id)
+                    .or(records::map_id.eq(map_id).and(records::time.gt(time)))
+                    .or(records::map_id
+                        .eq(map_id)
+                        .and(records::time.eq(time))
+                        .and(records::player_id.gt(player_id))),
+            );
+        }
+
+        query
+            .order_by((records::map_id, records::time, records::player_id))
+            .limit(CHUNK_SIZE)
+            .load(conn)
+    }
+
+    fn players_after(&self, conn: &MysqlConnection, cursor: &Cursor) -> QueryResult<Vec<Player>> {
+        use crate::schema::players;
+
+        let mut query = players::table.into_boxed();
+        if let Cursor::Player(login) = cursor {
+            query = query.filter(players::login.gt(login));
+        }
+
+        query.order_by(players::login).limit(CHUNK_SIZE).load(conn)
+    }
+
+    fn maps_after(&self, conn: &MysqlConnection, cursor: &Cursor) -> QueryResult<Vec<Map>> {
+        use crate::schema::maps;
+
+        let filter = &self.filter;
+        let mut query = maps::table.into_boxed();
+
+        if let Some(ref map_id) = filter.map_id {
+            query = query.filter(maps::maniaplanet_map_id.eq(map_id));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(maps::uploaded_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(maps::uploaded_at.lt(until));
+        }
+        if let Some(ref environment) = filter.environment {
+            query = query.filter(maps::environment.eq(environment));
+        }
+        if let Some(ref title_pack) = filter.title_pack {
+            query = query.filter(maps::title_pack.eq(title_pack));
+        }
+        if let Some(ref map_type) = filter.map_type {
+            query = query.filter(maps::map_type.eq(map_type));
+        }
+        if let Cursor::Map(map_id) = cursor {
+            query = query.filter(maps::maniaplanet_map_id.gt(map_id));
+        }
+
+        query
+            .order_by(maps::maniaplanet_map_id)
+            .limit(CHUNK_SIZE)
+            .load(conn)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+    pub map: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub environment: Option<String>,
+    #[serde(rename = "titlePack")]
+    pub title_pack: Option<String>,
+    #[serde(rename = "mapType")]
+    pub map_type: Option<String>,
+}
+
+impl ExportQuery {
+    pub fn export(&self, dataset: Dataset) -> Result<Export, ApiError> {
+        let format = match self.format {
+            Some(ref format) => format.parse().map_err(ApiError::validation)?,
+            None => ExportFormat::Csv,
+        };
+        let date = |date: &Option<String>| -> Result<_, ApiError> {
+            match date {
+                Some(date) => Ok(Some(parse_date(date).map_err(ApiError::validation)?)),
+                None => Ok(None),
+            }
+        };
+
+        let filter = ExportFilter {
+            map_id: self.map.clone(),
+            since: date(&self.since)?,
+            until: date(&self.until)?,
+            environment: self.environment.clone(),
+            title_pack: self.title_pack.clone(),
+            map_type: self.map_type.clone(),
+        };
+        filter.validate(dataset).map_err(ApiError::validation)?;
+
+        Ok(Export::new(dataset, format, filter))
+    }
+}
+
+/// Answers with the export, read from the database while it is sent.
+fn export_response(dataset: Dataset, query: &ExportQuery, state: &AppState) -> HttpResponse {
+    let export = match query.export(dataset) {
+        Ok(export) => export,
+        Err(e) => return e.json_response(),
+    };
+    let content_type = export.format.content_type();
+    let file_name = format!("{}.{}", dataset.as_str(), export.format.extension());
+
+    let pool = state.pool.clone();
+    let chunks = stream::unfold(Some(export), move |export| {
+        let pool = pool.clone();
+        export.map(|mut export| {
+            web::block(move || -> Result<_, ApiError> {
+                let conn = pool.get()?;
+                Ok(match export.next_chunk(&conn)? {
+                    Some(chunk) => (Bytes::from(chunk), Some(export)),
+                    None => (Bytes::new(), None),
+                })
+            })
+            .map_err(|e| Error::from(ApiError::from(e)))
+        })
+    })
+    .filter(|chunk| !chunk.is_empty());
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .streaming(chunks)
+}
+
+pub fn records_export_route(
+    query: web::Query<ExportQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> HttpResponse {
+    export_response(Dataset::Records, &query, &state)
+}
+
+pub fn players_export_route(
+    query: web::Query<ExportQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> HttpResponse {
+    export_response(Dataset::Players, &query, &state)
+}
+
+pub fn maps_export_route(
+    query: web::Query<ExportQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> HttpResponse {
+    export_response(Dataset::Maps, &query, &state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> ExportQuery {
+        ExportQuery {
+            format: None,
+            map: None,
+            since: None,
+            until: None,
+            environment: None,
+            title_pack: None,
+            map_type: None,
+        }
+    }
+
+    fn record_row() -> RecordRow {
+        RecordRow {
+            map_id: String::from("NullId"),
+            map_name: String::from("Null, \"the\" map"),
+            player_id: String::from("smokegun"),
+            nickname: String::from("SmokeGun"),
+            rank: 1,
+            time: 61234,
+            respawn_count: 3,
+            try_count: 12,
+            medal: Some(String::from("gold")),
+            created_at: NaiveDate::from_ymd(2019, 4, 26).and_hms(15, 49, 39),
+            updated_at: NaiveDate::from_ymd(2019, 4, 27).and_hms(8, 30, 0),
+        }
+    }
+
+    #[test]
+    fn test_csv_rows() {
+        let mut out = Vec::new();
+        write_rows(ExportFormat::Csv, &[record_row()], true, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "mapId,mapName,playerId,nickname,rank,time,respawnCount,tryCount,medal,createdAt,updatedAt\r\n\
+             NullId,\"Null, \"\"the\"\" map\",smokegun,SmokeGun,1,61234,3,12,gold,\
+             2019-04-26 15:49:39,2019-04-27 08:30:00\r\n"
+        );
+
+        let mut out = Vec::new();
+        write_rows::<PlayerRow>(ExportFormat::Csv, &[], true, &mut out);
+        assert_eq!(out, b"login,nickname,zonePath,language\r\n");
+    }
+
+    #[test]
+    fn test_ndjson_rows() {
+        let player = PlayerRow {
+            login: String::from("encht"),
+            nickname: String::from("line\nbreak"),
+            zone_path: None,
+            language: Some(String::from("fr")),
+        };
+
+        let mut out = Vec::new();
+        write_rows(ExportFormat::Ndjson, &[record_row()], true, &mut out);
+        write_rows(ExportFormat::Ndjson, &[player], false, &mut out);
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["mapName"], "Null, \"the\" map");
+        assert_eq!(record["updatedAt"], "2019-04-27T08:30:00");
+        let player: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(player["nickname"], "line\nbreak");
+        assert!(player["zonePath"].is_null());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2019-04-26").unwrap(),
+            NaiveDate::from_ymd(2019, 4, 26).and_hms(0, 0, 0)
+        );
+        assert_eq!(
+            parse_date("2019-04-26T15:49:39").unwrap(),
+            NaiveDate::from_ymd(2019, 4, 26).and_hms(15, 49, 39)
+        );
+        assert!(parse_date("26/04/2019").is_err());
+    }
+
+    #[test]
+    fn test_export_query() {
+        let export = query().export(Dataset::Records).unwrap();
+        assert_eq!(export.format, ExportFormat::Csv);
+        assert_eq!(export.filter, ExportFilter::default());
+
+        let mut filtered = query();
+        filtered.format = Some(String::from("ndjson"));
+        filtered.map = Some(String::from("NullId"));
+        filtered.since = Some(String::from("2019-04-01"));
+        filtered.until = Some(String::from("2019-05-01"));
+        filtered.environment = Some(String::from("Storm"));
+        let export = filtered.export(Dataset::Records).unwrap();
+        assert_eq!(export.format, ExportFormat::Ndjson);
+        assert_eq!(export.filter.map_id.as_deref(), Some("NullId"));
+        assert_eq!(export.filter.environment.as_deref(), Some("Storm"));
+        assert!(filtered.export(Dataset::Maps).is_ok());
+        assert!(filtered.export(Dataset::Players).is_err());
+
+        let mut reversed = query();
+        reversed.since = Some(String::from("2019-05-01"));
+        reversed.until = Some(String::from("2019-04-01"));
+        assert!(reversed.export(Dataset::Records).is_err());
+
+        let mut xml = query();
+        xml.format = Some(String::from("xml"));
+        assert!(xml.export(Dataset::Maps).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+//! Atom feeds of the latest records.
+//!
+//! `/feeds/records.atom` follows every record, `/feeds/world-records.atom`
+//! the world records as they were set, and `/feeds/maps/{id}/records.atom` and
+//! `/feeds/players/{login}/records.atom` the records of a map or a player.
+//! Names are the plain ones, without formatting codes.
+//!
+//! An entry is a time of a player on a map: its id changes with the time, so
+//! an improved record is a new entry for the feed readers.
+
+use crate::app_state::AppState;
+use crate::error::ApiError;
+use crate::metrics;
+use crate::models::map::Map;
+use crate::models::player::Player;
+use crate::models::record::{time_text, Record};
+use crate::models::world_record::WorldRecord;
+use crate::records_api::{self, LatestRecords};
+use crate::schema;
+use crate::xml;
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use futures::Future;
+use serde_derive::Serialize;
+use std::fmt::Write;
+use std::sync::Arc;
+
+const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+/// Start of the ids of the feeds and their entries, which must not change
+/// with the host serving them.
+const TAG: &str = "tag:obstacle.ovh,2019";
+
+/// Number of entries of a feed.
+const FEED_SIZE: i64 = 50;
+
+#[derive(Serialize)]
+#[serde(rename = "feed")]
+pub struct Feed {
+    #[serde(rename = "@xmlns")]
+    xmlns: &'static str,
+    pub id: String,
+    pub title: String,
+    pub updated: String,
+    pub link: Link,
+    #[serde(rename = "$items")]
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "entry")]
+pub struct Entry {
+    pub id: String,
+    pub title: String,
+    pub updated: String,
+    pub author: Author,
+    pub link: Link,
+    pub summary: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "author")]
+pub struct Author {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "link")]
+pub struct Link {
+    #[serde(rename = "@rel")]
+    pub rel: &'static str,
+    #[serde(rename = "@href")]
+    pub href: String,
+}
+
+/// What a feed follows.
+#[derive(Debug)]
+pub enum Subject {
+    Latest,
+    WorldRecords,
+    Map { id: String, name: String },
+    Player { login: String, nickname: String },
+}
+
+impl Subject {
+    /// The path of the feed under `/feeds`, without extension.
+    fn path(&self) -> String {
+        match self {
+            Subject::Latest => String::from("records"),
+            Subject::WorldRecords => String::from("world-records"),
+            Subject::Map { id, .. } => format!("maps/{}/records", encode_segment(id)),
+            Subject::Player { login, .. } => {
+                format!("players/{}/records", encode_segment(login))
+            }
+        }
+    }
+
+    fn title(&self) -> String {
+        match self {
+            Subject::Latest => String::from("Latest records"),
+            Subject::WorldRecords => String::from("Latest world records"),
+            Subject::Map { name, .. } => format!("Latest records on {}", name),
+            Subject::Player { nickname, .. } => format!("Latest records of {}", nickname),
+        }
+    }
+
+    /// The latest records followed, the world records from their history so
+    /// a beaten one stays in the feed.
+    fn records(&self, conn: &MysqlConnection) -> QueryResult<Vec<(Record, Player, Map)>> {
+        let filter = match self {
+            Subject::Latest => LatestRecords::All,
+            Subject::WorldRecords => {
+                let world_records = WorldRecord::latest(conn, 0, FEED_SIZE)?;
+                return Ok(world_records
+                    .into_iter()
+                    .map(|(record, player, map)| (record.into_record(), player, map))
+                    .collect());
+            }
+            Subject::Map { id, .. } => LatestRecords::Map(id),
+            Subject::Player { login, .. } => LatestRecords::Player(login),
+        };
+        records_api::latest_records_of(conn, filter, 0, FEED_SIZE)
+    }
+}
+
+/// Percent-encodes a path segment, keeping the unreserved characters.
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+/// A date as RFC 3339, the dates of the database being in UTC.
+fn rfc3339(date: NaiveDateTime) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+impl Entry {
+    fn new(base_url: &str, subject: &Subject, record: Record, player: Player, map: Map) -> Self {
+        let time = time_text(record.time);
+        let (kind, title) = match subject {
+            Subject::WorldRecords => (
+                "world-records",
+                format!(
+                    "World record on {}: {} by {}",
+                    map.name_plain, time, player.nickname_plain
+                ),
+            ),
+            _ => (
+                "records",
+                format!("{}: {} on {}", player.nickname_plain, time, map.name_plain),
+            ),
+        };
+
+        let mut summary = format!(
+            "Rank {} on {} in {}, {} respawns, {} tries",
+            record.rank, map.name_plain, time, record.respawn_count, record.try_count
+        );
+        if let Some(medal) = record.medal {
+            let _ = write!(summary, ", {} medal", medal);
+        }
+
+        let map_id = encode_segment(&record.map_id);
+        Entry {
+            id: format!(
+                "{}:{}/{}/{}/{}",
+                TAG,
+                kind,
+                map_id,
+                encode_segment(&record.player_id),
+                record.time
+            ),
+            title,
+            updated: rfc3339(record.updated_at),
+            author: Author {
+                name: player.nickname_plain,
+            },
+            link: Link {
+                rel: "alternate",
+                href: format!("{}/v1/maps/{}/leaderboard", base_url, map_id),
+            },
+            summary,
+        }
+    }
+}
+
+impl Feed {
+    /// The feed of the records, the latest first. An empty feed is updated
+    /// `now`.
+    pub fn new(
+        base_url: &str,
+        subject: &Subject,
+        records: Vec<(Record, Player, Map)>,
+        now: NaiveDateTime,
+    ) -> Self {
+        let updated = records
+            .iter()
+            .map(|(record, _, _)| record.updated_at)
+            .max()
+            .unwrap_or(now);
+        let path = subject.path();
+
+        Feed {
+            xmlns: ATOM_NAMESPACE,
+            id: format!("{}:feeds/{}", TAG, path),
+            title: subject.title(),
+            updated: rfc3339(updated),
+            link: Link {
+                rel: "self",
+                href: format!("{}/feeds/{}.atom", base_url, path),
+            },
+            entries: records
+                .into_iter()
+                .map(|(record, player, map)| Entry::new(base_url, subject, record, player, map))
+                .collect(),
+        }
+    }
+}
+
+fn base_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+fn render(conn: &MysqlConnection, base_url: &str, subject: Subject) -> Result<String, ApiError> {
+    let records = subject.records(conn)?;
+    let feed = Feed::new(base_url, &subject, records, Utc::now().naive_utc());
+    Ok(xml::document_to_string(&feed)?)
+}
+
+fn atom_response(
+    res: Result<String, error::BlockingError<ApiError>>,
+) -> Result<HttpResponse, Error> {
+    match res {
+        Ok(body) => Ok(HttpResponse::Ok()
+            .content_type("application/atom+xml; charset=utf-8")
+            .body(body)),
+        Err(e) => Ok(ApiError::from(e).json_response()),
+    }
+}
+
+pub fn latest_records_feed_route(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let base_url = base_url(&req);
+    web::block(move || -> Result<String, ApiError> {
+        let conn = state.pool.get()?;
+        render(&conn, &base_url, Subject::Latest)
+    })
+    .then(atom_response)
+}
+
+pub fn world_records_feed_route(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let base_url = base_url(&req);
+    web::block(move || -> Result<String, ApiError> {
+        let conn = state.pool.get()?;
+        render(&conn, &base_url, Subject::WorldRecords)
+    })
+    .then(atom_response)
+}
+
+pub fn map_records_feed_route(
+    req: HttpRequest,
+    map_id: web::Path<String>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let base_url = base_url(&req);
+    web::block(move || -> Result<String, ApiError> {
+        let conn = state.pool.get()?;
+        let name: String = schema::maps::table
+            .find(&*map_id)
+            .select(schema::maps::name_plain)
+            .get_result(&conn)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(format!("Unknown map {}", map_id)))?;
+
+        let subject = Subject::Map {
+            id: map_id.into_inner(),
+            name,
+        };
+        render(&conn, &base_url, subject)
+    })
+    .then(atom_response)
+}
+
+pub fn player_records_feed_route(
+    req: HttpRequest,
+    login: web::Path<String>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let base_url = base_url(&req);
+    web::block(move || -> Result<String, ApiError> {
+        let conn = state.pool.get()?;
+        let nickname: String = schema::players::table
+            .find(&*login)
+            .select(schema::players::nickname_plain)
+            .get_result(&conn)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(format!("Unknown player {}", login)))?;
+
+        let subject = Subject::Player {
+            login: login.into_inner(),
+            nickname,
+        };
+        render(&conn, &base_url, subject)
+    })
+    .then(atom_response)
+}
+
+/// Registers the `/feeds` routes.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    let resource = |path| metrics::scoped_resource("/feeds", path);
+
+    cfg.service(
+        web::scope("/feeds")
+            .service(
+                resource("/records.atom").route(web::get().to_async(latest_records_feed_route)),
+            )
+            .service(
+                resource("/world-records.atom")
+                    .route(web::get().to_async(world_records_feed_route)),
+            )
+            .service(
+                resource("/maps/{id}/records.atom")
+                    .route(web::get().to_async(map_records_feed_route)),
+            )
+            .service(
+                resource("/players/{login}/records.atom")
+                    .route(web::get().to_async(player_records_feed_route)),
+            ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn record(map: &Map, player: &Player, rank: u32, time: i32, medal: Option<&str>) -> Record {
+        Record {
+            rank,
+            time,
+            respawn_count: 3,
+            try_count: 12,
+            created_at: NaiveDate::from_ymd(2019, 4, 26).and_hms(15, 0, 0),
+            updated_at: NaiveDate::from_ymd(2019, 4, 27).and_hms(8, 30, time as u32 % 60),
+            player_id: player.login.clone(),
+            map_id: map.maniaplanet_map_id.clone(),
+            medal: medal.map(String::from),
+            first_time: None,
+        }
+    }
+
+    fn map() -> Map {
+        Map {
+            maniaplanet_map_id: String::from("Null Id"),
+            name: String::from("$f00Null &amp; Void"),
+            name_plain: String::from("Null & Void"),
+            player_id: String::from("smokegun"),
+            ..Default::default()
+        }
+    }
+
+    fn records() -> Vec<(Record, Player, Map)> {
+        let null = map();
+        let smokegun = Player {
+            login: String::from("smokegun"),
+            nickname: String::from("$f00Smoke$fffGun"),
+            nickname_plain: String::from("SmokeGun"),
+            ..Default::default()
+        };
+        let encht = Player {
+            login: String::from("encht"),
+            nickname: String::from("&lt;encht&gt;"),
+            nickname_plain: String::from("<encht>"),
+            ..Default::default()
+        };
+
+        vec![
+            (
+                record(&null, &smokegun, 1, 61234, Some("gold")),
+                smokegun,
+                map(),
+            ),
+            (record(&null, &encht, 2, 61500, None), encht, null),
+        ]
+    }
+
+    fn now() -> NaiveDateTime {
+        NaiveDate::from_ymd(2019, 5, 1).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn test_golden_records_feed() {
+        let feed = Feed::new("http://localhost:3000", &Subject::Latest, records(), now());
+        assert_eq!(
+            xml::document_to_string(&feed).unwrap(),
+            include_str!("../tests/golden/records_feed.xml")
+        );
+    }
+
+    #[test]
+    fn test_world_records_feed() {
+        // A world record beaten since then, as saved in the history
+        let world_records: Vec<_> = records()
+            .into_iter()
+            .skip(1)
+            .map(|(record, player, map)| {
+                let world_record = WorldRecord {
+                    id: 1,
+                    map_id: record.map_id,
+                    player_id: record.player_id,
+                    time: record.time,
+                    respawn_count: record.respawn_count,
+                    try_count: record.try_count,
+                    medal: record.medal,
+                    set_at: record.updated_at,
+                };
+                (world_record.into_record(), player, map)
+            })
+            .collect();
+        let feed = Feed::new(
+            "http://localhost:3000",
+            &Subject::WorldRecords,
+            world_records,
+            now(),
+        );
+
+        assert_eq!(feed.id, "tag:obstacle.ovh,2019:feeds/world-records");
+        assert_eq!(feed.entries.len(), 1);
+        assert_eq!(
+            feed.entries[0].id,
+            "tag:obstacle.ovh,2019:world-records/Null%20Id/encht/61500"
+        );
+        assert_eq!(
+            feed.entries[0].title,
+            "World record on Null & Void: 1:01.500 by <encht>"
+        );
+        assert!(feed.entries[0].summary.starts_with("Rank 1 "));
+    }
+
+    #[test]
+    fn test_empty_feed() {
+        let subject = Subject::Player {
+            login: String::from("new/player"),
+            nickname: String::from("New player"),
+        };
+        let feed = Feed::new("https://records.example", &subject, Vec::new(), now());
+
+        assert_eq!(
+            feed.id,
+            "tag:obstacle.ovh,2019:feeds/players/new%2Fplayer/records"
+        );
+        assert_eq!(feed.title, "Latest records of New player");
+        assert_eq!(feed.updated, "2019-05-01T00:00:00Z");
+        assert_eq!(
+            feed.link.href,
+            "https://records.example/feeds/players/new%2Fplayer/records.atom"
+        );
+        assert!(feed.entries.is_empty());
+    }
+
+    #[test]
+    fn test_encode_segment() {
+        assert_eq!(encode_segment("aZ09-._~"), "aZ09-._~");
+        assert_eq!(encode_segment("a b/c?é"), "a%20b%2Fc%3F%C3%A9");
+    }
+}
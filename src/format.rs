@@ -0,0 +1,174 @@
+//! Content negotiation for the game routes.
+//!
+//! The game routes answer in XML, which is what the ManiaScript clients
+//! always parsed. Newer clients and bots can ask for JSON instead, either with
+//! a `format=json` query parameter or with an `Accept: application/json`
+//! header. The query parameter wins over the header, and XML stays the
+//! default so the existing clients see the exact same bodies.
+
+use crate::error::ApiError;
+use crate::xml;
+use actix_web::http::header;
+use actix_web::{error, Error, HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Xml,
+    Json,
+}
+
+impl Format {
+    pub fn from_request(req: &HttpRequest) -> Format {
+        let accept = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok());
+
+        Format::negotiate(req.query_string(), accept)
+    }
+
+    fn negotiate(query: &str, accept: Option<&str>) -> Format {
+        let from_query = query
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("format"), Some(value)) => Format::from_name(value),
+                    _ => None,
+                }
+            })
+            .next_back();
+
+        from_query
+            .or_else(|| accept.and_then(Format::from_accept))
+            .unwrap_or(Format::Xml)
+    }
+
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "xml" => Some(Format::Xml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Picks the format from the media ranges of an `Accept` header, taking
+    /// their quality into account. Wildcards keep the default.
+    fn from_accept(accept: &str) -> Option<Format> {
+        let mut best: Option<(Format, f32)> = None;
+
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let format = match params.next() {
+                Some("application/json") => Format::Json,
+                Some("application/xml") | Some("text/xml") => Format::Xml,
+                _ => continue,
+            };
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .filter_map(|q| q.parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+
+            let better = match best {
+                Some((_, best_quality)) => quality > best_quality,
+                None => true,
+            };
+            if quality > 0.0 && better {
+                best = Some((format, quality));
+            }
+        }
+
+        best.map(|(format, _)| format)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Xml => "application/xml; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+
+    pub fn error_response(self, err: &ApiError) -> HttpResponse {
+        match self {
+            Format::Xml => err.error_response(),
+            Format::Json => err.json_response(),
+        }
+    }
+
+    /// Answers with the body built in this format, or with the error.
+    pub fn respond(
+        self,
+        res: Result<String, error::BlockingError<ApiError>>,
+    ) -> Result<HttpResponse, Error> {
+        match res {
+            Ok(body) => Ok(match self {
+                Format::Xml => xml::xml_response(body),
+                Format::Json => HttpResponse::Ok()
+                    .content_type(self.content_type())
+                    .body(body),
+            }),
+            Err(e) => {
+                let err = ApiError::from(e);
+                eprintln!("Error while sending response: {}", err);
+                Ok(self.error_response(&err))
+            }
+        }
+    }
+}
+
+pub fn to_json<T: Serialize>(value: &T) -> Result<String, ApiError> {
+    serde_json::to_string(value).map_err(|e| {
+        eprintln!("Error while serializing: {}", e);
+        ApiError::internal()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_is_xml() {
+        assert_eq!(Format::negotiate("", None), Format::Xml);
+        assert_eq!(Format::negotiate("", Some("*/*")), Format::Xml);
+        assert_eq!(
+            Format::negotiate("mapId=abc", Some("text/html")),
+            Format::Xml
+        );
+    }
+
+    #[test]
+    fn test_format_from_query() {
+        assert_eq!(
+            Format::negotiate("mapId=abc&format=json", None),
+            Format::Json
+        );
+        assert_eq!(
+            Format::negotiate("format=xml", Some("application/json")),
+            Format::Xml
+        );
+        assert_eq!(Format::negotiate("format=yaml", None), Format::Xml);
+    }
+
+    #[test]
+    fn test_format_from_accept() {
+        assert_eq!(
+            Format::negotiate("", Some("application/json")),
+            Format::Json
+        );
+        assert_eq!(
+            Format::negotiate("", Some("application/xml;q=0.5, application/json")),
+            Format::Json
+        );
+        assert_eq!(
+            Format::negotiate("", Some("application/json;q=0.5, text/xml")),
+            Format::Xml
+        );
+        assert_eq!(
+            Format::negotiate("", Some("application/json;q=0")),
+            Format::Xml
+        );
+    }
+}
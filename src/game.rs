@@ -1,9 +1,14 @@
 use crate::app_state::AppState;
+use crate::error::ApiError;
+use crate::format::{self, Format};
+use crate::metrics;
 use crate::models;
-use crate::records_api;
+use crate::models::medal::Medal;
+use crate::records_api::{self, Finish, NewFinish, Overview, OverviewLayout};
+use crate::rest;
 use crate::xml;
-use actix_web::{error, web, Error, HttpResponse};
-use diesel::prelude::*;
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use chrono::{Duration, NaiveDateTime, Utc};
 use futures::Future;
 use serde_derive::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -16,6 +21,33 @@ pub struct HasFinishedResult {
     pub login: String,
     pub old: i32,
     pub new: i32,
+    /// A medal better than the one of the previous best time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medal: Option<Medal>,
+    /// The zone path of the player, when it is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// The rank of the best time among the players of the zone
+    #[serde(default, rename = "zoneRank", skip_serializing_if = "Option::is_none")]
+    pub zone_rank: Option<u32>,
+    /// The finish was already saved with the same idempotency key
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool,
+}
+
+impl HasFinishedResult {
+    pub fn new(login: String, finish: Finish) -> Self {
+        HasFinishedResult {
+            is_new_best: finish.is_new_best,
+            login,
+            old: finish.old,
+            new: finish.new,
+            medal: finish.new_medal,
+            zone: finish.zone,
+            zone_rank: finish.zone_rank,
+            replayed: finish.replayed,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -27,64 +59,337 @@ pub struct HasFinishedPayload {
     pub map_id: String,
     #[serde(alias = "playerId")]
     pub player_id: String,
+    /// Chosen by the game server, a finish sent again with the same key is
+    /// not saved twice
+    #[serde(default, alias = "idempotencyKey")]
+    pub idempotency_key: Option<String>,
+    /// When the player finished, in UTC, for the finishes sent late
+    #[serde(default, alias = "finishedAt")]
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+/// Longest id the database accepts for a map or a player.
+const MAX_ID_LEN: usize = 255;
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+
+/// How far in the future the clock of a game server may be.
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+pub fn validate_map_id(map_id: &str) -> Result<(), ApiError> {
+    if map_id.is_empty() || map_id.len() > MAX_ID_LEN {
+        Err(ApiError::invalid_map(format!(
+            "Invalid map id: {:?}",
+            map_id
+        )))
+    } else {
+        Ok(())
+    }
 }
 
-fn string_to_xml_response(
-    res: Result<String, error::BlockingError<error::BlockingError<()>>>,
-) -> Result<HttpResponse, Error> {
-    match res {
-        Ok(body) => Ok(xml::xml_response(body)),
-        Err(e) => {
-            eprintln!("Error while sending xml: {}", e.to_string());
-            Ok(HttpResponse::InternalServerError().into())
+pub fn validate_login(login: &str) -> Result<(), ApiError> {
+    if login.is_empty() || login.len() > MAX_ID_LEN {
+        Err(ApiError::validation(format!(
+            "Invalid player login: {:?}",
+            login
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks the player info sent by the game server, the profile being optional.
+fn validate_player(player: &models::player::Player) -> Result<(), ApiError> {
+    validate_login(&player.login)?;
+
+    if let Some(ref path) = player.zone_path {
+        if !models::player::is_valid_zone_path(path) {
+            return Err(ApiError::validation(
+                "zonePath must be non-empty zones separated by |",
+            ));
+        }
+    }
+
+    if let Some(ref language) = player.language {
+        if language.is_empty()
+            || language.len() > 16
+            || !language
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(ApiError::validation("Invalid language"));
+        }
+    }
+
+    if let Some(ref avatar) = player.avatar {
+        if avatar.len() > 255 {
+            return Err(ApiError::validation("avatar must be at most 255 bytes"));
         }
     }
+
+    Ok(())
+}
+
+/// Checks the map info sent by the game server, every value being optional.
+fn validate_map(map: &models::map::Map) -> Result<(), ApiError> {
+    validate_map_id(&map.maniaplanet_map_id)?;
+    validate_login(&map.player_id)?;
+
+    let times = [
+        ("authorTime", map.author_time),
+        ("goldTime", map.gold_time),
+        ("silverTime", map.silver_time),
+        ("bronzeTime", map.bronze_time),
+    ];
+    for (name, time) in times.iter() {
+        if let Some(time) = time {
+            if *time <= 0 {
+                return Err(ApiError::validation(format!("{} must be positive", name)));
+            }
+        }
+    }
+
+    // Each medal must be at least as easy as the one before
+    let known: Vec<(&str, i32)> = times
+        .iter()
+        .filter_map(|(name, time)| time.map(|t| (*name, t)))
+        .collect();
+    for pair in known.windows(2) {
+        if pair[0].1 > pair[1].1 {
+            return Err(ApiError::validation(format!(
+                "{} must not be greater than {}",
+                pair[0].0, pair[1].0
+            )));
+        }
+    }
+
+    if let Some(count) = map.checkpoint_count {
+        if count < 0 {
+            return Err(ApiError::validation("checkpointCount must not be negative"));
+        }
+    }
+
+    if let Some(ref hash) = map.file_hash {
+        if hash.is_empty() || hash.len() > 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ApiError::validation("fileHash must be hexadecimal"));
+        }
+    }
+
+    let texts = [
+        ("environment", &map.environment, 64),
+        ("titlePack", &map.title_pack, 128),
+        ("mapType", &map.map_type, 128),
+    ];
+    for (name, text, max_len) in texts.iter() {
+        if let Some(text) = text {
+            if text.len() > *max_len {
+                return Err(ApiError::validation(format!(
+                    "{} must be at most {} bytes",
+                    name, max_len
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl HasFinishedPayload {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        validate_map_id(&self.map_id)?;
+        validate_login(&self.player_id)?;
+
+        if self.time <= 0 {
+            return Err(ApiError::validation("time must be positive"));
+        }
+        if self.respawn_count < 0 {
+            return Err(ApiError::validation("respawnCount must not be negative"));
+        }
+
+        if let Some(ref key) = self.idempotency_key {
+            if key.is_empty()
+                || key.len() > MAX_IDEMPOTENCY_KEY_LEN
+                || !key
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            {
+                return Err(ApiError::validation(format!(
+                    "idempotencyKey must be 1 to {} letters, digits, - or _",
+                    MAX_IDEMPOTENCY_KEY_LEN
+                )));
+            }
+        }
+
+        if let Some(finished_at) = self.finished_at {
+            let latest = Utc::now().naive_utc() + Duration::minutes(MAX_CLOCK_SKEW_MINUTES);
+            if finished_at > latest {
+                return Err(ApiError::validation("finishedAt must not be in the future"));
+            }
+            let earliest = Utc::now().naive_utc() - Duration::days(records_api::FINISH_KEY_DAYS);
+            if finished_at < earliest {
+                return Err(ApiError::validation(format!(
+                    "finishedAt must be within the last {} days",
+                    records_api::FINISH_KEY_DAYS
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_finish(&self) -> NewFinish<'_> {
+        NewFinish {
+            time: self.time,
+            respawn_count: self.respawn_count,
+            player_id: &self.player_id,
+            map_id: &self.map_id,
+            idempotency_key: self.idempotency_key.as_deref(),
+            finished_at: self.finished_at,
+        }
+    }
+}
+
+fn negotiated_error(err: ApiError, req: &HttpRequest) -> Error {
+    let response = Format::from_request(req).error_response(&err);
+    error::InternalError::from_response(err.to_string(), response).into()
+}
+
+/// Json extractor configuration answering malformed payloads with the error
+/// schema of the requested format.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, req| {
+        negotiated_error(
+            ApiError::validation(format!("Invalid payload: {}", err)),
+            req,
+        )
+    })
+}
+
+/// Query extractor configuration answering malformed queries with the error
+/// schema of the requested format.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, req| {
+        negotiated_error(ApiError::validation(format!("Invalid query: {}", err)), req)
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename = "response")]
+struct OkResult {
+    id: &'static str,
+}
+
+fn ok_body(format: Format) -> Result<String, ApiError> {
+    match format {
+        Format::Xml => Ok(xml::element_to_string(&OkResult { id: "ok" })?),
+        Format::Json => format::to_json(&OkResult { id: "ok" }),
+    }
 }
 
 pub fn has_finished_route(
+    req: HttpRequest,
     payload: web::Json<HasFinishedPayload>,
     state: web::Data<Arc<AppState>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let format = Format::from_request(&req);
+
     // First we block during the access to the database
-    web::block(move || {
+    web::block(move || -> Result<String, ApiError> {
+        let conn = state.pool.get()?;
+
+        payload
+            .validate()
+            .and_then(|_| {
+                if models::player::Player::is_banned(&conn, &payload.player_id)? {
+                    Err(ApiError::banned())
+                } else {
+                    Ok(())
+                }
+            })
+            .inspect_err(|e| {
+                metrics::REJECTED_FINISHES
+                    .with_label_values(&[e.code.as_str()])
+                    .inc();
+            })?;
+
+        let finish = records_api::has_finished(&conn, &payload.to_finish())?;
 
-        // ip: 37.166.70.85 and 88.122.245.65
-        let banned_players = ["xxel94toonzxx", "encht"];
-        let is_banned = banned_players
-            .iter()
-            .any(|&banned_player| payload.player_id == banned_player);
+        let result = HasFinishedResult::new(String::from(&payload.player_id), finish);
 
-        if is_banned {
-            return Err(error::BlockingError::Error(()));
+        match format {
+            Format::Xml => Ok(xml::element_to_string(&result)?),
+            Format::Json => format::to_json(&result),
         }
+    })
+    // then we can send the response
+    .then(move |res| format.respond(res))
+}
 
-        let conn: &MysqlConnection = &state.pool.get().unwrap();
+/// Most finishes sent at once.
+const MAX_BULK_FINISHES: usize = 200;
 
-        let finished = records_api::has_finished(
-            conn,
-            payload.time,
-            payload.respawn_count,
-            &payload.player_id,
-            &payload.map_id,
-        );
+/// The finishes of a bulk submission as XML, a `<finish>` element for each.
+pub fn finishes_to_xml(results: &[HasFinishedResult]) -> Result<String, xml::Error> {
+    let mut writer = xml::Writer::new(Vec::new())?;
+    for result in results {
+        writer.write_named("finish", result)?;
+    }
 
-        let result = match finished {
-            Ok((is_new_best, old, new)) => HasFinishedResult {
-                is_new_best,
-                old,
-                new,
-                login: String::from(&payload.player_id),
-            },
-            Err(_) => return Err(error::BlockingError::Error(())),
-        };
+    let bytes = writer.finish()?;
+    Ok(String::from_utf8(bytes).expect("the xml writer only writes utf-8"))
+}
+
+/// Saves the finishes of a round at once. Nothing is saved if any of them is
+/// invalid or comes from a banned player.
+pub fn has_finished_bulk_route(
+    req: HttpRequest,
+    payload: web::Json<Vec<HasFinishedPayload>>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let format = Format::from_request(&req);
+
+    web::block(move || -> Result<String, ApiError> {
+        if payload.len() > MAX_BULK_FINISHES {
+            return Err(ApiError::validation(format!(
+                "At most {} finishes can be sent at once",
+                MAX_BULK_FINISHES
+            )));
+        }
+
+        let conn = state.pool.get()?;
+
+        for (idx, finish) in payload.iter().enumerate() {
+            finish
+                .validate()
+                .and_then(|_| {
+                    if models::player::Player::is_banned(&conn, &finish.player_id)? {
+                        Err(ApiError::banned())
+                    } else {
+                        Ok(())
+                    }
+                })
+                .map_err(|mut e| {
+                    metrics::REJECTED_FINISHES
+                        .with_label_values(&[e.code.as_str()])
+                        .inc();
+                    e.message = format!("Finish {}: {}", idx, e.message);
+                    e
+                })?;
+        }
+
+        let finishes: Vec<_> = payload.iter().map(HasFinishedPayload::to_finish).collect();
+        let results: Vec<_> = records_api::have_finished(&conn, &finishes)?
+            .into_iter()
+            .zip(payload.iter())
+            .map(|(finish, payload)| HasFinishedResult::new(payload.player_id.clone(), finish))
+            .collect();
 
-        match serde_xml_rs::to_string(&result) {
-            Ok(body) => Ok(body),
-            Err(_) => Err(error::BlockingError::Error(())),
+        match format {
+            Format::Xml => Ok(finishes_to_xml(&results)?),
+            Format::Json => format::to_json(&results),
         }
     })
-    // then we can send the response
-    .then(string_to_xml_response)
+    .then(move |res| format.respond(res))
 }
 
 #[derive(Deserialize)]
@@ -93,27 +398,111 @@ pub struct OverviewQuery {
     pub map_id: String,
     #[serde(alias = "playerId")]
     pub player_id: String,
+    /// Only keeps the players of this zone and its subzones, ranked among
+    /// themselves
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Rows of the overview, including the one of the player
+    #[serde(default)]
+    pub rows: Option<usize>,
+    /// Best records always shown
+    #[serde(default)]
+    pub top: Option<usize>,
+    /// Records shown before and after the one of the player
+    #[serde(default)]
+    pub around: Option<usize>,
+}
+
+/// Most rows of an overview.
+const MAX_OVERVIEW_ROWS: usize = 50;
+
+impl OverviewQuery {
+    /// The requested layout, the default one filling the missing values.
+    pub fn layout(&self) -> Result<OverviewLayout, ApiError> {
+        let default = OverviewLayout::default();
+        let layout = OverviewLayout {
+            rows: self.rows.unwrap_or(default.rows),
+            top: self.top.unwrap_or(default.top),
+            around: self.around.unwrap_or(default.around),
+        };
+
+        if !(1..=MAX_OVERVIEW_ROWS).contains(&layout.rows) {
+            return Err(ApiError::validation(format!(
+                "rows must be between 1 and {}",
+                MAX_OVERVIEW_ROWS
+            )));
+        }
+        if layout.top >= layout.rows {
+            return Err(ApiError::validation("top must be less than rows"));
+        }
+        if layout.around >= layout.rows {
+            return Err(ApiError::validation("around must be less than rows"));
+        }
+
+        Ok(layout)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename = "total")]
+struct Total {
+    #[serde(rename = "$value")]
+    count: usize,
+}
+
+/// The overview as XML: the records, then their total count.
+pub fn overview_to_xml(overview: &Overview) -> Result<String, xml::Error> {
+    let mut writer = xml::Writer::new(Vec::new())?;
+    for record in &overview.records {
+        writer.write(record)?;
+    }
+    writer.write(&Total {
+        count: overview.total,
+    })?;
+
+    let bytes = writer.finish()?;
+    Ok(String::from_utf8(bytes).expect("the xml writer only writes utf-8"))
+}
+
+/// Checks a zone filter.
+fn validate_zone(zone: &Option<String>) -> Result<(), ApiError> {
+    match zone {
+        Some(zone) if !models::player::is_valid_zone_path(zone) => {
+            Err(ApiError::validation(format!("Invalid zone: {:?}", zone)))
+        }
+        _ => Ok(()),
+    }
 }
 
 pub fn overview_route(
+    req: HttpRequest,
     parameters: web::Query<OverviewQuery>,
     state: web::Data<Arc<AppState>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let format = Format::from_request(&req);
+
     // First we block during the access to the database
-    web::block(move || {
-        let conn: &MysqlConnection = &state.pool.get().unwrap();
-        let result = records_api::overview(conn, &parameters.player_id, &parameters.map_id);
-
-        match result {
-            Ok(records) => Ok(xml::to_string(records)),
-            Err(e) => {
-                eprintln!("Error: {}", e.to_string());
-                Err(error::BlockingError::Error(()))
-            }
+    web::block(move || -> Result<String, ApiError> {
+        validate_map_id(&parameters.map_id)?;
+        validate_login(&parameters.player_id)?;
+        validate_zone(&parameters.zone)?;
+        let layout = parameters.layout()?;
+
+        let conn = state.pool.get()?;
+        let overview = records_api::overview(
+            &conn,
+            &parameters.player_id,
+            &parameters.map_id,
+            parameters.zone.as_deref(),
+            layout,
+        )?;
+        match format {
+            Format::Xml => Ok(overview_to_xml(&overview)?),
+            Format::Json => format::to_json(&rest::OverviewJson::from(overview)),
         }
     })
     // then we can send the response
-    .then(string_to_xml_response)
+    .then(move |res| format.respond(res))
 }
 
 pub fn ok_stub() -> HttpResponse {
@@ -121,33 +510,179 @@ pub fn ok_stub() -> HttpResponse {
 }
 
 pub fn player_replace_or_create(
+    req: HttpRequest,
     data: web::Json<models::player::Player>,
     state: web::Data<Arc<AppState>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let format = Format::from_request(&req);
+
     // First we block during the access to the database
-    web::block(move || {
-        let conn: &MysqlConnection = &state.pool.get().unwrap();
-        match data.insert_or_replace(conn) {
-            Ok(_size) => Ok(String::from("<response><id>ok</id></response>")),
-            Err(_) => Err(error::BlockingError::Error(())),
-        }
+    web::block(move || -> Result<String, ApiError> {
+        validate_player(&data)?;
+
+        let conn = state.pool.get()?;
+        data.insert_or_replace(&conn)?;
+        ok_body(format)
     })
     // then we can send the response
-    .then(string_to_xml_response)
+    .then(move |res| format.respond(res))
 }
 
 pub fn map_replace_or_create(
+    req: HttpRequest,
     data: web::Json<models::map::Map>,
     state: web::Data<Arc<AppState>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let format = Format::from_request(&req);
+
     // First we block during the access to the database
-    web::block(move || {
-        let conn: &MysqlConnection = &state.pool.get().unwrap();
-        match data.insert_or_replace(conn) {
-            Ok(_size) => Ok(String::from("<response><id>ok</id></response>")),
-            Err(_) => Err(error::BlockingError::Error(())),
-        }
+    web::block(move || -> Result<String, ApiError> {
+        validate_map(&data)?;
+
+        let conn = state.pool.get()?;
+        data.insert_or_replace(&conn)?;
+        ok_body(format)
     })
     // then we can send the response
-    .then(string_to_xml_response)
+    .then(move |res| format.respond(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::map::Map;
+    use crate::models::player::Player;
+
+    fn map() -> Map {
+        Map {
+            maniaplanet_map_id: String::from("NullId"),
+            name: String::from("NullId"),
+            player_id: String::from("gotatang"),
+            ..Default::default()
+        }
+    }
+
+    fn overview_query(
+        rows: Option<usize>,
+        top: Option<usize>,
+        around: Option<usize>,
+    ) -> OverviewQuery {
+        OverviewQuery {
+            map_id: String::from("NullId"),
+            player_id: String::from("smokegun"),
+            zone: None,
+            rows,
+            top,
+            around,
+        }
+    }
+
+    #[test]
+    fn test_overview_layout() {
+        assert_eq!(
+            overview_query(None, None, None).layout().unwrap(),
+            OverviewLayout::default()
+        );
+        assert_eq!(
+            overview_query(Some(8), Some(1), Some(2)).layout().unwrap(),
+            OverviewLayout {
+                rows: 8,
+                top: 1,
+                around: 2
+            }
+        );
+
+        assert!(overview_query(Some(0), Some(0), None).layout().is_err());
+        assert!(overview_query(Some(51), None, None).layout().is_err());
+        assert!(overview_query(Some(5), Some(5), None).layout().is_err());
+        assert!(overview_query(Some(5), Some(1), Some(5)).layout().is_err());
+    }
+
+    #[test]
+    fn test_validate_player_profile() {
+        let mut player = Player {
+            login: String::from("smokegun"),
+            nickname: String::from("SmokeGun"),
+            ..Default::default()
+        };
+        assert!(validate_player(&player).is_ok());
+
+        player.zone_path = Some(String::from("World|Europe|France"));
+        player.language = Some(String::from("fr-FR"));
+        player.avatar = Some(String::from("Skins/Avatars/smokegun.dds"));
+        assert!(validate_player(&player).is_ok());
+
+        player.zone_path = Some(String::from("World||France"));
+        assert!(validate_player(&player).is_err());
+        player.zone_path = None;
+
+        player.language = Some(String::from("fr FR"));
+        assert!(validate_player(&player).is_err());
+    }
+
+    #[test]
+    fn test_validate_finish_replay_fields() {
+        let mut finish = HasFinishedPayload {
+            time: 72000,
+            respawn_count: 0,
+            map_id: String::from("NullId"),
+            player_id: String::from("smokegun"),
+            idempotency_key: Some(String::from("server-1_42")),
+            finished_at: Some(Utc::now().naive_utc() - Duration::hours(2)),
+        };
+        assert!(finish.validate().is_ok());
+
+        finish.idempotency_key = Some(String::new());
+        assert!(finish.validate().is_err());
+        finish.idempotency_key = Some("k".repeat(65));
+        assert!(finish.validate().is_err());
+        finish.idempotency_key = Some(String::from("server 1"));
+        assert!(finish.validate().is_err());
+        finish.idempotency_key = None;
+
+        finish.finished_at = Some(Utc::now().naive_utc() + Duration::hours(1));
+        assert!(finish.validate().is_err());
+        finish.finished_at = Some(Utc::now().naive_utc() - Duration::days(8));
+        assert!(finish.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_map_without_metadata() {
+        assert!(validate_map(&map()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_map_medals() {
+        let valid = Map {
+            author_time: Some(30000),
+            gold_time: Some(32000),
+            bronze_time: Some(45000),
+            checkpoint_count: Some(4),
+            file_hash: Some(String::from("0123456789ABCDEF")),
+            ..map()
+        };
+        assert!(validate_map(&valid).is_ok());
+
+        let gold_before_author = Map {
+            author_time: Some(30000),
+            gold_time: Some(29000),
+            ..map()
+        };
+        assert!(validate_map(&gold_before_author).is_err());
+
+        let negative = Map {
+            silver_time: Some(-1),
+            ..map()
+        };
+        assert!(validate_map(&negative).is_err());
+    }
+
+    #[test]
+    fn test_validate_map_hash() {
+        let not_hex = Map {
+            file_hash: Some(String::from("not a hash")),
+            ..map()
+        };
+        assert!(validate_map(&not_hex).is_err());
+    }
 }
@@ -1,13 +1,19 @@
 use crate::models::map::Map;
+use crate::models::medal::MedalCount;
+use crate::models::nickname::NicknameChange;
 use crate::models::player::Player;
 use crate::models::record::Record;
+use crate::models::stats::{ImprovedMap, PlayerStats};
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use juniper::{EmptyMutation, FieldResult, RootNode};
 use std::convert::TryInto;
 
 use crate::app_state::Pool;
+use crate::records_api;
 use crate::schema;
+use crate::search;
+use crate::{escape, mp_text};
 
 #[derive(Clone)]
 pub struct DbContext(pub Pool);
@@ -16,6 +22,18 @@ impl juniper::Context for DbContext {}
 
 pub struct QueryRoot;
 
+/// Offset and limit of a search, 20 results by default and 100 at most. A
+/// search has at most `search::MAX_CANDIDATES` results, a page starting
+/// after them is refused.
+fn search_page(offset: Option<i32>, limit: Option<i32>) -> FieldResult<(i64, i64)> {
+    let offset = i64::from(offset.unwrap_or(0).max(0));
+    let limit = i64::from(limit.unwrap_or(20).clamp(0, 100));
+    if offset >= search::MAX_CANDIDATES as i64 {
+        return Err(format!("offset must be below {}", search::MAX_CANDIDATES).into());
+    }
+    Ok((offset, limit))
+}
+
 #[juniper::object(Context = DbContext,)]
 impl QueryRoot {
     fn players(&self, context: &DbContext) -> FieldResult<Vec<Player>> {
@@ -41,6 +59,52 @@ impl QueryRoot {
         Ok(schema::maps::table.find(&id).get_result(conn).optional()?)
     }
 
+    /// Players whose nickname matches the query, best match first, 200 at
+    /// most
+    fn search_players(
+        &self,
+        context: &DbContext,
+        query: String,
+        offset: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<Player>> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        let (offset, limit) = search_page(offset, limit)?;
+        Ok(search::search_players(conn, &query, offset, limit)?)
+    }
+
+    /// Maps whose name matches the query, best match first, 200 at most
+    fn search_maps(
+        &self,
+        context: &DbContext,
+        query: String,
+        offset: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<Map>> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        let (offset, limit) = search_page(offset, limit)?;
+        Ok(search::search_maps(conn, &query, offset, limit)?)
+    }
+
+    /// The players with the most medals, only the ones of the zone and its
+    /// subzones when a zone is given
+    fn medal_leaderboard(
+        &self,
+        context: &DbContext,
+        offset: Option<i32>,
+        limit: Option<i32>,
+        zone: Option<String>,
+    ) -> FieldResult<Vec<MedalCount>> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        let (offset, limit) = search_page(offset, limit)?;
+        Ok(records_api::medal_leaderboard(
+            conn,
+            offset,
+            limit,
+            zone.as_deref(),
+        )?)
+    }
+
     fn record(
         &self,
         context: &DbContext,
@@ -73,6 +137,35 @@ impl Player {
         self.nickname.as_str()
     }
 
+    /// The nickname without formatting codes
+    fn nickname_plain(&self) -> &str {
+        self.nickname_plain.as_str()
+    }
+
+    /// The nickname rendered as HTML
+    fn nickname_html(&self) -> String {
+        mp_text::to_html(&escape::unescape(&self.nickname))
+    }
+
+    /// The zones of the player separated by `|`, from the world to the most
+    /// local one
+    fn zone_path(&self) -> Option<&str> {
+        self.zone_path.as_deref()
+    }
+
+    fn zones(&self) -> Vec<&str> {
+        Player::zones(self)
+    }
+
+    fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// The path or URL of the avatar image
+    fn avatar(&self) -> Option<&str> {
+        self.avatar.as_deref()
+    }
+
     fn maps(&self, context: &DbContext) -> FieldResult<Vec<Map>> {
         let conn: &MysqlConnection = &context.0.get().unwrap();
         Ok(schema::maps::table
@@ -80,6 +173,49 @@ impl Player {
             .load(conn)?)
     }
 
+    /// The nicknames used by the player, the current one first
+    fn nickname_history(
+        &self,
+        context: &DbContext,
+        offset: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<NicknameChange>> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        let (offset, limit) = search_page(offset, limit)?;
+        Ok(NicknameChange::history(conn, &self.login, offset, limit)?)
+    }
+
+    fn medals(&self, context: &DbContext) -> FieldResult<MedalCount> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        Ok(
+            records_api::player_medals(conn, &self.login)?.unwrap_or(MedalCount {
+                player_id: self.login.clone(),
+                nickname: self.nickname.clone(),
+                author: 0,
+                gold: 0,
+                silver: 0,
+                bronze: 0,
+            }),
+        )
+    }
+
+    fn stats(&self, context: &DbContext) -> FieldResult<PlayerStats> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        Ok(PlayerStats::of(conn, &self.login)?)
+    }
+
+    /// The maps where the player gained the most time since their first
+    /// finish, 5 by default and 20 at most
+    fn most_improved_maps(
+        &self,
+        context: &DbContext,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<ImprovedMap>> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        let limit = limit.unwrap_or(5).clamp(0, 20) as usize;
+        Ok(ImprovedMap::of_player(conn, &self.login, limit)?)
+    }
+
     fn records(&self, context: &DbContext) -> FieldResult<Vec<Record>> {
         let conn: &MysqlConnection = &context.0.get().unwrap();
         Ok(schema::records::table
@@ -100,6 +236,58 @@ impl Map {
         self.name.as_str()
     }
 
+    /// The name without formatting codes
+    fn name_plain(&self) -> &str {
+        self.name_plain.as_str()
+    }
+
+    /// The name rendered as HTML
+    fn name_html(&self) -> String {
+        mp_text::to_html(&escape::unescape(&self.name))
+    }
+
+    /// Time of the author in milliseconds
+    fn author_time(&self) -> Option<i32> {
+        self.author_time
+    }
+
+    fn gold_time(&self) -> Option<i32> {
+        self.gold_time
+    }
+
+    fn silver_time(&self) -> Option<i32> {
+        self.silver_time
+    }
+
+    fn bronze_time(&self) -> Option<i32> {
+        self.bronze_time
+    }
+
+    fn environment(&self) -> Option<&str> {
+        self.environment.as_deref()
+    }
+
+    fn title_pack(&self) -> Option<&str> {
+        self.title_pack.as_deref()
+    }
+
+    fn checkpoint_count(&self) -> Option<i32> {
+        self.checkpoint_count
+    }
+
+    fn map_type(&self) -> Option<&str> {
+        self.map_type.as_deref()
+    }
+
+    fn file_hash(&self) -> Option<&str> {
+        self.file_hash.as_deref()
+    }
+
+    /// When the current file of the map was first saved
+    fn uploaded_at(&self) -> Option<NaiveDateTime> {
+        self.uploaded_at
+    }
+
     fn author(&self, context: &DbContext) -> FieldResult<Player> {
         let conn: &MysqlConnection = &context.0.get().unwrap();
         Ok(schema::players::table
@@ -155,6 +343,112 @@ impl Record {
     fn updated_at(&self) -> NaiveDateTime {
         self.updated_at
     }
+
+    /// The best medal earned: author, gold, silver or bronze
+    fn medal(&self) -> Option<&str> {
+        self.medal.as_deref()
+    }
+
+    /// The time of the first finish, unknown for the oldest records
+    fn first_time(&self) -> Option<i32> {
+        self.first_time
+    }
+}
+
+#[juniper::object(Context = DbContext,)]
+impl MedalCount {
+    fn player(&self, context: &DbContext) -> FieldResult<Player> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        Ok(schema::players::table
+            .find(&self.player_id)
+            .get_result(conn)?)
+    }
+
+    fn author(&self) -> i32 {
+        self.author as i32
+    }
+
+    fn gold(&self) -> i32 {
+        self.gold as i32
+    }
+
+    fn silver(&self) -> i32 {
+        self.silver as i32
+    }
+
+    fn bronze(&self) -> i32 {
+        self.bronze as i32
+    }
+}
+
+#[juniper::object]
+impl PlayerStats {
+    fn record_count(&self) -> i32 {
+        self.record_count
+    }
+
+    /// First places, ties included
+    fn world_record_count(&self) -> i32 {
+        self.world_record_count
+    }
+
+    fn top_ten_count(&self) -> i32 {
+        self.top_ten_count
+    }
+
+    fn average_rank(&self) -> Option<f64> {
+        self.average_rank
+    }
+
+    fn median_rank(&self) -> Option<f64> {
+        self.median_rank
+    }
+
+    /// A float, the sum of the tries not fitting in a GraphQL `Int`
+    fn total_tries(&self) -> f64 {
+        self.total_tries as f64
+    }
+
+    fn first_activity(&self) -> Option<NaiveDateTime> {
+        self.first_activity
+    }
+
+    fn last_activity(&self) -> Option<NaiveDateTime> {
+        self.last_activity
+    }
+}
+
+#[juniper::object(Context = DbContext,)]
+impl ImprovedMap {
+    fn map(&self, context: &DbContext) -> FieldResult<Map> {
+        let conn: &MysqlConnection = &context.0.get().unwrap();
+        Ok(schema::maps::table.find(&self.map_id).get_result(conn)?)
+    }
+
+    fn first_time(&self) -> i32 {
+        self.first_time
+    }
+
+    fn time(&self) -> i32 {
+        self.time
+    }
+
+    /// Milliseconds gained since the first finish
+    fn improvement(&self) -> i32 {
+        self.improvement
+    }
+}
+
+#[juniper::object]
+impl NicknameChange {
+    fn nickname(&self) -> &str {
+        self.nickname.as_str()
+    }
+
+    /// Unknown for the nicknames used before the history was kept
+    fn changed_at(&self) -> Option<NaiveDateTime> {
+        self.changed_at
+    }
 }
 
 pub type Schema = RootNode<'static, QueryRoot, EmptyMutation<DbContext>>;
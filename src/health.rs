@@ -0,0 +1,101 @@
+//! Health and readiness probes.
+//!
+//! `/healthz` only tells that the process is up, `/readyz` checks that the
+//! service can actually answer requests: a database connection can be taken
+//! from the pool, every migration had been applied when the server started
+//! and the statistics of the players have been cached since the start. The
+//! probes only read: the migrations are not looked at again.
+
+use crate::app_state::AppState;
+use actix_web::{web, Error, HttpResponse};
+use futures::Future;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long the readiness probe waits for a database connection.
+const POOL_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct HealthStatus {
+    pub status: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessStatus {
+    pub status: &'static str,
+    /// Result of each check, `ok` or the reason of the failure
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+impl ReadinessStatus {
+    pub fn is_ready(&self) -> bool {
+        self.checks.values().all(|result| result == "ok")
+    }
+}
+
+pub fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(HealthStatus { status: "ok" })
+}
+
+fn check_readiness(state: &AppState) -> ReadinessStatus {
+    let mut checks = BTreeMap::new();
+
+    let database = match state.pool.get_timeout(POOL_TIMEOUT) {
+        Ok(_) => String::from("ok"),
+        Err(e) => e.to_string(),
+    };
+    checks.insert("database", database);
+
+    let migrations = if state.pending_migrations.is_empty() {
+        String::from("ok")
+    } else {
+        format!("pending: {}", state.pending_migrations.join(", "))
+    };
+    checks.insert("migrations", migrations);
+
+    let cache = if state.cache_warmed.load(Ordering::SeqCst) {
+        "ok"
+    } else {
+        "warming up"
+    };
+    checks.insert("cache", String::from(cache));
+
+    let mut status = ReadinessStatus {
+        status: "ready",
+        checks,
+    };
+    if !status.is_ready() {
+        status.status = "unavailable";
+    }
+    status
+}
+
+pub fn readyz(state: web::Data<Arc<AppState>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || Ok::<_, ()>(check_readiness(&state))).then(|res| match res {
+        Ok(status) if status.is_ready() => Ok(HttpResponse::Ok().json(status)),
+        Ok(status) => Ok(HttpResponse::ServiceUnavailable().json(status)),
+        Err(_) => Ok(HttpResponse::ServiceUnavailable().json(HealthStatus {
+            status: "unavailable",
+        })),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[test]
+    fn test_healthz() {
+        let mut app = test::init_service(
+            App::new().service(web::resource("/healthz").route(web::get().to(healthz))),
+        );
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+
+        let body = test::read_response(&mut app, req);
+        assert_eq!(body, r#"{"status":"ok"}"#.as_bytes());
+    }
+}
@@ -0,0 +1,860 @@
+//! Import of records from the dumps of the old LoopBack API and of other
+//! record systems.
+//!
+//! A dump is a list of records, in JSON (an array of objects or one object
+//! per line) or in CSV with a header line. Each row names a map and a player,
+//! and may carry the name and author of the map and the nickname of the
+//! player. A `Mapping` tells in which fields they are; the default one reads
+//! the LoopBack names, which are also the ones of the exports.
+//!
+//! Ids are compared without case, like the database does. Missing players and
+//! maps are created, the existing ones are left as they are since they are
+//! newer than any dump. An imported record replaces the existing one only if
+//! its time is better, keeping the earlier creation date. The records of
+//! banned players are left out. The maps with new records are re-ranked, and a
+//! world record is saved for those whose first place was beaten.
+
+use crate::escape::Escape;
+use crate::game;
+use crate::models::map::Map;
+use crate::models::medal::Medal;
+use crate::models::nickname::NicknameChange;
+use crate::models::player::Player;
+use crate::models::record::Record;
+use crate::models::world_record::WorldRecord;
+use crate::mp_text;
+use crate::records_api;
+use crate::search;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde_derive::Deserialize;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceFormat {
+    /// An array of objects, or one object per line
+    Json,
+    /// A header line, then one record per line
+    Csv,
+}
+
+impl FromStr for SourceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" | "ndjson" => Ok(SourceFormat::Json),
+            "csv" => Ok(SourceFormat::Csv),
+            _ => Err(format!("unknown source format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeUnit {
+    #[default]
+    Milliseconds,
+    Centiseconds,
+    /// Possibly with decimals, e.g. `61.234`
+    Seconds,
+}
+
+/// The names of the fields of a dump, read from a JSON file such as
+/// `{"mapId": "Challenge", "playerId": "Login", "timeUnit": "centiseconds"}`.
+/// The fields not given keep their default name.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct Mapping {
+    pub map_id: String,
+    pub map_name: String,
+    pub map_author: String,
+    pub player_id: String,
+    pub nickname: String,
+    pub time: String,
+    pub respawn_count: String,
+    pub try_count: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub time_unit: TimeUnit,
+}
+
+impl Default for Mapping {
+    fn default() -> Self {
+        Mapping {
+            map_id: String::from("mapId"),
+            map_name: String::from("mapName"),
+            map_author: String::from("mapAuthor"),
+            player_id: String::from("playerId"),
+            nickname: String::from("nickname"),
+            time: String::from("time"),
+            respawn_count: String::from("respawnCount"),
+            try_count: String::from("tryCount"),
+            created_at: String::from("createdAt"),
+            updated_at: String::from("updatedAt"),
+            time_unit: TimeUnit::Milliseconds,
+        }
+    }
+}
+
+/// The fields of a row of a dump, the empty ones left out.
+pub type Fields = HashMap<String, String>;
+
+/// A row of a dump with its number (the line in the file, or the position in
+/// a JSON array), for the report.
+pub type SourceRow = (usize, Fields);
+
+/// Reads the rows of a dump.
+pub fn read_rows(format: SourceFormat, text: &str) -> Result<Vec<SourceRow>, String> {
+    match format {
+        SourceFormat::Json => read_json(text),
+        SourceFormat::Csv => read_csv(text),
+    }
+}
+
+fn json_fields(object: serde_json::Map<String, serde_json::Value>) -> Fields {
+    object
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::Null => return None,
+                serde_json::Value::String(s) => s,
+                value => value.to_string(),
+            };
+            Some((key, value)).filter(|(_, value)| !value.is_empty())
+        })
+        .collect()
+}
+
+fn read_json(text: &str) -> Result<Vec<SourceRow>, String> {
+    if text.trim_start().starts_with('[') {
+        let objects: Vec<serde_json::Map<String, serde_json::Value>> =
+            serde_json::from_str(text).map_err(|e| e.to_string())?;
+        return Ok(objects
+            .into_iter()
+            .enumerate()
+            .map(|(i, object)| (i + 1, json_fields(object)))
+            .collect());
+    }
+
+    let mut rows = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object = serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        rows.push((i + 1, json_fields(object)));
+    }
+    Ok(rows)
+}
+
+/// Splits CSV text in lines of fields (RFC 4180), each with the number of
+/// the line where it starts. Quoted fields may hold line breaks.
+fn csv_lines(text: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut lines = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut line_number = 1;
+    let mut start = 1;
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            '\n' if quoted => {
+                line_number += 1;
+                field.push(c);
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                lines.push((start, std::mem::take(&mut fields)));
+                line_number += 1;
+                start = line_number;
+            }
+            _ => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(format!("line {}: unterminated quoted field", start));
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        lines.push((start, fields));
+    }
+    Ok(lines
+        .into_iter()
+        .filter(|(_, fields)| fields.iter().any(|field| !field.is_empty()))
+        .collect())
+}
+
+fn read_csv(text: &str) -> Result<Vec<SourceRow>, String> {
+    let mut lines = csv_lines(text)?.into_iter();
+    let header = match lines.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+
+    lines
+        .map(|(number, values)| {
+            if values.len() != header.len() {
+                return Err(format!(
+                    "line {}: {} fields instead of {}",
+                    number,
+                    values.len(),
+                    header.len()
+                ));
+            }
+            let fields = header
+                .iter()
+                .cloned()
+                .zip(values)
+                .filter(|(_, value)| !value.is_empty())
+                .collect();
+            Ok((number, fields))
+        })
+        .collect()
+}
+
+/// Reads a date as RFC 3339 (`2019-04-26T15:49:39.000Z`), as UTC without
+/// offset (`2019-04-26T15:49:39` or `2019-04-26 15:49:39`, with optional
+/// fractions of seconds) or as a UNIX timestamp in seconds.
+pub fn parse_timestamp(text: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Ok(date.naive_utc());
+    }
+    for format in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(date);
+        }
+    }
+    text.parse()
+        .ok()
+        .and_then(|seconds| NaiveDateTime::from_timestamp_opt(seconds, 0))
+        .ok_or_else(|| format!("invalid date {:?}", text))
+}
+
+fn parse_time(text: &str, unit: TimeUnit) -> Result<i32, String> {
+    let invalid = || format!("invalid time {:?}", text);
+    let time = match unit {
+        TimeUnit::Milliseconds => text.parse::<i32>().map_err(|_| invalid())?,
+        TimeUnit::Centiseconds => text
+            .parse::<i32>()
+            .ok()
+            .and_then(|time| time.checked_mul(10))
+            .ok_or_else(invalid)?,
+        TimeUnit::Seconds => {
+            let seconds = text.parse::<f64>().map_err(|_| invalid())?;
+            let time = (seconds * 1000.0).round();
+            if !time.is_finite() || time > f64::from(i32::MAX) {
+                return Err(invalid());
+            }
+            time as i32
+        }
+    };
+    if time <= 0 {
+        return Err(invalid());
+    }
+    Ok(time)
+}
+
+/// A record read from a dump. The names are raw, as the game sends them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LegacyRecord {
+    pub map_id: String,
+    pub map_name: Option<String>,
+    pub map_author: Option<String>,
+    pub player_id: String,
+    pub nickname: Option<String>,
+    pub time: i32,
+    pub respawn_count: i32,
+    pub try_count: i32,
+    pub created_at: Option<NaiveDateTime>,
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+impl LegacyRecord {
+    pub fn from_fields(mapping: &Mapping, fields: &Fields) -> Result<Self, String> {
+        let get = |name: &String| fields.get(name).map(|value| value.trim().to_owned());
+        let required = |name: &String| get(name).ok_or_else(|| format!("missing field {:?}", name));
+        let count = |name: &String, default: i32| match get(name) {
+            Some(value) => value
+                .parse::<i32>()
+                .ok()
+                .filter(|count| *count >= 0)
+                .ok_or_else(|| format!("invalid {} {:?}", name, value)),
+            None => Ok(default),
+        };
+        let date = |name: &String| get(name).map(|value| parse_timestamp(&value)).transpose();
+
+        let map_id = required(&mapping.map_id)?;
+        game::validate_map_id(&map_id).map_err(|e| e.message)?;
+        let player_id = required(&mapping.player_id)?;
+        game::validate_login(&player_id).map_err(|e| e.message)?;
+        let map_author = get(&mapping.map_author);
+        if let Some(ref author) = map_author {
+            game::validate_login(author).map_err(|e| e.message)?;
+        }
+
+        Ok(LegacyRecord {
+            map_id,
+            map_name: get(&mapping.map_name),
+            map_author,
+            player_id,
+            nickname: get(&mapping.nickname),
+            time: parse_time(&required(&mapping.time)?, mapping.time_unit)?,
+            respawn_count: count(&mapping.respawn_count, 0)?,
+            try_count: count(&mapping.try_count, 1)?,
+            created_at: date(&mapping.created_at)?,
+            updated_at: date(&mapping.updated_at)?,
+        })
+    }
+
+    /// Whether this record is better than another one of the same player on
+    /// the same map, the oldest one winning ties.
+    fn beats(&self, other: &LegacyRecord) -> bool {
+        self.time < other.time
+            || (self.time == other.time
+                && self.updated_at.or(self.created_at) < other.updated_at.or(other.created_at))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LegacyReport {
+    /// Number of rows read
+    pub rows: usize,
+    /// The rows not imported, with their number and the reason
+    pub skipped: Vec<(usize, String)>,
+    /// Rows left out for a better one of the same player on the same map
+    pub duplicates: usize,
+    /// New maps without author, whose records are left out
+    pub unknown_maps: Vec<String>,
+    /// Banned players, whose records are left out
+    pub banned_players: Vec<String>,
+    pub new_players: usize,
+    pub new_maps: usize,
+    pub new_records: usize,
+    /// Existing records replaced by a better imported time
+    pub improved_records: usize,
+    /// Records already as good in the database
+    pub kept_records: usize,
+}
+
+/// The key of an id in a `Source`, ids differing only by their case being the
+/// same for the database.
+fn key(id: &str) -> String {
+    id.to_lowercase()
+}
+
+/// The best record of each player on each map, and the name and author of
+/// each map and the nickname of each player, as found in the rows. They are
+/// keyed by `key`, the records by the keys of their map and player.
+#[derive(Debug, Default)]
+struct Source {
+    records: BTreeMap<(String, String), LegacyRecord>,
+    map_names: HashMap<String, String>,
+    map_authors: HashMap<String, String>,
+    nicknames: HashMap<String, String>,
+}
+
+fn collect(mapping: &Mapping, rows: &[SourceRow], report: &mut LegacyReport) -> Source {
+    let mut source = Source::default();
+    report.rows = rows.len();
+
+    for (number, fields) in rows {
+        let record = match LegacyRecord::from_fields(mapping, fields) {
+            Ok(record) => record,
+            Err(e) => {
+                report.skipped.push((*number, e));
+                continue;
+            }
+        };
+
+        if let Some(ref name) = record.map_name {
+            source
+                .map_names
+                .entry(key(&record.map_id))
+                .or_insert_with(|| name.clone());
+        }
+        if let Some(ref author) = record.map_author {
+            source
+                .map_authors
+                .entry(key(&record.map_id))
+                .or_insert_with(|| author.clone());
+        }
+        if let Some(ref nickname) = record.nickname {
+            source
+                .nicknames
+                .entry(key(&record.player_id))
+                .or_insert_with(|| nickname.clone());
+        }
+
+        let key = (key(&record.map_id), key(&record.player_id));
+        match source.records.get(&key) {
+            Some(best) if !record.beats(best) => {}
+            _ => {
+                source.records.insert(key, record);
+            }
+        }
+    }
+
+    report.duplicates = report.rows - report.skipped.len() - source.records.len();
+    source
+}
+
+/// Imports the rows of a dump in one transaction. A dry run makes the same
+/// import and rolls it back, so its report is exact.
+pub fn import(
+    conn: &MysqlConnection,
+    mapping: &Mapping,
+    rows: &[SourceRow],
+    dry_run: bool,
+) -> QueryResult<LegacyReport> {
+    let mut report = LegacyReport::default();
+    let source = collect(mapping, rows, &mut report);
+
+    let result = conn.transaction(|| {
+        apply(conn, &source, &mut report)?;
+        if dry_run {
+            Err(diesel::result::Error::RollbackTransaction)
+        } else {
+            Ok(())
+        }
+    });
+
+    match result {
+        Ok(()) => Ok(report),
+        Err(diesel::result::Error::RollbackTransaction) if dry_run => Ok(report),
+        Err(e) => Err(e),
+    }
+}
+
+fn insert_player(conn: &MysqlConnection, login: &str, nickname: &str) -> QueryResult<()> {
+    use crate::schema::players;
+
+    let escaped_nick = format!("{}", Escape(nickname));
+    let plain_nick = mp_text::plain_text(nickname);
+    diesel::insert_into(players::table)
+        .values((
+            players::login.eq(login),
+            players::nickname.eq(&escaped_nick),
+            players::nickname_plain.eq(&plain_nick),
+        ))
+        .execute(conn)?;
+    NicknameChange::record(conn, login, &escaped_nick)?;
+    search::index_name(conn, search::Kind::Player, login, &plain_nick)?;
+    Ok(())
+}
+
+fn insert_map(conn: &MysqlConnection, map_id: &str, name: &str, author: &str) -> QueryResult<()> {
+    use crate::schema::maps;
+
+    let name_plain = mp_text::plain_text(name);
+    diesel::insert_into(maps::table)
+        .values((
+            maps::maniaplanet_map_id.eq(map_id),
+            maps::name.eq(format!("{}", Escape(name))),
+            maps::name_plain.eq(&name_plain),
+            maps::player_id.eq(author),
+        ))
+        .execute(conn)?;
+    search::index_name(conn, search::Kind::Map, map_id, &name_plain)?;
+    Ok(())
+}
+
+fn apply(conn: &MysqlConnection, source: &Source, report: &mut LegacyReport) -> QueryResult<()> {
+    use crate::schema::{maps, players, records};
+
+    // A new map needs an author, its records are skipped without one
+    let map_ids: BTreeMap<String, &str> = source
+        .records
+        .values()
+        .map(|record| (key(&record.map_id), record.map_id.as_str()))
+        .collect();
+    let mut new_maps = BTreeMap::new();
+    let mut unknown_maps = BTreeSet::new();
+    for (map_key, map_id) in &map_ids {
+        let exists: Option<Map> = maps::table.find(map_id).get_result(conn).optional()?;
+        if exists.is_some() {
+            continue;
+        }
+        match source.map_authors.get(map_key) {
+            Some(author) => {
+                new_maps.insert(*map_id, author.as_str());
+            }
+            None => {
+                unknown_maps.insert(map_key.as_str());
+            }
+        }
+    }
+    report.unknown_maps = unknown_maps
+        .iter()
+        .map(|map_key| map_ids[*map_key].to_string())
+        .collect();
+
+    let logins: BTreeMap<String, &str> = source
+        .records
+        .values()
+        .filter(|record| !unknown_maps.contains(key(&record.map_id).as_str()))
+        .map(|record| record.player_id.as_str())
+        .chain(new_maps.values().cloned())
+        .map(|login| (key(login), login))
+        .collect();
+    let mut banned_players = BTreeSet::new();
+    for (login_key, login) in &logins {
+        let exists: Option<Player> = players::table.find(login).get_result(conn).optional()?;
+        match exists {
+            Some(ref player) if player.banned => {
+                banned_players.insert(login_key.as_str());
+                report.banned_players.push(player.login.clone());
+            }
+            Some(_) => {}
+            None => {
+                let nickname = source
+                    .nicknames
+                    .get(login_key)
+                    .map_or(*login, String::as_str);
+                insert_player(conn, login, nickname)?;
+                report.new_players += 1;
+            }
+        }
+    }
+
+    let records: Vec<&LegacyRecord> = source
+        .records
+        .iter()
+        .filter(|((map_key, login_key), _)| {
+            !unknown_maps.contains(map_key.as_str()) && !banned_players.contains(login_key.as_str())
+        })
+        .map(|(_, record)| record)
+        .collect();
+
+    for (map_id, author) in &new_maps {
+        let name = source
+            .map_names
+            .get(&key(map_id))
+            .map_or(*map_id, String::as_str);
+        insert_map(conn, map_id, name, author)?;
+        report.new_maps += 1;
+    }
+
+    let now = Utc::now().naive_utc();
+    // The maps of the records with their best time before the import, a
+    // better imported time is a world record
+    let mut first_places: BTreeMap<&str, (Map, Option<i32>)> = BTreeMap::new();
+    let mut updated_maps = BTreeSet::new();
+    for record in records {
+        let created_at = record.created_at.or(record.updated_at).unwrap_or(now);
+        let updated_at = record.updated_at.unwrap_or(created_at);
+        let exists: Option<Record> = records::table
+            .find((&record.map_id, &record.player_id))
+            .get_result(conn)
+            .optional()?;
+        if let Some(ref existing) = exists {
+            if existing.time <= record.time {
+                report.kept_records += 1;
+                continue;
+            }
+        }
+
+        if !first_places.contains_key(record.map_id.as_str()) {
+            let map: Map = maps::table.find(&record.map_id).get_result(conn)?;
+            let best: Option<i32> = records::table
+                .filter(records::map_id.eq(&record.map_id))
+                .select(diesel::dsl::min(records::time))
+                .get_result(conn)?;
+            first_places.insert(record.map_id.as_str(), (map, best));
+        }
+        let medal = Medal::for_time(&first_places[record.map_id.as_str()].0, record.time);
+
+        match exists {
+            Some(existing) => {
+                diesel::update(&existing)
+                    .set((
+                        records::time.eq(record.time),
+                        records::respawn_count.eq(record.respawn_count),
+                        records::try_count.eq(record.try_count),
+                        records::medal.eq(medal.map(Medal::as_str)),
+                        records::created_at.eq(cmp::min(existing.created_at, created_at)),
+                        records::updated_at.eq(updated_at),
+                    ))
+                    .execute(conn)?;
+                report.improved_records += 1;
+            }
+            None => {
+                diesel::insert_into(records::table)
+                    .values(&Record {
+                        rank: 0,
+                        time: record.time,
+                        respawn_count: record.respawn_count,
+                        try_count: record.try_count,
+                        created_at,
+                        updated_at,
+                        player_id: record.player_id.clone(),
+                        map_id: record.map_id.clone(),
+                        medal: medal.map(|medal| medal.as_str().to_string()),
+                        first_time: Some(record.time),
+                    })
+                    .execute(conn)?;
+                report.new_records += 1;
+            }
+        }
+        updated_maps.insert(record.map_id.as_str());
+    }
+
+    for map_id in updated_maps {
+        records_api::update_ranks(conn, map_id)?;
+        records_api::update_medals(conn, map_id)?;
+
+        let first: Record = records::table
+            .filter(records::map_id.eq(map_id))
+            .order_by((records::time, records::updated_at))
+            .first(conn)?;
+        let beaten = match first_places[map_id].1 {
+            Some(best) => first.time < best,
+            None => true,
+        };
+        if beaten {
+            WorldRecord::save(conn, &first)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn fields(pairs: &[(&str, &str)]) -> Fields {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let text = "mapId,nickname,time\r\nA,\"$f00Red, \"\"quoted\"\"\",61234\r\n\r\nB,\"two\nlines\",\r\nC,x,1";
+        let rows = read_rows(SourceFormat::Csv, text).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    2,
+                    fields(&[
+                        ("mapId", "A"),
+                        ("nickname", "$f00Red, \"quoted\""),
+                        ("time", "61234")
+                    ])
+                ),
+                (4, fields(&[("mapId", "B"), ("nickname", "two\nlines")])),
+                (
+                    6,
+                    fields(&[("mapId", "C"), ("nickname", "x"), ("time", "1")])
+                ),
+            ]
+        );
+
+        assert!(read_rows(SourceFormat::Csv, "a,b\n1,2,3\n").is_err());
+        assert!(read_rows(SourceFormat::Csv, "a\n\"open\n").is_err());
+        assert!(read_rows(SourceFormat::Csv, "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_json() {
+        let array =
+            r#"[{"mapId": "A", "time": 61234, "nickname": null}, {"mapId": "B", "time": ""}]"#;
+        assert_eq!(
+            read_rows(SourceFormat::Json, array).unwrap(),
+            vec![
+                (1, fields(&[("mapId", "A"), ("time", "61234")])),
+                (2, fields(&[("mapId", "B")])),
+            ]
+        );
+
+        let lines = "{\"mapId\": \"A\"}\n\n{\"mapId\": \"B\"}\n";
+        assert_eq!(
+            read_rows(SourceFormat::Json, lines).unwrap(),
+            vec![
+                (1, fields(&[("mapId", "A")])),
+                (3, fields(&[("mapId", "B")])),
+            ]
+        );
+        assert!(read_rows(SourceFormat::Json, "{\"mapId\": \"A\"}\nnope").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let date = NaiveDate::from_ymd(2019, 4, 26).and_hms(15, 49, 39);
+        assert_eq!(parse_timestamp("2019-04-26T15:49:39.000Z").unwrap(), date);
+        assert_eq!(parse_timestamp("2019-04-26T17:49:39+02:00").unwrap(), date);
+        assert_eq!(parse_timestamp("2019-04-26T15:49:39").unwrap(), date);
+        assert_eq!(parse_timestamp("2019-04-26 15:49:39").unwrap(), date);
+        assert_eq!(parse_timestamp("1556293779").unwrap(), date);
+        assert!(parse_timestamp("26/04/2019").is_err());
+    }
+
+    #[test]
+    fn test_record_from_fields() {
+        let record = LegacyRecord::from_fields(
+            &Mapping::default(),
+            &fields(&[
+                ("mapId", "A"),
+                ("playerId", "smokegun"),
+                ("time", "61234"),
+                ("tryCount", "12"),
+                ("updatedAt", "2019-04-26T15:49:39.000Z"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(record.time, 61234);
+        assert_eq!(record.respawn_count, 0);
+        assert_eq!(record.try_count, 12);
+        assert_eq!(record.created_at, None);
+        assert!(record.updated_at.is_some());
+
+        let mapping: Mapping = serde_json::from_str(
+            r#"{"mapId": "Challenge", "playerId": "Login", "time": "Score", "timeUnit": "seconds"}"#,
+        )
+        .unwrap();
+        assert_eq!(mapping.nickname, "nickname");
+        let record = LegacyRecord::from_fields(
+            &mapping,
+            &fields(&[("Challenge", "A"), ("Login", "encht"), ("Score", "61.2345")]),
+        )
+        .unwrap();
+        assert_eq!((record.player_id.as_str(), record.time), ("encht", 61235));
+
+        assert!(serde_json::from_str::<Mapping>(r#"{"login": "Login"}"#).is_err());
+        assert!(LegacyRecord::from_fields(&mapping, &fields(&[("Challenge", "A")])).is_err());
+        assert!(LegacyRecord::from_fields(
+            &Mapping::default(),
+            &fields(&[("mapId", "A"), ("playerId", "p"), ("time", "-5")])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_collect_keeps_best() {
+        let rows = vec![
+            (
+                1,
+                fields(&[
+                    ("mapId", "A"),
+                    ("playerId", "p"),
+                    ("time", "3000"),
+                    ("nickname", "Old"),
+                ]),
+            ),
+            (
+                2,
+                fields(&[("mapId", "A"), ("playerId", "p"), ("time", "2000")]),
+            ),
+            (
+                3,
+                fields(&[
+                    ("mapId", "A"),
+                    ("playerId", "p"),
+                    ("time", "2500"),
+                    ("nickname", "New"),
+                ]),
+            ),
+            (4, fields(&[("mapId", "A"), ("playerId", "q")])),
+            (
+                5,
+                fields(&[
+                    ("mapId", "B"),
+                    ("playerId", "p"),
+                    ("time", "1000"),
+                    ("mapAuthor", "q"),
+                ]),
+            ),
+        ];
+
+        let mut report = LegacyReport::default();
+        let source = collect(&Mapping::default(), &rows, &mut report);
+        assert_eq!(report.rows, 5);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, 4);
+        assert_eq!(report.duplicates, 2);
+        assert_eq!(source.records.len(), 2);
+        assert_eq!(
+            source.records[&("a".to_string(), "p".to_string())].time,
+            2000
+        );
+        assert_eq!(source.nicknames["p"], "Old");
+        assert_eq!(source.map_authors["b"], "q");
+    }
+
+    #[test]
+    fn test_collect_ignores_case() {
+        let rows = vec![
+            (
+                1,
+                fields(&[("mapId", "A"), ("playerId", "SmokeGun"), ("time", "3000")]),
+            ),
+            (
+                2,
+                fields(&[
+                    ("mapId", "a"),
+                    ("playerId", "smokegun"),
+                    ("time", "2000"),
+                    ("nickname", "Smoke"),
+                ]),
+            ),
+        ];
+
+        let mut report = LegacyReport::default();
+        let source = collect(&Mapping::default(), &rows, &mut report);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(source.records.len(), 1);
+        let record = &source.records[&("a".to_string(), "smokegun".to_string())];
+        assert_eq!((record.map_id.as_str(), record.time), ("a", 2000));
+        assert_eq!(source.nicknames[&key("SMOKEGUN")], "Smoke");
+    }
+
+    #[test]
+    fn test_collect_skips_invalid_ids() {
+        let long_login = "x".repeat(256);
+        let rows = vec![
+            (
+                1,
+                fields(&[("mapId", "A"), ("playerId", &long_login), ("time", "3000")]),
+            ),
+            (
+                2,
+                fields(&[
+                    ("mapId", "B"),
+                    ("playerId", "p"),
+                    ("time", "3000"),
+                    ("mapAuthor", &long_login),
+                ]),
+            ),
+            (
+                3,
+                fields(&[("mapId", "C"), ("playerId", "p"), ("time", "3000")]),
+            ),
+        ];
+
+        let mut report = LegacyReport::default();
+        let source = collect(&Mapping::default(), &rows, &mut report);
+        let skipped: Vec<usize> = report.skipped.iter().map(|(number, _)| *number).collect();
+        assert_eq!(skipped, vec![1, 2]);
+        assert!(report.skipped[0].1.contains("Invalid player login"));
+        assert_eq!(source.records.len(), 1);
+    }
+}
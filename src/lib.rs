@@ -0,0 +1,37 @@
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate prometheus;
+
+// database
+pub mod app_state;
+pub mod db;
+pub mod graphql;
+pub mod models;
+pub mod schema;
+
+// records related functions
+pub mod dump;
+pub mod export;
+pub mod legacy;
+pub mod records_api;
+pub mod search;
+pub mod webhooks;
+
+// utils
+pub mod error;
+pub mod escape;
+pub mod format;
+pub mod health;
+pub mod metrics;
+pub mod mp_text;
+pub mod xml;
+
+// routes used in game
+pub mod game;
+
+// JSON API
+pub mod rest;
+
+// Atom feeds
+pub mod feeds;
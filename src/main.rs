@@ -1,25 +1,8 @@
-#[macro_use]
-extern crate diesel;
-
-// database
-pub mod app_state;
-pub mod graphql;
-pub mod models;
-pub mod schema;
-
-// records related functions
-pub mod records_api;
-
-// utils
-pub mod escape;
-pub mod xml;
-
-// routes used in game
-pub mod game;
-
-use crate::app_state::*;
-use crate::game::*;
-use crate::graphql::*;
+use records_rust::app_state::*;
+use records_rust::game::*;
+use records_rust::graphql::*;
+use records_rust::{db, feeds, health, metrics, rest, webhooks};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 use actix_cors::Cors;
@@ -31,6 +14,9 @@ use futures::Future;
 use juniper::http::graphiql::graphiql_source;
 use juniper::http::GraphQLRequest;
 use std::env;
+use std::io;
+use std::str::FromStr;
+use structopt::StructOpt;
 
 #[allow(dead_code)]
 fn graphiql() -> HttpResponse {
@@ -47,7 +33,7 @@ fn graphql(
     web::block(move || {
         let ctx = DbContext(state.pool.clone());
         let res = data.execute(&state.schema, &ctx);
-        Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
+        serde_json::to_string(&res)
     })
     .map_err(Error::from)
     .and_then(|user| {
@@ -57,8 +43,118 @@ fn graphql(
     })
 }
 
+/// What to do with pending migrations when the server starts.
+#[derive(Debug)]
+enum StartupMigrations {
+    /// Refuse to start while migrations are pending
+    Check,
+    /// Apply the pending migrations
+    Apply,
+    /// Start even if migrations are pending
+    Ignore,
+}
+
+impl FromStr for StartupMigrations {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "check" => Ok(StartupMigrations::Check),
+            "apply" => Ok(StartupMigrations::Apply),
+            "ignore" => Ok(StartupMigrations::Ignore),
+            _ => Err(format!("unknown migrations mode: {}", s)),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "records-rust",
+    about = "The API for the Obstacle online records."
+)]
+struct Opt {
+    /// What to do on startup with pending migrations: check, apply or ignore
+    #[structopt(long = "migrations", default_value = "check")]
+    migrations: StartupMigrations,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Manage the database migrations instead of starting the server
+    #[structopt(name = "migrations")]
+    Migrations(MigrationsCommand),
+}
+
+#[derive(StructOpt)]
+enum MigrationsCommand {
+    /// List the migrations and whether they are applied
+    #[structopt(name = "list")]
+    List,
+    /// Apply the pending migrations
+    #[structopt(name = "run")]
+    Run,
+    /// Revert the latest applied migration
+    #[structopt(name = "revert")]
+    Revert,
+}
+
+fn to_io_error<E: ToString>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+fn migrations_command(conn: &MysqlConnection, command: MigrationsCommand) -> io::Result<()> {
+    match command {
+        MigrationsCommand::List => {
+            for (version, applied) in db::migrations_status(conn).map_err(to_io_error)? {
+                let mark = if applied { "X" } else { " " };
+                println!("[{}] {}", mark, version);
+            }
+        }
+        MigrationsCommand::Run => {
+            let count = db::run_pending_migrations(conn, &mut io::stdout()).map_err(to_io_error)?;
+            println!("{} migration(s) applied", count);
+        }
+        MigrationsCommand::Revert => {
+            match db::revert_latest_migration(conn).map_err(to_io_error)? {
+                Some(name) => println!("Migration {} reverted", name),
+                None => println!("No migration to revert"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure the database schema matches `schema.rs` before serving requests,
+/// returns the migrations still pending.
+fn prepare_database(
+    conn: &MysqlConnection,
+    mode: &StartupMigrations,
+) -> io::Result<Vec<&'static str>> {
+    match mode {
+        StartupMigrations::Check => {
+            let pending = db::pending_migrations(conn).map_err(to_io_error)?;
+            if !pending.is_empty() {
+                return Err(to_io_error(format!(
+                    "The database is not up to date, pending migrations: {}. \
+                     Run them with `records-rust migrations run` or start with `--migrations apply`.",
+                    pending.join(", ")
+                )));
+            }
+        }
+        StartupMigrations::Apply => {
+            db::run_pending_migrations(conn, &mut io::stdout()).map_err(to_io_error)?;
+        }
+        StartupMigrations::Ignore => {}
+    }
+    db::pending_migrations(conn).map_err(to_io_error)
+}
+
 fn main() -> std::io::Result<()> {
     dotenv().ok();
+    let opt = Opt::from_args();
 
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
@@ -71,15 +167,33 @@ fn main() -> std::io::Result<()> {
         .build(manager)
         .expect("Failed to create the MysqlConnection pool.");
 
+    let pending_migrations = {
+        let conn = pool.get().map_err(to_io_error)?;
+        if let Some(Command::Migrations(command)) = opt.command {
+            return migrations_command(&conn, command);
+        }
+        prepare_database(&conn, &opt.migrations)?
+    };
+
+    webhooks::spawn_worker(pool.clone());
+
     let app_state = Arc::new(AppState {
         pool,
         schema: create_schema(),
+        cache_warmed: AtomicBool::new(false),
+        pending_migrations,
     });
+    spawn_cache_warm_up(Arc::clone(&app_state));
 
     HttpServer::new(move || {
         App::new()
             .data(app_state.clone())
-            .wrap(middleware::Logger::default())
+            .wrap(
+                middleware::Logger::default()
+                    .exclude("/healthz")
+                    .exclude("/readyz"),
+            )
+            .wrap(metrics::Metrics)
             .wrap(
                 Cors::new() // <- Construct CORS middleware builder
                     .allowed_origin("https://www.obstacle.ovh")
@@ -89,22 +203,37 @@ fn main() -> std::io::Result<()> {
                     .max_age(3600),
             )
             .service(
-                web::resource("/api/Records/player-finished")
+                metrics::resource("/api/Records/player-finished")
+                    .data(json_config())
                     .route(web::post().to_async(has_finished_route)),
             )
             .service(
-                web::resource("/api/Records/overview").route(web::get().to_async(overview_route)),
+                metrics::resource("/api/Records/player-finished/bulk")
+                    .data(json_config().limit(256 * 1024))
+                    .route(web::post().to_async(has_finished_bulk_route)),
             )
-            .service(web::resource("/api/Users/Login").route(web::post().to(ok_stub)))
             .service(
-                web::resource("/api/Players/replaceOrCreate")
+                metrics::resource("/api/Records/overview")
+                    .data(query_config())
+                    .route(web::get().to_async(overview_route)),
+            )
+            .service(metrics::resource("/api/Users/Login").route(web::post().to(ok_stub)))
+            .service(
+                metrics::resource("/api/Players/replaceOrCreate")
+                    .data(json_config())
                     .route(web::post().to_async(player_replace_or_create)),
             )
             .service(
-                web::resource("/api/Maps/replaceOrCreate")
+                metrics::resource("/api/Maps/replaceOrCreate")
+                    .data(json_config())
                     .route(web::post().to_async(map_replace_or_create)),
             )
-            .service(web::resource("/graphql").route(web::post().to_async(graphql)))
+            .service(metrics::resource("/graphql").route(web::post().to_async(graphql)))
+            .configure(rest::config)
+            .configure(feeds::config)
+            .service(metrics::resource("/healthz").route(web::get().to(health::healthz)))
+            .service(metrics::resource("/readyz").route(web::get().to_async(health::readyz)))
+            .service(metrics::resource("/metrics").route(web::get().to(metrics::metrics_route)))
         //.service(web::resource("/graphiql").route(web::get().to(graphiql)))
     })
     .bind("127.0.0.1:3000")?
@@ -114,9 +243,17 @@ fn main() -> std::io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::map::Map;
-    use crate::models::player::Player;
     use actix_web::{test, web, App};
+    use chrono::{Duration, Utc};
+    use records_rust::legacy;
+    use records_rust::models::map::Map;
+    use records_rust::models::nickname::NicknameChange;
+    use records_rust::models::player::Player;
+    use records_rust::models::record::Record;
+    use records_rust::models::stats::PlayerStats;
+    use records_rust::records_api::{self, NewFinish};
+    use records_rust::schema::{finish_keys, records, world_records};
+    use std::thread;
 
     fn create_app_state() -> Arc<AppState> {
         dotenv().ok();
@@ -128,6 +265,8 @@ mod tests {
         Arc::new(AppState {
             pool,
             schema: create_schema(),
+            cache_warmed: AtomicBool::new(true),
+            pending_migrations: Vec::new(),
         })
     }
 
@@ -163,6 +302,8 @@ mod tests {
             respawn_count: 32,
             map_id: String::from("NullId"),
             player_id: String::from("gotatang"),
+            idempotency_key: None,
+            finished_at: None,
         };
 
         let req = test::TestRequest::post()
@@ -174,6 +315,197 @@ mod tests {
         assert!(resp.status().is_success());
     }
 
+    #[test]
+    fn test_player_finished_replayed() {
+        let state = create_app_state();
+        Player {
+            login: String::from("replayed_finisher"),
+            nickname: String::from("replayed_finisher"),
+            zone_path: Some(String::from("World|Europe|France")),
+            ..Player::default()
+        }
+        .insert_or_replace(&state.pool.get().unwrap())
+        .unwrap();
+        let mut app = test::init_service(
+            App::new().data(Arc::clone(&state)).service(
+                web::resource("/api/Records/player-finished")
+                    .route(web::post().to_async(has_finished_route)),
+            ),
+        );
+
+        let payload = HasFinishedPayload {
+            time: 71000,
+            respawn_count: 3,
+            map_id: String::from("NullId"),
+            player_id: String::from("replayed_finisher"),
+            idempotency_key: Some(String::from("test-replayed-finish")),
+            finished_at: None,
+        };
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/api/Records/player-finished?format=json")
+                .set_json(&payload)
+                .to_request();
+
+            let resp = test::call_service(&mut app, req);
+            assert!(resp.status().is_success());
+            let body = test::read_body(resp);
+            let result: HasFinishedResult = serde_json::from_slice(&body).unwrap();
+            results.push(result);
+        }
+
+        assert!(results[1].replayed);
+        assert_eq!(results[0].new, results[1].new);
+        assert_eq!(results[0].old, results[1].old);
+        assert_eq!(results[1].zone.as_deref(), Some("World|Europe|France"));
+        assert_eq!(results[0].zone_rank, results[1].zone_rank);
+        assert!(results[1].zone_rank.is_some());
+    }
+
+    #[test]
+    fn test_player_finished_banned() {
+        let state = create_app_state();
+        let mut app = test::init_service(
+            App::new().data(Arc::clone(&state)).service(
+                web::resource("/api/Records/player-finished")
+                    .data(json_config())
+                    .route(web::post().to_async(has_finished_route)),
+            ),
+        );
+
+        let payload = HasFinishedPayload {
+            time: 72000,
+            respawn_count: 32,
+            map_id: String::from("NullId"),
+            player_id: String::from("encht"),
+            idempotency_key: None,
+            finished_at: None,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/api/Records/player-finished")
+            .set_json(&payload)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+
+        let body = test::read_body(resp);
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("<code>banned</code>"));
+    }
+
+    #[test]
+    fn test_player_finished_banned_json() {
+        let state = create_app_state();
+        let mut app = test::init_service(
+            App::new().data(Arc::clone(&state)).service(
+                web::resource("/api/Records/player-finished")
+                    .data(json_config())
+                    .route(web::post().to_async(has_finished_route)),
+            ),
+        );
+
+        let payload = HasFinishedPayload {
+            time: 72000,
+            respawn_count: 32,
+            map_id: String::from("NullId"),
+            player_id: String::from("encht"),
+            idempotency_key: None,
+            finished_at: None,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/api/Records/player-finished")
+            .header(http::header::ACCEPT, "application/json")
+            .set_json(&payload)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+
+        let body = test::read_body(resp);
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains(r#""code":"banned""#));
+    }
+
+    #[test]
+    fn test_players_finished_bulk() {
+        let state = create_app_state();
+        let mut app = test::init_service(
+            App::new().data(Arc::clone(&state)).service(
+                web::resource("/api/Records/player-finished/bulk")
+                    .data(json_config())
+                    .route(web::post().to_async(has_finished_bulk_route)),
+            ),
+        );
+
+        let payload = vec![
+            HasFinishedPayload {
+                time: 72000,
+                respawn_count: 32,
+                map_id: String::from("NullId"),
+                player_id: String::from("gotatang"),
+                idempotency_key: None,
+                finished_at: None,
+            },
+            HasFinishedPayload {
+                time: 71000,
+                respawn_count: 12,
+                map_id: String::from("NullId"),
+                player_id: String::from("smokegun"),
+                idempotency_key: None,
+                finished_at: None,
+            },
+        ];
+
+        let req = test::TestRequest::post()
+            .uri("/api/Records/player-finished/bulk?format=json")
+            .set_json(&payload)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert!(resp.status().is_success());
+
+        let body = test::read_body(resp);
+        let results: Vec<HasFinishedResult> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].login, "smokegun");
+    }
+
+    #[test]
+    fn test_players_finished_bulk_invalid() {
+        let state = create_app_state();
+        let mut app = test::init_service(
+            App::new().data(Arc::clone(&state)).service(
+                web::resource("/api/Records/player-finished/bulk")
+                    .data(json_config())
+                    .route(web::post().to_async(has_finished_bulk_route)),
+            ),
+        );
+
+        let payload = vec![HasFinishedPayload {
+            time: -1,
+            respawn_count: 0,
+            map_id: String::from("NullId"),
+            player_id: String::from("gotatang"),
+            idempotency_key: None,
+            finished_at: None,
+        }];
+
+        let req = test::TestRequest::post()
+            .uri("/api/Records/player-finished/bulk")
+            .set_json(&payload)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    }
+
     #[test]
     fn test_overview_get() {
         let state = create_app_state();
@@ -189,6 +521,65 @@ mod tests {
         assert!(resp.status().is_success());
     }
 
+    #[test]
+    fn test_records_feed_get() {
+        let state = create_app_state();
+        let mut app =
+            test::init_service(App::new().data(Arc::clone(&state)).configure(feeds::config));
+        let req = test::TestRequest::get()
+            .uri("/feeds/records.atom")
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "application/atom+xml; charset=utf-8"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/feeds/maps/UnknownMapId/records.atom")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_records_export_get() {
+        let state = create_app_state();
+        let mut app =
+            test::init_service(App::new().data(Arc::clone(&state)).configure(rest::config));
+        let req = test::TestRequest::get()
+            .uri("/v1/export/players")
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "text/csv; charset=utf-8"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/v1/export/records?since=yesterday")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_player_profile_get() {
+        let state = create_app_state();
+        let mut app =
+            test::init_service(App::new().data(Arc::clone(&state)).configure(rest::config));
+        let req = test::TestRequest::get()
+            .uri("/v1/players/UnknownPlayer/profile")
+            .to_request();
+
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+    }
+
     #[test]
     fn test_player_replace_or_create_get() {
         let state = create_app_state();
@@ -219,6 +610,11 @@ mod tests {
         let payload = Player {
             login: String::from("gotatang"),
             nickname: String::from("gotatang"),
+            banned: false,
+            nickname_plain: String::new(),
+            zone_path: Some(String::from("World|Europe|France")),
+            language: Some(String::from("fr")),
+            avatar: None,
         };
 
         let req = test::TestRequest::post()
@@ -261,6 +657,17 @@ mod tests {
             maniaplanet_map_id: String::from("NullId"),
             name: String::from("NullId"),
             player_id: String::from("gotatang"),
+            name_plain: String::new(),
+            author_time: Some(30000),
+            gold_time: Some(32000),
+            silver_time: Some(36000),
+            bronze_time: Some(45000),
+            environment: Some(String::from("Storm")),
+            title_pack: Some(String::from("obstacle@smokegun")),
+            checkpoint_count: Some(4),
+            map_type: Some(String::from("ObstacleArena")),
+            file_hash: Some(String::from("0123456789abcdef")),
+            uploaded_at: None,
         };
 
         let req = test::TestRequest::post()
@@ -271,4 +678,318 @@ mod tests {
         let resp = test::call_service(&mut app, req);
         assert!(resp.status().is_success());
     }
+
+    #[test]
+    fn test_merge_player_into_itself() {
+        let state = create_app_state();
+        let conn = state.pool.get().unwrap();
+        let map_id = "MergeItselfId";
+        let login = "merge_itself";
+
+        let finish = NewFinish {
+            time: 61000,
+            respawn_count: 0,
+            player_id: login,
+            map_id,
+            idempotency_key: None,
+            finished_at: None,
+        };
+        records_api::has_finished(&conn, &finish).unwrap();
+
+        assert!(records_api::merge_players(&conn, login, login).is_err());
+        assert!(records_api::merge_players(&conn, login, "MERGE_ITSELF").is_err());
+
+        let record: Record = records::table
+            .find((map_id, login))
+            .get_result(&conn)
+            .unwrap();
+        assert_eq!(record.time, 61000);
+    }
+
+    #[test]
+    fn test_bulk_saves_every_world_record() {
+        let state = create_app_state();
+        let conn = state.pool.get().unwrap();
+        let map_id = "BulkWorldRecordsId";
+        let finish = |player_id, time| NewFinish {
+            time,
+            respawn_count: 0,
+            player_id,
+            map_id,
+            idempotency_key: None,
+            finished_at: None,
+        };
+
+        records_api::have_finished(
+            &conn,
+            &[
+                finish("bulk_wr_a", 60000),
+                finish("bulk_wr_b", 55000),
+                // Equaling a world record is not one
+                finish("bulk_wr_c", 55000),
+                finish("bulk_wr_a", 50000),
+            ],
+        )
+        .unwrap();
+
+        let times: Vec<(String, i32)> = world_records::table
+            .filter(world_records::map_id.eq(map_id))
+            .order_by(world_records::id)
+            .select((world_records::player_id, world_records::time))
+            .load(&conn)
+            .unwrap();
+        assert_eq!(
+            times,
+            vec![
+                (String::from("bulk_wr_a"), 60000),
+                (String::from("bulk_wr_b"), 55000),
+                (String::from("bulk_wr_a"), 50000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_legacy_import_beating_first_place() {
+        let state = create_app_state();
+        let conn = state.pool.get().unwrap();
+        let map_id = "LegacyFirstPlaceId";
+        let import = |text| {
+            let rows = legacy::read_rows(legacy::SourceFormat::Csv, text).unwrap();
+            legacy::import(&conn, &legacy::Mapping::default(), &rows, false).unwrap()
+        };
+
+        import(
+            "mapId,mapAuthor,playerId,time,createdAt\n\
+             LegacyFirstPlaceId,legacy_a,legacy_a,60000,2019-01-01T00:00:00Z\n\
+             LegacyFirstPlaceId,legacy_a,legacy_b,65000,2019-01-01T00:00:00Z\n",
+        );
+        let report = import(
+            "mapId,playerId,time,createdAt\n\
+             LegacyFirstPlaceId,legacy_b,55000,2019-06-01T00:00:00Z\n",
+        );
+        assert_eq!(report.improved_records, 1);
+
+        let record: Record = records::table
+            .find((map_id, "legacy_b"))
+            .get_result(&conn)
+            .unwrap();
+        assert_eq!(record.time, 55000);
+        assert_eq!(record.rank, 1);
+        assert_eq!(record.first_time, Some(65000));
+        assert_eq!(
+            record.created_at,
+            legacy::parse_timestamp("2019-01-01T00:00:00Z").unwrap()
+        );
+
+        let times: Vec<(String, i32)> = world_records::table
+            .filter(world_records::map_id.eq(map_id))
+            .order_by(world_records::id)
+            .select((world_records::player_id, world_records::time))
+            .load(&conn)
+            .unwrap();
+        assert_eq!(
+            times,
+            vec![
+                (String::from("legacy_a"), 60000),
+                (String::from("legacy_b"), 55000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_first_time_and_forgets_finish_keys() {
+        let state = create_app_state();
+        let conn = state.pool.get().unwrap();
+        let map_id = "MergeFirstTimeId";
+        let finish = |player_id, time, idempotency_key, days_ago| NewFinish {
+            time,
+            respawn_count: 0,
+            player_id,
+            map_id,
+            idempotency_key,
+            finished_at: Some(Utc::now().naive_utc() - Duration::days(days_ago)),
+        };
+
+        // The merged player finished first, the other one has the best time
+        records_api::has_finished(&conn, &finish("merge_first_from", 70000, Some("k1"), 3))
+            .unwrap();
+        records_api::has_finished(&conn, &finish("merge_first_into", 65000, None, 1)).unwrap();
+
+        records_api::merge_players(&conn, "merge_first_from", "merge_first_into").unwrap();
+
+        let record: Record = records::table
+            .find((map_id, "merge_first_into"))
+            .get_result(&conn)
+            .unwrap();
+        assert_eq!(record.time, 65000);
+        assert_eq!(record.first_time, Some(70000));
+
+        let keys: i64 = finish_keys::table
+            .filter(finish_keys::player_id.eq("merge_first_from"))
+            .count()
+            .get_result(&conn)
+            .unwrap();
+        assert_eq!(keys, 0);
+    }
+
+    #[test]
+    fn test_merge_keeps_current_nickname_first() {
+        let state = create_app_state();
+        let conn = state.pool.get().unwrap();
+
+        let player = |login: &str, nickname: &str| Player {
+            login: login.to_string(),
+            nickname: nickname.to_string(),
+            ..Player::default()
+        };
+        player("merge_nick_into", "Into")
+            .insert_or_replace(&conn)
+            .unwrap();
+        // The nickname of the merged player is saved last
+        player("merge_nick_from", "From")
+            .insert_or_replace(&conn)
+            .unwrap();
+
+        records_api::merge_players(&conn, "merge_nick_from", "merge_nick_into").unwrap();
+
+        let history = NicknameChange::history(&conn, "merge_nick_into", 0, 10).unwrap();
+        assert_eq!(history[0].nickname, "Into");
+        assert!(history.iter().any(|change| change.nickname == "From"));
+    }
+
+    #[test]
+    fn test_stats_invalidated_while_computed() {
+        let state = create_app_state();
+        let conn = state.pool.get().unwrap();
+        let map_id = "StatsInvalidatedId";
+        let login = "stats_invalidated";
+
+        records_api::delete_record(&conn, map_id, login).unwrap();
+        let finish = NewFinish {
+            time: 61000,
+            respawn_count: 0,
+            player_id: login,
+            map_id,
+            idempotency_key: None,
+            finished_at: None,
+        };
+        records_api::has_finished(&conn, &finish).unwrap();
+        assert_eq!(PlayerStats::of(&conn, login).unwrap().total_tries, 1);
+
+        // The statistics are asked for while a change of the records is
+        // not committed yet
+        let reader = conn
+            .transaction::<_, diesel::result::Error, _>(|| {
+                diesel::update(records::table.find((map_id, login)))
+                    .set(records::try_count.eq(5))
+                    .execute(&conn)?;
+                PlayerStats::invalidate(&conn, login)?;
+
+                let state = Arc::clone(&state);
+                let reader = thread::spawn(move || {
+                    let conn = state.pool.get().unwrap();
+                    PlayerStats::of(&conn, login).unwrap()
+                });
+                thread::sleep(std::time::Duration::from_millis(200));
+                Ok(reader)
+            })
+            .unwrap();
+
+        assert_eq!(reader.join().unwrap().total_tries, 5);
+        assert_eq!(PlayerStats::of(&conn, login).unwrap().total_tries, 5);
+    }
+
+    #[test]
+    fn test_stats_of_uncached_players_concurrently() {
+        let state = create_app_state();
+        let logins: Vec<String> = (0..8).map(|i| format!("stats_gap_{}", i)).collect();
+        {
+            let conn = state.pool.get().unwrap();
+            for login in &logins {
+                let finish = NewFinish {
+                    time: 61000,
+                    respawn_count: 0,
+                    player_id: login,
+                    map_id: "StatsGapId",
+                    idempotency_key: None,
+                    finished_at: None,
+                };
+                records_api::has_finished(&conn, &finish).unwrap();
+                PlayerStats::invalidate(&conn, login).unwrap();
+            }
+        }
+
+        let readers: Vec<_> = logins
+            .into_iter()
+            .map(|login| {
+                let state = Arc::clone(&state);
+                thread::spawn(move || {
+                    let conn = state.pool.get().unwrap();
+                    PlayerStats::of(&conn, &login).unwrap()
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap().record_count, 1);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_finishes() {
+        let state = create_app_state();
+        let map_id = "ConcurrentFinishesId";
+        let login = "concurrent_finisher";
+
+        {
+            let conn = state.pool.get().unwrap();
+            records_api::delete_record(&conn, map_id, login).unwrap();
+        }
+
+        let times = [61000, 60000, 62000, 59000, 63000, 59500, 64000, 60500];
+        let handles: Vec<_> = times
+            .iter()
+            .map(|&time| {
+                let state = Arc::clone(&state);
+                thread::spawn(move || {
+                    let conn = state.pool.get().unwrap();
+                    let finish = NewFinish {
+                        time,
+                        respawn_count: 0,
+                        player_id: login,
+                        map_id,
+                        idempotency_key: None,
+                        finished_at: None,
+                    };
+                    records_api::has_finished(&conn, &finish).unwrap()
+                })
+            })
+            .collect();
+        let finishes: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        let conn = state.pool.get().unwrap();
+        let record: Record = records::table
+            .find((map_id, login))
+            .get_result(&conn)
+            .unwrap();
+
+        // The best time is kept, ranked, and every new best was counted once
+        assert_eq!(record.time, 59000);
+        assert_eq!(record.rank, 1);
+        let new_bests = finishes.iter().filter(|finish| finish.is_new_best).count();
+        assert_eq!(record.try_count as usize, new_bests);
+
+        // Only one finish created the record
+        let first_finishes = finishes
+            .iter()
+            .filter(|finish| finish.is_new_best && finish.old == finish.new)
+            .count();
+        assert_eq!(first_finishes, 1);
+        assert!(finishes
+            .iter()
+            .filter(|finish| finish.is_new_best && finish.old != finish.new)
+            .all(|finish| finish.new < finish.old));
+    }
 }
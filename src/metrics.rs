@@ -0,0 +1,251 @@
+//! Prometheus metrics.
+//!
+//! The metrics are registered in the default prometheus registry and exposed
+//! in the text format on `/metrics`. Request latencies are observed by the
+//! `Metrics` middleware, the records counters are updated from `records_api`.
+
+use crate::app_state::AppState;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpResponse, Resource};
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::sync::Arc;
+use std::time::Instant;
+
+lazy_static! {
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "records_http_request_duration_seconds",
+        "Time spent answering a request, by route",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "records_db_pool_connections",
+        "Number of connections currently managed by the database pool"
+    )
+    .unwrap();
+    pub static ref POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "records_db_pool_idle_connections",
+        "Number of idle connections in the database pool"
+    )
+    .unwrap();
+    pub static ref POOL_MAX_SIZE: IntGauge = register_int_gauge!(
+        "records_db_pool_max_size",
+        "Maximum number of connections of the database pool"
+    )
+    .unwrap();
+    pub static ref FINISHES: IntCounter =
+        register_int_counter!("records_finishes_total", "Number of finishes saved").unwrap();
+    pub static ref NEW_BESTS: IntCounter = register_int_counter!(
+        "records_new_personal_bests_total",
+        "Number of finishes improving the personal best of a player"
+    )
+    .unwrap();
+    pub static ref WORLD_RECORDS: IntCounter = register_int_counter!(
+        "records_world_records_total",
+        "Number of finishes taking the first place of a map"
+    )
+    .unwrap();
+    pub static ref REPLAYED_FINISHES: IntCounter = register_int_counter!(
+        "records_replayed_finishes_total",
+        "Number of finishes sent again with an idempotency key already saved"
+    )
+    .unwrap();
+    pub static ref REJECTED_FINISHES: IntCounterVec = register_int_counter_vec!(
+        "records_rejected_finishes_total",
+        "Number of finishes rejected, by error code",
+        &["code"]
+    )
+    .unwrap();
+    pub static ref WEBHOOK_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "records_webhook_deliveries_total",
+        "Number of webhook calls made, by result: delivered, retried or given_up",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref UPDATE_RANKS_DURATION: Histogram = register_histogram!(
+        "records_update_ranks_duration_seconds",
+        "Time spent recomputing the ranks of a map"
+    )
+    .unwrap();
+}
+
+/// The pattern of a resource, labeling its requests in the metrics, e.g.
+/// `/v1/maps/{id}`. actix-web doesn't tell which resource matched a request,
+/// so each one carries its pattern as data.
+pub struct RoutePattern(pub String);
+
+/// A resource whose requests are labeled with its pattern.
+pub fn resource(path: &str) -> Resource {
+    scoped_resource("", path)
+}
+
+/// A resource of a scope whose requests are labeled with its full pattern,
+/// the scope being its prefix. The data of the scope is not passed to a
+/// resource with data of its own, it must be given to the resource.
+pub fn scoped_resource(scope: &str, path: &str) -> Resource {
+    web::resource(path).data(RoutePattern(format!("{}{}", scope, path)))
+}
+
+/// Middleware observing the duration of every request.
+pub struct Metrics;
+
+impl<S, B> Transform<S> for Metrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsMiddleware { service })
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+
+        Box::new(self.service.call(req).map(move |res| {
+            // Unknown paths are grouped together to keep the number of series bounded
+            let route = match res.request().app_data::<RoutePattern>() {
+                Some(RoutePattern(pattern)) => pattern.as_str(),
+                None => "unmatched",
+            };
+
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[
+                    res.request().method().as_str(),
+                    route,
+                    res.status().as_str(),
+                ])
+                .observe(start.elapsed().as_secs_f64());
+
+            res
+        }))
+    }
+}
+
+pub fn metrics_route(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let pool_state = state.pool.state();
+    POOL_CONNECTIONS.set(i64::from(pool_state.connections));
+    POOL_IDLE_CONNECTIONS.set(i64::from(pool_state.idle_connections));
+    POOL_MAX_SIZE.set(i64::from(state.pool.max_size()));
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(e) => {
+            eprintln!("Error while encoding metrics: {}", e);
+            HttpResponse::InternalServerError().into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[test]
+    fn test_requests_are_observed_by_route() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Metrics)
+                .service(resource("/test/maps/{id}").route(web::get().to(HttpResponse::Ok))),
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/test/maps/someMapId")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert!(resp.status().is_success());
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains(
+            r#"records_http_request_duration_seconds_count{method="GET",route="/test/maps/{id}",status="200"} 1"#
+        ));
+    }
+
+    fn requests_count(route: &str, status: &str) -> Option<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut buffer)
+            .unwrap();
+        let prefix = format!(
+            r#"records_http_request_duration_seconds_count{{method="GET",route="{}",status="{}"}} "#,
+            route, status
+        );
+        String::from_utf8(buffer)
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with(&prefix))
+            .map(|line| line[prefix.len()..].to_string())
+    }
+
+    #[test]
+    fn test_route_label_is_the_pattern() {
+        let mut app = test::init_service(
+            App::new().wrap(Metrics).service(
+                web::scope("/label").service(
+                    scoped_resource("/label", "/players/{login}/records")
+                        .route(web::get().to(HttpResponse::NotFound)),
+                ),
+            ),
+        );
+
+        // The login is also a static segment of the route, is percent-encoded,
+        // and the answer of a matched route is a 404
+        let req = test::TestRequest::get()
+            .uri("/label/players/rec%6Frds/records")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status().as_u16(), 404);
+        assert_eq!(
+            requests_count("/label/players/{login}/records", "404").as_deref(),
+            Some("1")
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/label/unknown/path")
+            .to_request();
+        test::call_service(&mut app, req);
+        assert!(requests_count("/label/unknown/path", "404").is_none());
+        assert!(requests_count("unmatched", "404").is_some());
+    }
+}
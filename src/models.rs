@@ -1,3 +1,8 @@
 pub mod map;
+pub mod medal;
+pub mod nickname;
 pub mod player;
 pub mod record;
+pub mod stats;
+pub mod webhook;
+pub mod world_record;
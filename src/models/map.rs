@@ -1,10 +1,15 @@
 use crate::escape::Escape;
 use crate::models::player::Player;
+use crate::mp_text;
+use crate::records_api;
 use crate::schema::{maps, players};
+use crate::search;
+use crate::webhooks::{self, Event};
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Queryable, Identifiable, Insertable, Deserialize, Serialize, Debug)]
+#[derive(Queryable, Identifiable, Insertable, Deserialize, Serialize, Debug, Default)]
 #[primary_key(maniaplanet_map_id)]
 pub struct Map {
     #[serde(alias = "maniaplanetMapId")]
@@ -12,46 +17,204 @@ pub struct Map {
     pub name: String,
     #[serde(alias = "playerId")]
     pub player_id: String,
+    /// The name without formatting codes, computed when it is saved
+    #[serde(skip)]
+    pub name_plain: String,
+    /// Times in milliseconds
+    #[serde(default, alias = "authorTime")]
+    pub author_time: Option<i32>,
+    #[serde(default, alias = "goldTime")]
+    pub gold_time: Option<i32>,
+    #[serde(default, alias = "silverTime")]
+    pub silver_time: Option<i32>,
+    #[serde(default, alias = "bronzeTime")]
+    pub bronze_time: Option<i32>,
+    #[serde(default)]
+    pub environment: Option<String>,
+    #[serde(default, alias = "titlePack")]
+    pub title_pack: Option<String>,
+    #[serde(default, alias = "checkpointCount")]
+    pub checkpoint_count: Option<i32>,
+    #[serde(default, alias = "mapType")]
+    pub map_type: Option<String>,
+    #[serde(default, alias = "fileHash")]
+    pub file_hash: Option<String>,
+    /// When the current file of the map was first saved
+    #[serde(default, alias = "uploadedAt")]
+    pub uploaded_at: Option<NaiveDateTime>,
+}
+
+/// The map info sent by the game server. Unknown values are left as they are
+/// when a map is updated.
+#[derive(Insertable, AsChangeset, Default)]
+#[table_name = "maps"]
+pub struct MapMetadata<'a> {
+    pub author_time: Option<i32>,
+    pub gold_time: Option<i32>,
+    pub silver_time: Option<i32>,
+    pub bronze_time: Option<i32>,
+    pub environment: Option<&'a str>,
+    pub title_pack: Option<&'a str>,
+    pub checkpoint_count: Option<i32>,
+    pub map_type: Option<&'a str>,
+    pub file_hash: Option<&'a str>,
+    pub uploaded_at: Option<NaiveDateTime>,
 }
 
 impl Map {
+    pub fn metadata(&self) -> MapMetadata<'_> {
+        MapMetadata {
+            author_time: self.author_time,
+            gold_time: self.gold_time,
+            silver_time: self.silver_time,
+            bronze_time: self.bronze_time,
+            environment: self.environment.as_deref(),
+            title_pack: self.title_pack.as_deref(),
+            checkpoint_count: self.checkpoint_count,
+            map_type: self.map_type.as_deref(),
+            file_hash: self.file_hash.as_deref(),
+            uploaded_at: self.uploaded_at,
+        }
+    }
+
     pub fn insert_or_replace(&self, conn: &MysqlConnection) -> QueryResult<usize> {
-        let author_exists: Option<Player> = players::table
-            .find(&self.player_id)
-            .get_result(conn)
-            .optional()?;
-
-        if author_exists.is_none() {
-            let player = Player {
-                login: self.player_id.clone(),
-                nickname: self.player_id.clone(),
+        conn.transaction(|| {
+            let author_exists: Option<Player> = players::table
+                .find(&self.player_id)
+                .get_result(conn)
+                .optional()?;
+
+            let author_nickname = match author_exists {
+                Some(ref author) => author.nickname_plain.clone(),
+                None => self.player_id.clone(),
             };
-            diesel::insert_into(players::table)
-                .values(player)
-                .execute(conn)?;
+            if author_exists.is_none() {
+                let player = Player {
+                    login: self.player_id.clone(),
+                    nickname: self.player_id.clone(),
+                    banned: false,
+                    nickname_plain: self.player_id.clone(),
+                    ..Default::default()
+                };
+                diesel::insert_into(players::table)
+                    .values(&player)
+                    .execute(conn)?;
+                search::index_name(conn, search::Kind::Player, &player.login, &player.login)?;
+            }
+
+            let map_exists: Option<Map> = maps::table
+                .find(&self.maniaplanet_map_id)
+                .get_result(conn)
+                .optional()?;
+
+            let escaped_name = format!("{}", Escape(&self.name));
+            let plain_name = mp_text::plain_text(&self.name);
+
+            let mut metadata = self.metadata();
+
+            let count = match map_exists {
+                Some(map) => {
+                    // A new file is a new upload
+                    metadata.uploaded_at = match metadata.file_hash {
+                        Some(hash) if map.file_hash.as_deref() != Some(hash) => {
+                            Some(Utc::now().naive_utc())
+                        }
+                        _ => None,
+                    };
+
+                    diesel::update(&map)
+                        .set((
+                            maps::name.eq(escaped_name),
+                            maps::name_plain.eq(&plain_name),
+                            maps::player_id.eq(&self.player_id),
+                            &metadata,
+                        ))
+                        .execute(conn)?
+                }
+                _ => {
+                    metadata.uploaded_at = Some(Utc::now().naive_utc());
+
+                    let count = diesel::insert_into(maps::table)
+                        .values((
+                            maps::name.eq(escaped_name),
+                            maps::name_plain.eq(&plain_name),
+                            maps::maniaplanet_map_id.eq(&self.maniaplanet_map_id),
+                            maps::player_id.eq(&self.player_id),
+                            &metadata,
+                        ))
+                        .execute(conn)?;
+
+                    webhooks::enqueue(
+                        conn,
+                        &Event::NewMap {
+                            map_id: self.maniaplanet_map_id.clone(),
+                            map_name: plain_name.clone(),
+                            player_id: self.player_id.clone(),
+                            nickname: author_nickname,
+                        },
+                    )?;
+
+                    count
+                }
+            };
+
+            search::index_name(
+                conn,
+                search::Kind::Map,
+                &self.maniaplanet_map_id,
+                &plain_name,
+            )?;
+
+            let medal_times = [
+                self.author_time,
+                self.gold_time,
+                self.silver_time,
+                self.bronze_time,
+            ];
+            if medal_times.iter().any(Option::is_some) {
+                records_api::update_medals(conn, &self.maniaplanet_map_id)?;
+            }
+
+            Ok(count)
+        })
+    }
+
+    /// Recomputes the plain name of every map, returns the number of maps
+    /// updated.
+    pub fn refresh_plain_names(conn: &MysqlConnection) -> QueryResult<usize> {
+        let names: Vec<(String, String, String)> = maps::table
+            .select((maps::maniaplanet_map_id, maps::name, maps::name_plain))
+            .load(conn)?;
+
+        let mut updated = 0;
+        for (map_id, name, old_plain) in names {
+            let plain = mp_text::plain_text_of_escaped(&name);
+            if plain != old_plain {
+                diesel::update(maps::table.find(map_id))
+                    .set(maps::name_plain.eq(plain))
+                    .execute(conn)?;
+                updated += 1;
+            }
         }
 
-        let map_exists: Option<Map> = maps::table
-            .find(&self.maniaplanet_map_id)
-            .get_result(conn)
-            .optional()?;
+        Ok(updated)
+    }
 
-        let escaped_name = format!("{}", Escape(&self.name));
+    pub fn rename(conn: &MysqlConnection, map_id: &str, name: &str) -> QueryResult<usize> {
+        let escaped_name = format!("{}", Escape(name));
+        let plain_name = mp_text::plain_text(name);
 
-        match map_exists {
-            Some(map) => diesel::update(&map)
+        conn.transaction(|| {
+            let count = diesel::update(maps::table.find(map_id))
                 .set((
                     maps::name.eq(escaped_name),
-                    maps::player_id.eq(&self.player_id),
-                ))
-                .execute(conn),
-            _ => diesel::insert_into(maps::table)
-                .values((
-                    maps::name.eq(escaped_name),
-                    maps::maniaplanet_map_id.eq(&self.maniaplanet_map_id),
-                    maps::player_id.eq(&self.player_id),
+                    maps::name_plain.eq(&plain_name),
                 ))
-                .execute(conn),
-        }
+                .execute(conn)?;
+            if count > 0 {
+                search::index_name(conn, search::Kind::Map, map_id, &plain_name)?;
+            }
+            Ok(count)
+        })
     }
 }
@@ -0,0 +1,120 @@
+use crate::models::map::Map;
+use diesel::sql_types::*;
+use serde_derive::{Deserialize, Serialize};
+
+/// The medals of a map, from the hardest to the easiest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Medal {
+    Author,
+    Gold,
+    Silver,
+    Bronze,
+}
+
+impl Medal {
+    pub const ALL: [Medal; 4] = [Medal::Author, Medal::Gold, Medal::Silver, Medal::Bronze];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Medal::Author => "author",
+            Medal::Gold => "gold",
+            Medal::Silver => "silver",
+            Medal::Bronze => "bronze",
+        }
+    }
+
+    pub fn parse(medal: &str) -> Option<Medal> {
+        Medal::ALL.iter().cloned().find(|m| m.as_str() == medal)
+    }
+
+    /// The target time of the medal on the map, if it is known.
+    pub fn target(self, map: &Map) -> Option<i32> {
+        match self {
+            Medal::Author => map.author_time,
+            Medal::Gold => map.gold_time,
+            Medal::Silver => map.silver_time,
+            Medal::Bronze => map.bronze_time,
+        }
+    }
+
+    /// The best medal a time earns on the map.
+    pub fn for_time(map: &Map, time: i32) -> Option<Medal> {
+        Medal::ALL
+            .iter()
+            .cloned()
+            .find(|medal| match medal.target(map) {
+                Some(target) => time <= target,
+                None => false,
+            })
+    }
+
+    /// Whether this medal is better than another one, or than none.
+    pub fn improves(self, previous: Option<Medal>) -> bool {
+        match previous {
+            Some(previous) => self < previous,
+            None => true,
+        }
+    }
+}
+
+/// The number of records of a player earning each medal, only the best medal
+/// of a record counts.
+#[derive(QueryableByName, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MedalCount {
+    #[sql_type = "VarChar"]
+    pub player_id: String,
+    #[sql_type = "VarChar"]
+    pub nickname: String,
+    #[sql_type = "BigInt"]
+    pub author: i64,
+    #[sql_type = "BigInt"]
+    pub gold: i64,
+    #[sql_type = "BigInt"]
+    pub silver: i64,
+    #[sql_type = "BigInt"]
+    pub bronze: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> Map {
+        Map {
+            author_time: Some(30000),
+            gold_time: Some(32000),
+            silver_time: None,
+            bronze_time: Some(45000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_medal_for_time() {
+        let map = map();
+        assert_eq!(Medal::for_time(&map, 29000), Some(Medal::Author));
+        assert_eq!(Medal::for_time(&map, 30000), Some(Medal::Author));
+        assert_eq!(Medal::for_time(&map, 31000), Some(Medal::Gold));
+        assert_eq!(Medal::for_time(&map, 40000), Some(Medal::Bronze));
+        assert_eq!(Medal::for_time(&map, 50000), None);
+        assert_eq!(Medal::for_time(&Map::default(), 1), None);
+    }
+
+    #[test]
+    fn test_medal_improves() {
+        assert!(Medal::Gold.improves(None));
+        assert!(Medal::Gold.improves(Some(Medal::Silver)));
+        assert!(!Medal::Gold.improves(Some(Medal::Gold)));
+        assert!(!Medal::Bronze.improves(Some(Medal::Author)));
+    }
+
+    #[test]
+    fn test_medal_names() {
+        for medal in Medal::ALL.iter() {
+            assert_eq!(Medal::parse(medal.as_str()), Some(*medal));
+        }
+        assert_eq!(Medal::parse("platinum"), None);
+    }
+}
@@ -0,0 +1,46 @@
+use crate::schema::nickname_history;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde_derive::Serialize;
+
+/// A nickname used by a player, stored escaped like `Player::nickname`.
+#[derive(Queryable, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NicknameChange {
+    #[serde(skip)]
+    pub id: i32,
+    #[serde(skip)]
+    pub player_id: String,
+    pub nickname: String,
+    /// When the player took this nickname, unknown for the nicknames used
+    /// before the history was kept
+    pub changed_at: Option<NaiveDateTime>,
+}
+
+impl NicknameChange {
+    /// Saves a new nickname of a player.
+    pub fn record(conn: &MysqlConnection, player_id: &str, nickname: &str) -> QueryResult<usize> {
+        diesel::insert_into(nickname_history::table)
+            .values((
+                nickname_history::player_id.eq(player_id),
+                nickname_history::nickname.eq(nickname),
+                nickname_history::changed_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+    }
+
+    /// The nicknames of a player, the latest first.
+    pub fn history(
+        conn: &MysqlConnection,
+        player_id: &str,
+        offset: i64,
+        limit: i64,
+    ) -> QueryResult<Vec<NicknameChange>> {
+        nickname_history::table
+            .filter(nickname_history::player_id.eq(player_id))
+            .order_by(nickname_history::id.desc())
+            .offset(offset)
+            .limit(limit)
+            .load(conn)
+    }
+}
@@ -1,34 +1,167 @@
 use crate::escape::Escape;
+use crate::models::nickname::NicknameChange;
+use crate::mp_text;
 use crate::schema::players;
+use crate::search;
+use crate::webhooks::{self, Event};
 use diesel::prelude::*;
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, Queryable, Identifiable, Insertable)]
+#[derive(Deserialize, Serialize, Queryable, Identifiable, Insertable, Default)]
 #[primary_key(login)]
 pub struct Player {
     pub login: String,
     pub nickname: String,
+    /// Banned players can't save records, it is never changed by `insert_or_replace`
+    #[serde(default)]
+    pub banned: bool,
+    /// The nickname without formatting codes, computed when it is saved
+    #[serde(skip)]
+    pub nickname_plain: String,
+    /// The zones of the player separated by `|`, from the world to the most
+    /// local one
+    #[serde(default, alias = "zonePath")]
+    pub zone_path: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+    /// The path or URL of the avatar image
+    #[serde(default)]
+    pub avatar: Option<String>,
+}
+
+/// The profile info sent by the game server. Unknown values are left as they
+/// are when a player is updated.
+#[derive(Insertable, AsChangeset, Default)]
+#[table_name = "players"]
+pub struct PlayerProfile<'a> {
+    pub zone_path: Option<&'a str>,
+    pub language: Option<&'a str>,
+    pub avatar: Option<&'a str>,
+}
+
+/// Whether a zone path is made of non-empty zones separated by `|`.
+pub fn is_valid_zone_path(path: &str) -> bool {
+    path.len() <= 255 && !path.split('|').any(|zone| zone.trim().is_empty())
+}
+
+/// The `LIKE` pattern matching the zone paths inside a zone.
+pub fn subzones_pattern(zone: &str) -> String {
+    format!("{}|%", search::escape_like(zone))
 }
 
 impl Player {
+    pub fn profile(&self) -> PlayerProfile<'_> {
+        PlayerProfile {
+            zone_path: self.zone_path.as_deref(),
+            language: self.language.as_deref(),
+            avatar: self.avatar.as_deref(),
+        }
+    }
+
+    /// The zones of the player, from the world to the most local one.
+    pub fn zones(&self) -> Vec<&str> {
+        match self.zone_path {
+            Some(ref path) => path.split('|').collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Saves the player, a new nickname is added to its history.
     pub fn insert_or_replace(&self, conn: &MysqlConnection) -> QueryResult<usize> {
-        let exists: Option<Player> = players::table
-            .find(&self.login)
+        conn.transaction(|| {
+            let exists: Option<Player> = players::table
+                .find(&self.login)
+                .get_result(conn)
+                .optional()?;
+
+            let escaped_nick = format!("{}", Escape(&self.nickname));
+            let plain_nick = mp_text::plain_text(&self.nickname);
+
+            let renamed = match exists {
+                Some(ref player) => player.nickname != escaped_nick,
+                None => true,
+            };
+            if renamed {
+                NicknameChange::record(conn, &self.login, &escaped_nick)?;
+            }
+
+            let count = match exists {
+                Some(player) => diesel::update(&player)
+                    .set((
+                        players::nickname.eq(escaped_nick),
+                        players::nickname_plain.eq(&plain_nick),
+                        &self.profile(),
+                    ))
+                    .execute(conn)?,
+                _ => diesel::insert_into(players::table)
+                    .values((
+                        players::login.eq(&self.login),
+                        players::nickname.eq(escaped_nick),
+                        players::nickname_plain.eq(&plain_nick),
+                        &self.profile(),
+                    ))
+                    .execute(conn)?,
+            };
+
+            search::index_name(conn, search::Kind::Player, &self.login, &plain_nick)?;
+            Ok(count)
+        })
+    }
+
+    /// Recomputes the plain nickname of every player, returns the number of
+    /// players updated.
+    pub fn refresh_plain_nicknames(conn: &MysqlConnection) -> QueryResult<usize> {
+        let nicknames: Vec<(String, String, String)> = players::table
+            .select((players::login, players::nickname, players::nickname_plain))
+            .load(conn)?;
+
+        let mut updated = 0;
+        for (login, nickname, old_plain) in nicknames {
+            let plain = mp_text::plain_text_of_escaped(&nickname);
+            if plain != old_plain {
+                diesel::update(players::table.find(login))
+                    .set(players::nickname_plain.eq(plain))
+                    .execute(conn)?;
+                updated += 1;
+            }
+        }
+
+        Ok(updated)
+    }
+
+    pub fn is_banned(conn: &MysqlConnection, login: &str) -> QueryResult<bool> {
+        let banned: Option<bool> = players::table
+            .find(login)
+            .select(players::banned)
             .get_result(conn)
             .optional()?;
+        Ok(banned.unwrap_or(false))
+    }
 
-        let escaped_nick = format!("{}", Escape(&self.nickname));
-
-        match exists {
-            Some(player) => diesel::update(&player)
-                .set(players::nickname.eq(escaped_nick))
-                .execute(conn),
-            _ => diesel::insert_into(players::table)
-                .values((
-                    players::login.eq(&self.login),
-                    players::nickname.eq(escaped_nick),
-                ))
-                .execute(conn),
-        }
+    /// Bans or unbans a player, a ban is sent to the webhooks.
+    pub fn set_banned(conn: &MysqlConnection, login: &str, banned: bool) -> QueryResult<usize> {
+        conn.transaction(|| {
+            let previous: Option<(bool, String)> = players::table
+                .find(login)
+                .select((players::banned, players::nickname_plain))
+                .get_result(conn)
+                .optional()?;
+
+            let count = diesel::update(players::table.find(login))
+                .set(players::banned.eq(banned))
+                .execute(conn)?;
+
+            if let Some((false, nickname)) = previous.filter(|_| banned) {
+                webhooks::enqueue(
+                    conn,
+                    &Event::Ban {
+                        player_id: login.to_string(),
+                        nickname,
+                    },
+                )?;
+            }
+
+            Ok(count)
+        })
     }
 }
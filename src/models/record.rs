@@ -14,6 +14,13 @@ pub struct Record {
     pub updated_at: NaiveDateTime,
     pub player_id: String,
     pub map_id: String,
+    /// The best medal earned, see `Medal::as_str`
+    #[serde(default)]
+    pub medal: Option<String>,
+    /// The time of the first finish, unknown for the records saved before it
+    /// was kept
+    #[serde(default)]
+    pub first_time: Option<i32>,
 }
 
 #[derive(Clone, QueryableByName, Deserialize, Serialize)]
@@ -29,3 +36,53 @@ pub struct RankedRecord {
     #[sql_type = "Integer"]
     pub time: i32,
 }
+
+/// A row of the overview, the one of the requesting player being flagged.
+#[derive(Clone, Serialize)]
+#[serde(rename = "records")]
+pub struct OverviewRecord {
+    pub rank: u32,
+    #[serde(rename = "playerId")]
+    pub player_id: String,
+    pub nickname: String,
+    pub time: i32,
+    #[serde(rename = "isPlayer", skip_serializing_if = "std::ops::Not::not")]
+    pub is_player: bool,
+}
+
+impl OverviewRecord {
+    pub fn new(record: RankedRecord, is_player: bool) -> Self {
+        OverviewRecord {
+            rank: record.rank,
+            player_id: record.player_id,
+            nickname: record.nickname,
+            time: record.time,
+            is_player,
+        }
+    }
+}
+
+/// Formats a time in milliseconds as `m:ss.mmm`.
+pub fn time_text(time: i32) -> String {
+    let sign = if time < 0 { "-" } else { "" };
+    let time = time.abs();
+    format!(
+        "{}{}:{:02}.{:03}",
+        sign,
+        time / 60_000,
+        time / 1000 % 60,
+        time % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_text() {
+        assert_eq!(time_text(0), "0:00.000");
+        assert_eq!(time_text(72345), "1:12.345");
+        assert_eq!(time_text(3_600_001), "60:00.001");
+    }
+}
@@ -0,0 +1,298 @@
+use crate::schema::{maps, player_stats, records};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_query;
+use diesel::sql_types::VarChar;
+use serde_derive::Serialize;
+
+/// Statistics of the records of a player.
+///
+/// They are computed when first read and kept in `player_stats` until the
+/// records or the ranks they count change, see `PlayerStats::invalidate`.
+#[derive(Queryable, Insertable, Serialize, Clone, Debug, PartialEq)]
+#[table_name = "player_stats"]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStats {
+    #[serde(skip)]
+    pub player_id: String,
+    pub record_count: i32,
+    /// First places, ties included
+    pub world_record_count: i32,
+    pub top_ten_count: i32,
+    pub average_rank: Option<f64>,
+    pub median_rank: Option<f64>,
+    /// Sum of the `try_count` of the records
+    pub total_tries: i64,
+    /// When the first record was set
+    pub first_activity: Option<NaiveDateTime>,
+    /// When a record was last improved
+    pub last_activity: Option<NaiveDateTime>,
+    #[serde(skip)]
+    pub computed_at: NaiveDateTime,
+}
+
+/// The rank, tries and dates of a record, counted by the statistics.
+pub type StatsRecord = (u32, i32, NaiveDateTime, NaiveDateTime);
+
+/// How many times the statistics of a player are computed again after a
+/// deadlock.
+const DEADLOCK_RETRIES: usize = 3;
+
+/// Whether MySQL rolled back the transaction to break a deadlock. diesel only
+/// tells it by the message of the error.
+fn is_deadlock(e: &Error) -> bool {
+    match e {
+        Error::DatabaseError(_, info) => info.message().starts_with("Deadlock found"),
+        _ => false,
+    }
+}
+
+impl PlayerStats {
+    pub fn compute(player_id: &str, records: &[StatsRecord], now: NaiveDateTime) -> Self {
+        let mut ranks: Vec<u32> = records.iter().map(|record| record.0).collect();
+        ranks.sort_unstable();
+
+        let count = ranks.len();
+        let average_rank = if count > 0 {
+            Some(ranks.iter().map(|rank| f64::from(*rank)).sum::<f64>() / count as f64)
+        } else {
+            None
+        };
+        let median_rank = match count {
+            0 => None,
+            _ if count % 2 == 1 => Some(f64::from(ranks[count / 2])),
+            _ => Some((f64::from(ranks[count / 2 - 1]) + f64::from(ranks[count / 2])) / 2.0),
+        };
+
+        PlayerStats {
+            player_id: player_id.to_string(),
+            record_count: count as i32,
+            world_record_count: ranks.iter().filter(|rank| **rank == 1).count() as i32,
+            top_ten_count: ranks.iter().filter(|rank| **rank <= 10).count() as i32,
+            average_rank,
+            median_rank,
+            total_tries: records.iter().map(|record| i64::from(record.1)).sum(),
+            first_activity: records.iter().map(|record| record.2).min(),
+            last_activity: records.iter().map(|record| record.3).max(),
+            computed_at: now,
+        }
+    }
+
+    /// The statistics of a player, from the cache or computed and cached.
+    ///
+    /// The cache and the records are read with locks, so a change of the
+    /// records invalidating the cache waits for the statistics to be saved, or
+    /// is waited for and counted. Otherwise statistics computed before a change
+    /// could be saved after its invalidation and kept.
+    ///
+    /// Locking a missing row of the cache locks the gap around it: two
+    /// players without statistics in the same gap deadlock when they save
+    /// them, and the one rolled back is computed again. So this must not be
+    /// called inside a transaction, which the deadlock would roll back.
+    pub fn of(conn: &MysqlConnection, player_id: &str) -> QueryResult<PlayerStats> {
+        let mut retries = 0;
+        loop {
+            match PlayerStats::cached_or_computed(conn, player_id) {
+                Err(ref e) if retries < DEADLOCK_RETRIES && is_deadlock(e) => retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    fn cached_or_computed(conn: &MysqlConnection, player_id: &str) -> QueryResult<PlayerStats> {
+        conn.transaction(|| {
+            let cached: Option<PlayerStats> = player_stats::table
+                .find(player_id)
+                .for_update()
+                .get_result(conn)
+                .optional()?;
+            if let Some(stats) = cached {
+                return Ok(stats);
+            }
+
+            let records: Vec<StatsRecord> = records::table
+                .filter(records::player_id.eq(player_id))
+                .select((
+                    records::rank,
+                    records::try_count,
+                    records::created_at,
+                    records::updated_at,
+                ))
+                .for_update()
+                .load(conn)?;
+            let stats = PlayerStats::compute(player_id, &records, Utc::now().naive_utc());
+
+            diesel::replace_into(player_stats::table)
+                .values(&stats)
+                .execute(conn)?;
+            Ok(stats)
+        })
+    }
+
+    /// Computes and caches the statistics of every player with records and
+    /// without cached statistics, returns how many were computed.
+    pub fn warm_up(conn: &MysqlConnection) -> QueryResult<usize> {
+        let missing: Vec<String> = records::table
+            .left_join(player_stats::table.on(player_stats::player_id.eq(records::player_id)))
+            .filter(player_stats::player_id.nullable().is_null())
+            .select(records::player_id)
+            .distinct()
+            .load(conn)?;
+
+        for player_id in &missing {
+            PlayerStats::of(conn, player_id)?;
+        }
+        Ok(missing.len())
+    }
+
+    /// Forgets the statistics of a player, after a change of its records.
+    pub fn invalidate(conn: &MysqlConnection, player_id: &str) -> QueryResult<usize> {
+        diesel::delete(player_stats::table.find(player_id)).execute(conn)
+    }
+
+    /// Forgets the statistics of every player with a record on a map, after
+    /// its ranks changed.
+    pub fn invalidate_map(conn: &MysqlConnection, map_id: &str) -> QueryResult<usize> {
+        let query = r#"
+DELETE player_stats FROM player_stats
+INNER JOIN records ON records.player_id = player_stats.player_id
+WHERE records.map_id = ?;
+"#;
+
+        sql_query(query).bind::<VarChar, _>(map_id).execute(conn)
+    }
+}
+
+/// A map where a player improved its first time.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImprovedMap {
+    pub map_id: String,
+    /// The name without formatting codes
+    pub map_name: String,
+    pub first_time: i32,
+    pub time: i32,
+    /// Milliseconds gained since the first finish
+    pub improvement: i32,
+}
+
+impl ImprovedMap {
+    pub fn new(map_id: String, map_name: String, first_time: i32, time: i32) -> Self {
+        ImprovedMap {
+            map_id,
+            map_name,
+            first_time,
+            time,
+            improvement: first_time - time,
+        }
+    }
+
+    /// Sorts maps by improvement, the largest first, and keeps `limit` of them.
+    pub fn most_improved(mut maps: Vec<ImprovedMap>, limit: usize) -> Vec<ImprovedMap> {
+        maps.sort_by(|a, b| {
+            b.improvement
+                .cmp(&a.improvement)
+                .then_with(|| a.map_id.cmp(&b.map_id))
+        });
+        maps.truncate(limit);
+        maps
+    }
+
+    /// The maps where a player gained the most time since its first finish.
+    pub fn of_player(
+        conn: &MysqlConnection,
+        player_id: &str,
+        limit: usize,
+    ) -> QueryResult<Vec<ImprovedMap>> {
+        let improved: Vec<(String, String, Option<i32>, i32)> = records::table
+            .inner_join(maps::table)
+            .filter(records::player_id.eq(player_id))
+            .filter(records::first_time.gt(records::time.nullable()))
+            .select((
+                records::map_id,
+                maps::name_plain,
+                records::first_time,
+                records::time,
+            ))
+            .load(conn)?;
+
+        let improved = improved
+            .into_iter()
+            .filter_map(|(map_id, map_name, first_time, time)| {
+                first_time.map(|first_time| ImprovedMap::new(map_id, map_name, first_time, time))
+            })
+            .collect();
+        Ok(ImprovedMap::most_improved(improved, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn date(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2019, 4, day).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn test_compute_stats() {
+        let records = [
+            (1, 10, date(3), date(20)),
+            (12, 4, date(1), date(2)),
+            (1, 1, date(5), date(5)),
+            (7, 30, date(8), date(9)),
+        ];
+        let stats = PlayerStats::compute("smokegun", &records, date(30));
+        assert_eq!(stats.record_count, 4);
+        assert_eq!(stats.world_record_count, 2);
+        assert_eq!(stats.top_ten_count, 3);
+        assert_eq!(stats.average_rank, Some(5.25));
+        assert_eq!(stats.median_rank, Some(4.0));
+        assert_eq!(stats.total_tries, 45);
+        assert_eq!(stats.first_activity, Some(date(1)));
+        assert_eq!(stats.last_activity, Some(date(20)));
+
+        let stats = PlayerStats::compute("smokegun", &records[1..], date(30));
+        assert_eq!(stats.median_rank, Some(7.0));
+
+        let stats = PlayerStats::compute("nobody", &[], date(30));
+        assert_eq!(stats.record_count, 0);
+        assert_eq!(stats.average_rank, None);
+        assert_eq!(stats.median_rank, None);
+        assert_eq!(stats.first_activity, None);
+    }
+
+    #[test]
+    fn test_is_deadlock() {
+        use diesel::result::DatabaseErrorKind;
+
+        let error = |message: &str| {
+            Error::DatabaseError(DatabaseErrorKind::__Unknown, Box::new(message.to_string()))
+        };
+        assert!(is_deadlock(&error(
+            "Deadlock found when trying to get lock; try restarting transaction"
+        )));
+        assert!(!is_deadlock(&error("Lock wait timeout exceeded")));
+        assert!(!is_deadlock(&Error::NotFound));
+    }
+
+    #[test]
+    fn test_most_improved() {
+        let map = |map_id: &str, first_time, time| {
+            ImprovedMap::new(map_id.to_string(), map_id.to_string(), first_time, time)
+        };
+        let maps = vec![
+            map("A", 60000, 59000),
+            map("B", 90000, 70000),
+            map("C", 30000, 29000),
+            map("D", 45000, 44990),
+        ];
+
+        let best = ImprovedMap::most_improved(maps, 3);
+        let ids: Vec<&str> = best.iter().map(|map| map.map_id.as_str()).collect();
+        assert_eq!(ids, vec!["B", "A", "C"]);
+        assert_eq!(best[0].improvement, 20000);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::schema::{webhook_deliveries, webhooks};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde_derive::Serialize;
+
+/// An URL called when some events happen.
+#[derive(Queryable, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    /// Names of the events sent to the webhook, separated by commas
+    pub events: String,
+    /// Body of the calls, with `{{field}}` placeholders, the event as JSON
+    /// if there is none
+    pub template: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &str) -> bool {
+        self.events.split(',').any(|name| name.trim() == event)
+    }
+
+    pub fn create(
+        conn: &MysqlConnection,
+        url: &str,
+        events: &[&str],
+        template: Option<&str>,
+    ) -> QueryResult<usize> {
+        diesel::insert_into(webhooks::table)
+            .values((
+                webhooks::url.eq(url),
+                webhooks::events.eq(events.join(",")),
+                webhooks::template.eq(template),
+                webhooks::created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+    }
+
+    pub fn find(conn: &MysqlConnection, id: i32) -> QueryResult<Option<Webhook>> {
+        webhooks::table.find(id).get_result(conn).optional()
+    }
+
+    pub fn list(conn: &MysqlConnection) -> QueryResult<Vec<Webhook>> {
+        webhooks::table.order_by(webhooks::id).load(conn)
+    }
+
+    /// The webhooks wanting an event.
+    pub fn subscribed(conn: &MysqlConnection, event: &str) -> QueryResult<Vec<Webhook>> {
+        let webhooks = Webhook::list(conn)?;
+        Ok(webhooks
+            .into_iter()
+            .filter(|webhook| webhook.wants(event))
+            .collect())
+    }
+
+    /// Deletes a webhook with its queued calls.
+    pub fn delete(conn: &MysqlConnection, id: i32) -> QueryResult<usize> {
+        diesel::delete(webhooks::table.find(id)).execute(conn)
+    }
+}
+
+/// A queued call of a webhook, with the URL of the webhook.
+#[derive(Queryable, Debug)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub url: String,
+    pub payload: String,
+    /// Number of failed attempts so far
+    pub attempts: i32,
+}
+
+impl WebhookDelivery {
+    /// Queues a call of a webhook, to be made as soon as possible.
+    pub fn enqueue(
+        conn: &MysqlConnection,
+        webhook_id: i32,
+        event: &str,
+        payload: &str,
+    ) -> QueryResult<usize> {
+        let now = Utc::now().naive_utc();
+        diesel::insert_into(webhook_deliveries::table)
+            .values((
+                webhook_deliveries::webhook_id.eq(webhook_id),
+                webhook_deliveries::event.eq(event),
+                webhook_deliveries::payload.eq(payload),
+                webhook_deliveries::next_attempt_at.eq(now),
+                webhook_deliveries::created_at.eq(now),
+            ))
+            .execute(conn)
+    }
+
+    /// Takes the calls due now, the oldest first, and pushes their next
+    /// attempt back by `lease` so other workers leave them alone while they
+    /// are made.
+    pub fn claim_due(
+        conn: &MysqlConnection,
+        limit: i64,
+        lease: Duration,
+    ) -> QueryResult<Vec<WebhookDelivery>> {
+        conn.transaction(|| {
+            let now = Utc::now().naive_utc();
+            let due: Vec<WebhookDelivery> = webhook_deliveries::table
+                .inner_join(webhooks::table)
+                .filter(webhook_deliveries::delivered_at.is_null())
+                .filter(webhook_deliveries::next_attempt_at.le(now))
+                .order_by(webhook_deliveries::id)
+                .limit(limit)
+                .select((
+                    webhook_deliveries::id,
+                    webhooks::url,
+                    webhook_deliveries::payload,
+                    webhook_deliveries::attempts,
+                ))
+                .for_update()
+                .load(conn)?;
+
+            if !due.is_empty() {
+                let ids: Vec<i32> = due.iter().map(|delivery| delivery.id).collect();
+                diesel::update(
+                    webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(ids)),
+                )
+                .set(webhook_deliveries::next_attempt_at.eq(now + lease))
+                .execute(conn)?;
+            }
+
+            Ok(due)
+        })
+    }
+
+    pub fn delivered(&self, conn: &MysqlConnection) -> QueryResult<usize> {
+        diesel::update(webhook_deliveries::table.find(self.id))
+            .set((
+                webhook_deliveries::attempts.eq(self.attempts + 1),
+                webhook_deliveries::delivered_at.eq(Utc::now().naive_utc()),
+                webhook_deliveries::next_attempt_at.eq(None::<NaiveDateTime>),
+                webhook_deliveries::last_error.eq(None::<String>),
+            ))
+            .execute(conn)
+    }
+
+    /// Records a failed attempt, the call is given up when there is no next
+    /// attempt.
+    pub fn failed(
+        &self,
+        conn: &MysqlConnection,
+        error: &str,
+        next_attempt_at: Option<NaiveDateTime>,
+    ) -> QueryResult<usize> {
+        let error: String = error.chars().take(255).collect();
+        diesel::update(webhook_deliveries::table.find(self.id))
+            .set((
+                webhook_deliveries::attempts.eq(self.attempts + 1),
+                webhook_deliveries::next_attempt_at.eq(next_attempt_at),
+                webhook_deliveries::last_error.eq(error),
+            ))
+            .execute(conn)
+    }
+}
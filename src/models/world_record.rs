@@ -0,0 +1,67 @@
+use crate::models::map::Map;
+use crate::models::player::Player;
+use crate::models::record::Record;
+use crate::schema::{maps, players, world_records};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+/// A world record as it was set, kept after it is beaten.
+#[derive(Queryable, Debug)]
+pub struct WorldRecord {
+    pub id: i32,
+    pub map_id: String,
+    pub player_id: String,
+    pub time: i32,
+    pub respawn_count: i32,
+    pub try_count: i32,
+    pub medal: Option<String>,
+    pub set_at: NaiveDateTime,
+}
+
+impl WorldRecord {
+    /// Saves a record as a new world record of its map.
+    pub fn save(conn: &MysqlConnection, record: &Record) -> QueryResult<usize> {
+        diesel::insert_into(world_records::table)
+            .values((
+                world_records::map_id.eq(&record.map_id),
+                world_records::player_id.eq(&record.player_id),
+                world_records::time.eq(record.time),
+                world_records::respawn_count.eq(record.respawn_count),
+                world_records::try_count.eq(record.try_count),
+                world_records::medal.eq(&record.medal),
+                world_records::set_at.eq(record.updated_at),
+            ))
+            .execute(conn)
+    }
+
+    /// The world records last set, the latest first.
+    pub fn latest(
+        conn: &MysqlConnection,
+        offset: i64,
+        limit: i64,
+    ) -> QueryResult<Vec<(WorldRecord, Player, Map)>> {
+        world_records::table
+            .inner_join(players::table)
+            .inner_join(maps::table)
+            .order_by((world_records::set_at.desc(), world_records::id.desc()))
+            .offset(offset)
+            .limit(limit)
+            .load(conn)
+    }
+
+    /// The record as it was when it was set, first of its map.
+    pub fn into_record(self) -> Record {
+        Record {
+            rank: 1,
+            time: self.time,
+            respawn_count: self.respawn_count,
+            try_count: self.try_count,
+            created_at: self.set_at,
+            updated_at: self.set_at,
+            player_id: self.player_id,
+            map_id: self.map_id,
+            medal: self.medal,
+            first_time: None,
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! ManiaPlanet text formatting.
+//!
+//! Nicknames and map names use `$` codes for their style:
+//!
+//! - `$f00` (one to three hex digits) sets the color, `$g` resets it
+//! - `$o` bold, `$i` italic, `$s` shadow, `$t` uppercase
+//! - `$w` wide, `$n` narrow, `$m` normal width
+//! - `$z` resets the style, `$<` and `$>` save and restore it
+//! - `$l[url]text$l` is a link, `$ltext$l` links to its text, `$h` and `$p`
+//!   are in-game manialinks
+//! - `$$` is a dollar sign, any other code is ignored
+//!
+//! The names are parsed into spans of text sharing the same style, which can
+//! be rendered as plain text (for searching and sorting) or as HTML.
+
+use crate::escape::{self, Escape};
+use std::fmt::Write;
+
+/// A color, each component goes from 0 to 15.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Width {
+    #[default]
+    Normal,
+    Wide,
+    Narrow,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    pub color: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub shadow: bool,
+    pub uppercase: bool,
+    pub width: Width,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Link {
+    /// `$l`, a web link
+    External(String),
+    /// `$h` or `$p`, an in-game manialink
+    Manialink(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+    pub link: Option<Link>,
+}
+
+fn hex_value(c: char) -> Option<u8> {
+    c.to_digit(16).map(|d| d as u8)
+}
+
+/// Reads the `[target]` following a link code, if any.
+fn link_target(chars: &[char], pos: &mut usize) -> Option<String> {
+    if chars.get(*pos) != Some(&'[') {
+        return None;
+    }
+    let end = chars[*pos..].iter().position(|&c| c == ']')? + *pos;
+    let target = chars[*pos + 1..end].iter().collect();
+    *pos = end + 1;
+    Some(target)
+}
+
+/// The text up to the next link code, used as target of links without one.
+fn text_until_link_end(chars: &[char], pos: usize, code: char) -> String {
+    let mut end = pos;
+    while end < chars.len() {
+        if chars[end] == '$' && end + 1 < chars.len() {
+            if chars[end + 1].to_ascii_lowercase() == code {
+                break;
+            }
+            end += 2;
+        } else {
+            end += 1;
+        }
+    }
+    plain_text(&chars[pos..end].iter().collect::<String>())
+}
+
+pub fn parse(text: &str) -> Vec<Span> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut style = Style::default();
+    let mut saved_styles = Vec::new();
+    let mut link: Option<Link> = None;
+    let mut pos = 0;
+
+    let mut push_char = |c: char, style: Style, link: &Option<Link>| match spans.last_mut() {
+        Some(span) if span.style == style && &span.link == link => span.text.push(c),
+        _ => spans.push(Span {
+            text: c.to_string(),
+            style,
+            link: link.clone(),
+        }),
+    };
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        pos += 1;
+
+        if c != '$' {
+            push_char(c, style, &link);
+            continue;
+        }
+
+        let code = match chars.get(pos) {
+            Some(&code) => code,
+            // A lone `$` at the end is dropped
+            None => break,
+        };
+        pos += 1;
+
+        if let Some(r) = hex_value(code) {
+            let mut components = [r, 0, 0];
+            for component in components.iter_mut().skip(1) {
+                match chars.get(pos).and_then(|&c| hex_value(c)) {
+                    Some(value) => {
+                        *component = value;
+                        pos += 1;
+                    }
+                    None => break,
+                }
+            }
+            style.color = Some(Color {
+                r: components[0],
+                g: components[1],
+                b: components[2],
+            });
+            continue;
+        }
+
+        match code.to_ascii_lowercase() {
+            '$' => push_char('$', style, &link),
+            'g' => style.color = None,
+            'o' => style.bold = true,
+            'i' => style.italic = true,
+            's' => style.shadow = true,
+            't' => style.uppercase = true,
+            'w' => style.width = Width::Wide,
+            'n' => style.width = Width::Narrow,
+            'm' => style.width = Width::Normal,
+            'z' => style = Style::default(),
+            '<' => saved_styles.push(style),
+            '>' => style = saved_styles.pop().unwrap_or_default(),
+            kind @ 'l' | kind @ 'h' | kind @ 'p' => {
+                let target = link_target(&chars, &mut pos);
+                if link.is_some() && target.is_none() {
+                    link = None;
+                } else {
+                    let target = target.unwrap_or_else(|| text_until_link_end(&chars, pos, kind));
+                    link = Some(if kind == 'l' {
+                        Link::External(target)
+                    } else {
+                        Link::Manialink(target)
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    spans
+}
+
+/// The text without any formatting code.
+pub fn plain_text(text: &str) -> String {
+    parse(text).into_iter().map(|span| span.text).collect()
+}
+
+/// The plain text of a name stored escaped.
+pub fn plain_text_of_escaped(text: &str) -> String {
+    plain_text(&escape::unescape(text))
+}
+
+/// The `href` of a web link, only http(s) links are kept.
+fn safe_url(target: &str) -> Option<String> {
+    let target = target.trim();
+    let lower = target.to_ascii_lowercase();
+
+    if lower.starts_with("http://") || lower.starts_with("https://") {
+        Some(target.to_string())
+    } else if target.is_empty() || lower.contains(':') {
+        None
+    } else {
+        Some(format!("http://{}", target))
+    }
+}
+
+fn css(style: &Style) -> String {
+    let mut css = String::new();
+
+    if let Some(color) = style.color {
+        let _ = write!(css, "color:#{:x}{:x}{:x};", color.r, color.g, color.b);
+    }
+    if style.bold {
+        css.push_str("font-weight:bold;");
+    }
+    if style.italic {
+        css.push_str("font-style:italic;");
+    }
+    if style.shadow {
+        css.push_str("text-shadow:1px 1px 1px rgba(0,0,0,0.5);");
+    }
+    if style.uppercase {
+        css.push_str("text-transform:uppercase;");
+    }
+    match style.width {
+        Width::Normal => {}
+        Width::Wide => css.push_str("letter-spacing:0.1em;"),
+        Width::Narrow => css.push_str("letter-spacing:-0.1em;"),
+    }
+
+    css
+}
+
+/// Renders the text as HTML, every text and attribute being escaped.
+pub fn to_html(text: &str) -> String {
+    let mut html = String::new();
+    let mut open_link: Option<Link> = None;
+
+    for span in parse(text) {
+        if span.link != open_link {
+            if let Some(Link::External(ref target)) = open_link {
+                if safe_url(target).is_some() {
+                    html.push_str("</a>");
+                }
+            }
+            if let Some(Link::External(ref target)) = span.link {
+                if let Some(url) = safe_url(target) {
+                    let _ = write!(
+                        html,
+                        "<a href=\"{}\" rel=\"nofollow noopener noreferrer\">",
+                        Escape(&url)
+                    );
+                }
+            }
+            open_link = span.link.clone();
+        }
+
+        let css = css(&span.style);
+        if css.is_empty() {
+            let _ = write!(html, "{}", Escape(&span.text));
+        } else {
+            let _ = write!(
+                html,
+                "<span style=\"{}\">{}</span>",
+                css,
+                Escape(&span.text)
+            );
+        }
+    }
+
+    if let Some(Link::External(ref target)) = open_link {
+        if safe_url(target).is_some() {
+            html.push_str("</a>");
+        }
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(plain_text("$f00Smoke$fff$oGun"), "SmokeGun");
+        assert_eq!(plain_text("$i$s$w$n$m$t$z$g$<Name$>"), "Name");
+        assert_eq!(plain_text("Cost: 5$$"), "Cost: 5$");
+        assert_eq!(plain_text("$l[http://obstacle.fr]Obstacle$l!"), "Obstacle!");
+        assert_eq!(plain_text("$h[obstacle]Manialink$h"), "Manialink");
+        assert_eq!(plain_text("Trailing $"), "Trailing ");
+        assert_eq!(plain_text("$xUnknown"), "Unknown");
+        assert_eq!(plain_text("$F0ABC"), "BC");
+        assert_eq!(plain_text("$f0 short"), " short");
+    }
+
+    #[test]
+    fn test_plain_text_of_escaped() {
+        assert_eq!(
+            plain_text_of_escaped("$f00&lt;Smoke&gt; &amp; $oGun"),
+            "<Smoke> & Gun"
+        );
+    }
+
+    #[test]
+    fn test_parse_styles() {
+        let spans = parse("$f00$oRed$zPlain$<$iIt$>Back");
+        let texts: Vec<&str> = spans.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, vec!["Red", "Plain", "It", "Back"]);
+
+        assert_eq!(spans[0].style.color, Some(Color { r: 15, g: 0, b: 0 }));
+        assert!(spans[0].style.bold);
+        assert_eq!(spans[1].style, Style::default());
+        assert!(spans[2].style.italic);
+        assert_eq!(spans[3].style, Style::default());
+    }
+
+    #[test]
+    fn test_parse_links() {
+        let spans = parse("$l[obstacle.fr]site$l $lhttp://a.b$l");
+        assert_eq!(
+            spans[0].link,
+            Some(Link::External(String::from("obstacle.fr")))
+        );
+        assert_eq!(spans[1].link, None);
+        assert_eq!(
+            spans[2].link,
+            Some(Link::External(String::from("http://a.b")))
+        );
+    }
+
+    #[test]
+    fn test_to_html() {
+        assert_eq!(
+            to_html("$f00$oSmoke$zGun"),
+            "<span style=\"color:#f00;font-weight:bold;\">Smoke</span>Gun"
+        );
+        assert_eq!(
+            to_html("$l[obstacle.fr]Obstacle$l"),
+            "<a href=\"http://obstacle.fr\" rel=\"nofollow noopener noreferrer\">Obstacle</a>"
+        );
+    }
+
+    #[test]
+    fn test_to_html_is_safe() {
+        assert_eq!(
+            to_html("<script>alert(\"x\")</script>"),
+            "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;"
+        );
+        assert_eq!(to_html("$l[javascript:alert(1)]click$l"), "click");
+        assert_eq!(
+            to_html("$l[http://a.b/\"onclick=\"x]a$l"),
+            "<a href=\"http://a.b/&quot;onclick=&quot;x\" rel=\"nofollow noopener noreferrer\">a</a>"
+        );
+    }
+}
@@ -1,13 +1,25 @@
+use crate::metrics;
 use crate::models::map::Map;
-use crate::models::player::Player;
+use crate::models::medal::{Medal, MedalCount};
+use crate::models::player::{self, Player};
 use crate::models::record::*;
-use chrono::Utc;
+use crate::models::stats::PlayerStats;
+use crate::models::world_record::WorldRecord;
+use crate::search;
+use crate::webhooks::{self, Event};
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
 use diesel::sql_query;
+use diesel::sql_types::{BigInt, Nullable, VarChar};
+use serde_derive::Serialize;
+use std::collections::BTreeSet;
 
-fn update_ranks(connection: &MysqlConnection, map_id: &str) -> QueryResult<usize> {
-    let query = format!(
-        r#"
+/// Recomputes the rank of every record of the map from the times, and
+/// forgets the statistics of its players.
+pub fn update_ranks(connection: &MysqlConnection, map_id: &str) -> QueryResult<usize> {
+    let _timer = metrics::UPDATE_RANKS_DURATION.start_timer();
+
+    let query = r#"
 UPDATE
 	records,
 	(
@@ -16,200 +28,743 @@ UPDATE
 		records.player_id,
 		records.map_id
 	from records
-	where records.map_id = '{}'
+	where records.map_id = ?
 	) as RankedRecords
 SET
 	records.rank = RankedRecords.rank
 WHERE records.map_id = RankedRecords.map_id and records.player_id = RankedRecords.player_id;
-"#,
-        map_id
-    );
+"#;
+
+    let ranked = sql_query(query)
+        .bind::<VarChar, _>(map_id)
+        .execute(connection)?;
+    PlayerStats::invalidate_map(connection, map_id)?;
+    Ok(ranked)
+}
+
+/// Recomputes the ranks of every map, returns the number of maps ranked.
+pub fn update_all_ranks(connection: &MysqlConnection) -> QueryResult<usize> {
+    use crate::schema::maps;
+
+    let map_ids: Vec<String> = maps::table
+        .select(maps::maniaplanet_map_id)
+        .load(connection)?;
+
+    for map_id in &map_ids {
+        update_ranks(connection, map_id)?;
+    }
+
+    Ok(map_ids.len())
+}
+
+/// Recomputes the medal of every record of the map from its medal times.
+pub fn update_medals(connection: &MysqlConnection, map_id: &str) -> QueryResult<usize> {
+    let query = r#"
+UPDATE records INNER JOIN maps ON records.map_id = maps.maniaplanet_map_id
+SET records.medal = CASE
+    WHEN records.time <= maps.author_time THEN 'author'
+    WHEN records.time <= maps.gold_time THEN 'gold'
+    WHEN records.time <= maps.silver_time THEN 'silver'
+    WHEN records.time <= maps.bronze_time THEN 'bronze'
+    ELSE NULL
+END
+WHERE records.map_id = ?;
+"#;
+
+    sql_query(query)
+        .bind::<VarChar, _>(map_id)
+        .execute(connection)
+}
+
+/// Re-ranks the map once a player improved its time, then queues the webhook
+/// events. Returns whether the record took the first place.
+fn on_new_best(connection: &MysqlConnection, player_id: &str, map_id: &str) -> QueryResult<bool> {
+    let old_rank = rank_of(connection, player_id, map_id)?;
+    update_ranks(connection, map_id)?;
+    announce_new_best(connection, player_id, map_id, old_rank)
+}
+
+/// The rank of the record of a player on a map, 0 for a record not ranked
+/// yet.
+fn rank_of(connection: &MysqlConnection, player_id: &str, map_id: &str) -> QueryResult<u32> {
+    use crate::schema::records;
 
-    sql_query(query).execute(connection)
+    records::table
+        .find((map_id, player_id))
+        .select(records::rank)
+        .get_result(connection)
 }
 
-pub fn has_finished(
+/// Queues a webhook event for a world record or an entry in the top 10, once
+/// the map is re-ranked, and returns whether the record is first. In a
+/// bulk submission, only the world record left once the map is re-ranked is
+/// announced, every one of them is saved by `save_finish`.
+fn announce_new_best(
     connection: &MysqlConnection,
-    time: i32,
-    rs_count: i32,
     player_id: &str,
     map_id: &str,
-) -> QueryResult<(bool, i32, i32)> {
+    old_rank: u32,
+) -> QueryResult<bool> {
     use crate::schema::{maps, players, records};
 
-    let map: Option<Map> = maps::table.find(map_id).get_result(connection).optional()?;
-    if map.is_none() {
-        diesel::insert_into(maps::table)
-            .values((
-                maps::maniaplanet_map_id.eq(map_id),
-                maps::name.eq("Unknwown map"),
-                maps::player_id.eq("smokegun"),
-            ))
-            .execute(connection)?;
+    let record: Record = records::table
+        .find((map_id, player_id))
+        .get_result(connection)?;
+    let (rank, time) = (record.rank, record.time);
+
+    let entered_top_ten = rank <= 10 && (old_rank == 0 || old_rank > 10);
+    if rank != 1 && !entered_top_ten {
+        return Ok(false);
     }
 
-    let player: Option<Player> = players::table
+    // Equaling the time of the first place is not a world record
+    let first_places: i64 = records::table
+        .filter(records::map_id.eq(map_id))
+        .filter(records::rank.eq(1))
+        .count()
+        .get_result(connection)?;
+    if rank == 1 && first_places > 1 && !entered_top_ten {
+        return Ok(true);
+    }
+
+    let map_name: String = maps::table
+        .find(map_id)
+        .select(maps::name_plain)
+        .get_result(connection)?;
+    let nickname: String = players::table
         .find(player_id)
-        .get_result(connection)
-        .optional()?;
-    if player.is_none() {
-        diesel::insert_into(players::table)
-            .values((
-                players::login.eq(player_id),
-                players::nickname.eq(player_id),
+        .select(players::nickname_plain)
+        .get_result(connection)?;
+    let map_id = map_id.to_string();
+    let player_id = player_id.to_string();
+    let time_text = time_text(time);
+
+    let event = if rank == 1 && first_places == 1 {
+        Event::WorldRecord {
+            map_id,
+            map_name,
+            player_id,
+            nickname,
+            time,
+            time_text,
+        }
+    } else {
+        Event::TopTen {
+            map_id,
+            map_name,
+            player_id,
+            nickname,
+            time,
+            time_text,
+            rank,
+        }
+    };
+    webhooks::enqueue(connection, &event)?;
+
+    Ok(rank == 1)
+}
+
+/// Counts saved finishes in the metrics, once their transaction committed so
+/// a rollback counts nothing.
+fn count_finishes(results: &[Finish], world_records: i64) {
+    for result in results {
+        if result.replayed {
+            metrics::REPLAYED_FINISHES.inc();
+        } else {
+            metrics::FINISHES.inc();
+            if result.is_new_best {
+                metrics::NEW_BESTS.inc();
+            }
+        }
+    }
+    metrics::WORLD_RECORDS.inc_by(world_records);
+}
+
+/// The outcome of a finish.
+#[derive(Debug, PartialEq)]
+pub struct Finish {
+    pub is_new_best: bool,
+    /// The previous best time, or the new one on a first finish
+    pub old: i32,
+    pub new: i32,
+    /// The medal earned, when it is better than the one of the previous best
+    pub new_medal: Option<Medal>,
+    /// The zone path of the player, when it is known
+    pub zone: Option<String>,
+    /// The rank of the best time among the players of the zone
+    pub zone_rank: Option<u32>,
+    /// Whether the finish was already saved with the same idempotency key,
+    /// the outcome being the one of the first time, except the zone rank
+    /// which is the current one
+    pub replayed: bool,
+}
+
+/// Saves a finish of a player on a map, creating the map and the player when
+/// they are unknown.
+///
+/// Everything happens in one transaction holding a lock on the map, so the
+/// finishes of a map are saved one after the other and its ranks stay
+/// consistent.
+pub fn has_finished(connection: &MysqlConnection, finish: &NewFinish) -> QueryResult<Finish> {
+    let (result, is_world_record) =
+        connection.transaction::<_, diesel::result::Error, _>(|| {
+            let result = save_finish(connection, finish)?;
+            let is_world_record = result.is_new_best
+                && !result.replayed
+                && on_new_best(connection, finish.player_id, finish.map_id)?;
+            Ok((result, is_world_record))
+        })?;
+
+    count_finishes(std::slice::from_ref(&result), i64::from(is_world_record));
+    Ok(result)
+}
+
+/// How many days the idempotency keys are kept by default. A finish which
+/// happened before could be one whose key was forgotten, it is refused.
+pub const FINISH_KEY_DAYS: i64 = 7;
+
+/// A finish sent by a game server.
+#[derive(Clone, Copy, Debug)]
+pub struct NewFinish<'a> {
+    pub time: i32,
+    pub respawn_count: i32,
+    pub player_id: &'a str,
+    pub map_id: &'a str,
+    /// Key chosen by the game server, a finish sent again with the same key
+    /// is not saved twice
+    pub idempotency_key: Option<&'a str>,
+    /// When the player finished, now if unknown
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+/// Saves several finishes in one transaction, in their order, and re-ranks
+/// each improved map once. Returns the outcome of each finish.
+///
+/// The maps are locked, then the unknown players created, in the order of
+/// their ids so concurrent submissions can't deadlock.
+pub fn have_finished(
+    connection: &MysqlConnection,
+    finishes: &[NewFinish],
+) -> QueryResult<Vec<Finish>> {
+    let (results, world_records) = connection.transaction::<_, diesel::result::Error, _>(|| {
+        let map_ids: BTreeSet<&str> = finishes.iter().map(|finish| finish.map_id).collect();
+        for map_id in &map_ids {
+            lock_map(connection, map_id)?;
+        }
+        let player_ids: BTreeSet<&str> = finishes.iter().map(|finish| finish.player_id).collect();
+        for player_id in &player_ids {
+            create_player(connection, player_id)?;
+        }
+
+        let mut results = Vec::with_capacity(finishes.len());
+        let mut new_bests = BTreeSet::new();
+        for finish in finishes {
+            let result = save_finish(connection, finish)?;
+            if result.is_new_best && !result.replayed {
+                new_bests.insert((finish.map_id, finish.player_id));
+            }
+            results.push(result);
+        }
+
+        let mut old_ranks = Vec::with_capacity(new_bests.len());
+        for &(map_id, player_id) in &new_bests {
+            old_ranks.push((map_id, player_id, rank_of(connection, player_id, map_id)?));
+        }
+
+        let improved_maps: BTreeSet<&str> = new_bests.iter().map(|&(map_id, _)| map_id).collect();
+        for map_id in improved_maps {
+            update_ranks(connection, map_id)?;
+        }
+        let mut world_records = 0;
+        for (map_id, player_id, old_rank) in old_ranks {
+            if announce_new_best(connection, player_id, map_id, old_rank)? {
+                world_records += 1;
+            }
+        }
+
+        Ok((results, world_records))
+    })?;
+
+    count_finishes(&results, world_records);
+    Ok(results)
+}
+
+/// Locks the map until the end of the transaction, creating it when it is
+/// unknown. The finishes of a map wait for each other on this lock.
+fn lock_map(connection: &MysqlConnection, map_id: &str) -> QueryResult<Map> {
+    use crate::schema::maps;
+
+    let map_created = diesel::insert_or_ignore_into(maps::table)
+        .values((
+            maps::maniaplanet_map_id.eq(map_id),
+            maps::name.eq("Unknwown map"),
+            maps::name_plain.eq("Unknwown map"),
+            maps::player_id.eq("smokegun"),
+        ))
+        .execute(connection)?;
+    if map_created > 0 {
+        search::index_name(connection, search::Kind::Map, map_id, "Unknwown map")?;
+    }
+
+    maps::table.find(map_id).for_update().get_result(connection)
+}
+
+/// Creates the player when it is unknown, named after its login.
+fn create_player(connection: &MysqlConnection, player_id: &str) -> QueryResult<()> {
+    use crate::schema::players;
+
+    let player_created = diesel::insert_or_ignore_into(players::table)
+        .values((
+            players::login.eq(player_id),
+            players::nickname.eq(player_id),
+            players::nickname_plain.eq(player_id),
+        ))
+        .execute(connection)?;
+    if player_created > 0 {
+        search::index_name(connection, search::Kind::Player, player_id, player_id)?;
+    }
+    Ok(())
+}
+
+/// The outcome of a finish saved with an idempotency key.
+#[derive(Queryable)]
+struct FinishKey {
+    is_new_best: bool,
+    old_time: i32,
+    new_time: i32,
+    medal: Option<String>,
+}
+
+/// Saves a finish without re-ranking the map, inside a transaction.
+fn save_finish(connection: &MysqlConnection, finish: &NewFinish) -> QueryResult<Finish> {
+    use crate::schema::{finish_keys, players, records};
+
+    let NewFinish {
+        time,
+        respawn_count: rs_count,
+        player_id,
+        map_id,
+        ..
+    } = *finish;
+
+    let map = lock_map(connection, map_id)?;
+
+    // The lock on the map also makes the replays wait for the first save
+    if let Some(key) = finish.idempotency_key {
+        let saved: Option<FinishKey> = finish_keys::table
+            .find((map_id, player_id, key))
+            .select((
+                finish_keys::is_new_best,
+                finish_keys::old_time,
+                finish_keys::new_time,
+                finish_keys::medal,
             ))
-            .execute(connection)?;
+            .get_result(connection)
+            .optional()?;
+
+        if let Some(saved) = saved {
+            let mut result = Finish {
+                is_new_best: saved.is_new_best,
+                old: saved.old_time,
+                new: saved.new_time,
+                new_medal: saved.medal.as_deref().and_then(Medal::parse),
+                zone: None,
+                zone_rank: None,
+                replayed: true,
+            };
+            let player: Player = players::table.find(player_id).get_result(connection)?;
+            add_zone_rank(connection, map_id, player, &mut result)?;
+            return Ok(result);
+        }
     }
 
-    let has_previous: Result<Record, _> = records::table
+    let finished_at = finish.finished_at.unwrap_or_else(|| Utc::now().naive_utc());
+
+    create_player(connection, player_id)?;
+
+    let medal = Medal::for_time(&map, time);
+
+    let player: Player = players::table.find(player_id).get_result(connection)?;
+
+    let previous_record: Option<Record> = records::table
         .find((map_id, player_id))
-        .get_result(connection);
+        .for_update()
+        .get_result(connection)
+        .optional()?;
 
-    match has_previous {
-        Ok(previous_record) => {
+    let mut result = match previous_record {
+        Some(previous_record) => {
             let old = previous_record.time;
             let new = time;
 
-            // update with new time
+            let mut new_medal = None;
+
             if new < old {
-                let _updated = diesel::update(&previous_record)
+                diesel::update(&previous_record)
                     .set((
                         records::time.eq(new),
                         records::respawn_count.eq(rs_count),
                         records::try_count.eq(records::try_count + 1),
-                        records::updated_at.eq(Utc::now().naive_utc()),
+                        records::updated_at.eq(improved_at(&previous_record, finished_at)),
+                        records::medal.eq(medal.map(Medal::as_str)),
                     ))
                     .execute(connection)?;
-                update_ranks(connection, map_id)?;
+
+                let previous_medal = previous_record.medal.as_deref().and_then(Medal::parse);
+                new_medal = medal.filter(|medal| medal.improves(previous_medal));
             }
 
-            Ok((new < old, old, new))
+            Finish {
+                is_new_best: new < old,
+                old,
+                new,
+                new_medal,
+                zone: None,
+                zone_rank: None,
+                replayed: false,
+            }
         }
 
-        _ => {
+        None => {
             let new = Record {
                 time,
                 respawn_count: rs_count,
                 try_count: 1,
-                created_at: Utc::now().naive_utc(),
-                updated_at: Utc::now().naive_utc(),
+                created_at: finished_at,
+                updated_at: finished_at,
                 player_id: player_id.to_string(),
                 map_id: map_id.to_string(),
                 rank: 0,
+                medal: medal.map(|medal| medal.as_str().to_string()),
+                first_time: Some(time),
             };
 
-            let _inserted_record = diesel::insert_into(records::table)
+            diesel::insert_into(records::table)
                 .values(new)
                 .execute(connection)?;
 
-            update_ranks(connection, map_id)?;
-
-            Ok((true, time, time))
+            Finish {
+                is_new_best: true,
+                old: time,
+                new: time,
+                new_medal: medal,
+                zone: None,
+                zone_rank: None,
+                replayed: false,
+            }
         }
+    };
+
+    if result.is_new_best {
+        save_world_record(connection, map_id, player_id, result.new)?;
+    }
+
+    if let Some(key) = finish.idempotency_key {
+        diesel::insert_into(finish_keys::table)
+            .values((
+                finish_keys::map_id.eq(map_id),
+                finish_keys::player_id.eq(player_id),
+                finish_keys::idempotency_key.eq(key),
+                finish_keys::is_new_best.eq(result.is_new_best),
+                finish_keys::old_time.eq(result.old),
+                finish_keys::new_time.eq(result.new),
+                finish_keys::medal.eq(result.new_medal.map(Medal::as_str)),
+                finish_keys::created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(connection)?;
     }
+
+    add_zone_rank(connection, map_id, player, &mut result)?;
+
+    Ok(result)
 }
 
-pub fn overview(
+/// Saves the record of the player in the history of the world records when
+/// its new time beats the times of every other player of the map. This runs
+/// while the map is locked, so each world record of a bulk submission is
+/// saved, even when it is beaten by a later finish of the submission.
+fn save_world_record(
     connection: &MysqlConnection,
-    player_id: &str,
     map_id: &str,
-) -> QueryResult<Vec<RankedRecord>> {
+    player_id: &str,
+    time: i32,
+) -> QueryResult<()> {
     use crate::schema::records;
 
-    let query = format!(
-        r#"
-    SELECT
-        records.rank,
-        records.player_id,
-        players.nickname,
-        records.time
-    FROM records
-    INNER JOIN players ON records.player_id=players.login
-    where map_id = "{}"
-    ORDER BY time;"#,
-        map_id
-    );
+    // Equaling the time of the first place is not a world record
+    let best_of_others: Option<i32> = records::table
+        .filter(records::map_id.eq(map_id))
+        .filter(records::player_id.ne(player_id))
+        .select(diesel::dsl::min(records::time))
+        .get_result(connection)?;
+    let is_world_record = match best_of_others {
+        Some(best) => time < best,
+        None => true,
+    };
 
-    let mut records = sql_query(query).load::<RankedRecord>(connection)?;
-    let mut rows = 15;
+    if is_world_record {
+        let record: Record = records::table
+            .find((map_id, player_id))
+            .get_result(connection)?;
+        WorldRecord::save(connection, &record)?;
+    }
+    Ok(())
+}
 
-    let has_record: Option<Record> = records::table
-        .find((map_id, player_id))
-        .get_result(connection)
-        .optional()?;
+/// When a record is improved by a finish. A finish sent late keeps its date
+/// unless it is older than the last improvement, the dates of a record never
+/// go backwards.
+fn improved_at(previous_record: &Record, finished_at: NaiveDateTime) -> NaiveDateTime {
+    finished_at
+        .max(previous_record.updated_at)
+        .max(previous_record.created_at)
+}
 
-    match has_record {
-        Some(player_record) => {
-            let player_idx = records
-                .binary_search_by(|record| record.time.cmp(&player_record.time))
-                .unwrap();
+/// Fills the zone of the player and the rank of the best time of the finish
+/// in this zone, when the zone is known.
+fn add_zone_rank(
+    connection: &MysqlConnection,
+    map_id: &str,
+    player: Player,
+    result: &mut Finish,
+) -> QueryResult<()> {
+    if let Some(zone) = player.zone_path {
+        let best = result.old.min(result.new);
+        result.zone_rank = Some(zone_rank_of_time(connection, map_id, &zone, best)?);
+        result.zone = Some(zone);
+    }
+    Ok(())
+}
 
-            if player_idx < rows {
-                records.truncate(rows);
-                Ok(records)
-            } else {
-                let mut res: Vec<RankedRecord> = Vec::with_capacity(rows);
+/// Deletes the idempotency keys saved before a date, returns the number of
+/// keys deleted.
+pub fn prune_finish_keys(
+    connection: &MysqlConnection,
+    before: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::schema::finish_keys;
 
-                res.extend_from_slice(&records[0..3]);
+    diesel::delete(finish_keys::table.filter(finish_keys::created_at.lt(before)))
+        .execute(connection)
+}
 
-                let row_minus_top3 = rows - 3;
-                let mut start_idx = player_idx - row_minus_top3 / 2;
-                let mut end_idx = player_idx + row_minus_top3 / 2;
+/// The rank of a time on the map among the players of a zone and its
+/// subzones: one more than the number of better times.
+fn zone_rank_of_time(
+    connection: &MysqlConnection,
+    map_id: &str,
+    zone: &str,
+    time: i32,
+) -> QueryResult<u32> {
+    use crate::schema::{players, records};
 
-                if end_idx >= records.len() {
-                    start_idx -= end_idx - records.len();
-                    end_idx = records.len();
-                }
+    let better: i64 = records::table
+        .inner_join(players::table)
+        .filter(records::map_id.eq(map_id))
+        .filter(records::time.lt(time))
+        .filter(
+            players::zone_path
+                .eq(zone)
+                .or(players::zone_path.like(player::subzones_pattern(zone))),
+        )
+        .count()
+        .get_result(connection)?;
 
-                res.extend_from_slice(&records[start_idx..end_idx]);
-                Ok(res)
-            }
-        }
+    Ok(better as u32 + 1)
+}
 
-        _ => {
-            // We keep one row at the end for the player
-            rows -= 1;
-
-            if records.len() > rows {
-                let mut res: Vec<RankedRecord> = Vec::with_capacity(rows);
-                res.extend_from_slice(&records[..rows - 3]);
-                res.extend_from_slice(&records[records.len() - 3..]);
-                Ok(res)
-            } else {
-                records.truncate(rows);
-                Ok(records)
-            }
+/// The ranks of a page of times sorted from the best, the first one having
+/// the given rank. Equal times share their rank.
+fn page_ranks(times: &[i32], offset: i64, first_rank: u32) -> Vec<u32> {
+    let mut ranks: Vec<u32> = Vec::with_capacity(times.len());
+
+    for (i, time) in times.iter().enumerate() {
+        let rank = match ranks.last() {
+            None => first_rank,
+            Some(&previous) if times[i - 1] == *time => previous,
+            Some(_) => offset as u32 + i as u32 + 1,
+        };
+        ranks.push(rank);
+    }
+
+    ranks
+}
+
+const OVERVIEW: &str = r#"
+SELECT
+    records.rank,
+    records.player_id,
+    players.nickname,
+    records.time
+FROM records
+INNER JOIN players ON records.player_id = players.login
+WHERE records.map_id = ?
+ORDER BY records.time;"#;
+
+const ZONE_OVERVIEW: &str = r#"
+SELECT
+    CAST(RANK() OVER (ORDER BY records.time) AS UNSIGNED) AS `rank`,
+    records.player_id,
+    players.nickname,
+    records.time
+FROM records
+INNER JOIN players ON records.player_id = players.login
+WHERE records.map_id = ?
+    AND (players.zone_path = ? OR players.zone_path LIKE ?)
+ORDER BY records.time;"#;
+
+/// How many records the overview shows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OverviewLayout {
+    /// Rows of the overview, including the one of the player
+    pub rows: usize,
+    /// Best records always shown when the player is further down
+    pub top: usize,
+    /// Records shown before and after the one of the player when it is
+    /// further down, as many as the rows allow
+    pub around: usize,
+}
+
+impl Default for OverviewLayout {
+    fn default() -> Self {
+        OverviewLayout {
+            rows: 15,
+            top: 3,
+            around: 6,
         }
     }
 }
 
+/// The indices of the records shown in the overview of a leaderboard of `len`
+/// records, `player_idx` being the one of the record of the player.
+///
+/// The best records are shown when the player is among them. When the player
+/// has no record, the last row is left for the player. Otherwise the `top`
+/// best records are followed by the records around the one of the player,
+/// `around` on each side at most.
+pub fn overview_window(
+    len: usize,
+    player_idx: Option<usize>,
+    layout: OverviewLayout,
+) -> Vec<usize> {
+    let rows = layout.rows;
+    let player_idx = match player_idx {
+        Some(idx) if idx < len => idx,
+        _ => return (0..len.min(rows.saturating_sub(1))).collect(),
+    };
+
+    if rows == 0 || player_idx < rows || len <= rows {
+        return (0..len.min(rows)).collect();
+    }
+
+    // The player is below the best records
+    let top = layout.top.min(rows - 1);
+    let window = (rows - top).min(layout.around.saturating_mul(2).saturating_add(1));
+    let start = (player_idx - window / 2).max(top).min(len - window);
+
+    (0..top).chain(start..start + window).collect()
+}
+
+/// The records of the overview and the size of the whole leaderboard.
+#[derive(Serialize)]
+pub struct Overview {
+    pub records: Vec<OverviewRecord>,
+    pub total: usize,
+}
+
+/// The records around the one of the player. With a zone, only the players
+/// of the zone and its subzones are kept, ranked among themselves.
+pub fn overview(
+    connection: &MysqlConnection,
+    player_id: &str,
+    map_id: &str,
+    zone: Option<&str>,
+    layout: OverviewLayout,
+) -> QueryResult<Overview> {
+    let records = match zone {
+        Some(zone) => sql_query(ZONE_OVERVIEW)
+            .bind::<VarChar, _>(map_id)
+            .bind::<VarChar, _>(zone)
+            .bind::<VarChar, _>(player::subzones_pattern(zone))
+            .load::<RankedRecord>(connection)?,
+        None => sql_query(OVERVIEW)
+            .bind::<VarChar, _>(map_id)
+            .load::<RankedRecord>(connection)?,
+    };
+
+    Ok(overview_of(&records, player_id, layout))
+}
+
+/// The rows of the overview shown to the player, out of the ranked records
+/// of the map, the player being flagged.
+fn overview_of(records: &[RankedRecord], player_id: &str, layout: OverviewLayout) -> Overview {
+    let player_idx = records
+        .iter()
+        .position(|record| record.player_id == player_id);
+
+    let shown = overview_window(records.len(), player_idx, layout)
+        .into_iter()
+        .map(|idx| OverviewRecord::new(records[idx].clone(), Some(idx) == player_idx))
+        .collect();
+
+    Overview {
+        records: shown,
+        total: records.len(),
+    }
+}
+
 pub fn latest_records(
     connection: &MysqlConnection,
     offset: i64,
     limit: i64,
+) -> QueryResult<Vec<(Record, Player, Map)>> {
+    latest_records_of(connection, LatestRecords::All, offset, limit)
+}
+
+/// Which of the latest records are kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LatestRecords<'a> {
+    All,
+    Map(&'a str),
+    Player(&'a str),
+}
+
+/// The records last improved, the latest first.
+pub fn latest_records_of(
+    connection: &MysqlConnection,
+    filter: LatestRecords,
+    offset: i64,
+    limit: i64,
 ) -> QueryResult<Vec<(Record, Player, Map)>> {
     use crate::schema::{maps, players, records};
 
     let join = records::table
         .inner_join(players::table)
-        .inner_join(maps::table);
+        .inner_join(maps::table)
+        .into_boxed();
 
-    let latest_rec = join
-        .offset(offset)
+    let join = match filter {
+        LatestRecords::All => join,
+        LatestRecords::Map(map_id) => join.filter(records::map_id.eq(map_id)),
+        LatestRecords::Player(player_id) => join.filter(records::player_id.eq(player_id)),
+    };
+
+    join.offset(offset)
         .limit(limit)
         .order_by(records::updated_at.desc())
-        .load(connection)?;
-
-    Ok(latest_rec)
+        .load(connection)
 }
 
-pub type MapRecords =  (Map, Player, Vec<(Record, Player)>);
+pub type MapRecords = (Map, Player, Vec<(Record, Player)>);
 
+/// A page of the leaderboard of a map. With a zone, only the players of the
+/// zone and its subzones are kept, ranked among themselves.
 pub fn map_records(
     connection: &MysqlConnection,
     offset: i64,
     limit: i64,
     map_id: &str,
+    zone: Option<&str>,
 ) -> QueryResult<Option<MapRecords>> {
     use crate::schema::{maps, players, records};
 
@@ -221,15 +776,35 @@ pub fn map_records(
 
     let cur_map = map.unwrap();
 
-    let join = records::table.inner_join(players::table);
+    let mut query = records::table
+        .inner_join(players::table)
+        .filter(records::map_id.eq(map_id))
+        .into_boxed();
+    if let Some(zone) = zone {
+        query = query.filter(
+            players::zone_path
+                .eq(zone)
+                .or(players::zone_path.like(player::subzones_pattern(zone))),
+        );
+    }
 
-    let records = join
+    let mut records: Vec<(Record, Player)> = query
         .offset(offset)
         .limit(limit)
         .order_by(records::time)
-        .filter(records::map_id.eq(map_id))
         .load(connection)?;
 
+    if let (Some(zone), Some((first, _))) = (zone, records.first()) {
+        let first_rank = zone_rank_of_time(connection, map_id, zone, first.time)?;
+        let times: Vec<i32> = records.iter().map(|(record, _)| record.time).collect();
+        for ((record, _), rank) in records
+            .iter_mut()
+            .zip(page_ranks(&times, offset, first_rank))
+        {
+            record.rank = rank;
+        }
+    }
+
     let player = players::table
         .find(&cur_map.player_id)
         .get_result(connection)?;
@@ -241,6 +816,8 @@ pub type PlayerRecords = (Player, Vec<(Record, Map)>);
 
 pub fn player_records(
     connection: &MysqlConnection,
+    offset: i64,
+    limit: i64,
     player_id: &str,
 ) -> QueryResult<Option<PlayerRecords>> {
     use crate::schema::{maps, players, records};
@@ -259,7 +836,428 @@ pub fn player_records(
         .inner_join(maps::table)
         .filter(records::player_id.eq(player_id))
         .order_by(records::updated_at.desc())
+        .offset(offset)
+        .limit(limit)
         .load::<(Record, Map)>(connection)?;
 
     Ok(Some((cur_player, records)))
 }
+
+const MEDAL_COUNTS: &str = r#"
+SELECT
+    records.player_id,
+    players.nickname,
+    CAST(COALESCE(SUM(records.medal = 'author'), 0) AS SIGNED) AS author,
+    CAST(COALESCE(SUM(records.medal = 'gold'), 0) AS SIGNED) AS gold,
+    CAST(COALESCE(SUM(records.medal = 'silver'), 0) AS SIGNED) AS silver,
+    CAST(COALESCE(SUM(records.medal = 'bronze'), 0) AS SIGNED) AS bronze
+FROM records
+INNER JOIN players ON records.player_id = players.login
+"#;
+
+/// The players with the most medals, author medals first, then gold, silver
+/// and bronze ones. With a zone, only the players of the zone and its
+/// subzones are kept.
+pub fn medal_leaderboard(
+    connection: &MysqlConnection,
+    offset: i64,
+    limit: i64,
+    zone: Option<&str>,
+) -> QueryResult<Vec<MedalCount>> {
+    let query = format!(
+        "{}
+WHERE records.medal IS NOT NULL
+    AND (? IS NULL OR players.zone_path = ? OR players.zone_path LIKE ?)
+GROUP BY records.player_id, players.nickname
+ORDER BY author DESC, gold DESC, silver DESC, bronze DESC, records.player_id
+LIMIT ? OFFSET ?;",
+        MEDAL_COUNTS
+    );
+
+    sql_query(query)
+        .bind::<Nullable<VarChar>, _>(zone)
+        .bind::<Nullable<VarChar>, _>(zone)
+        .bind::<Nullable<VarChar>, _>(zone.map(player::subzones_pattern))
+        .bind::<BigInt, _>(limit)
+        .bind::<BigInt, _>(offset)
+        .load(connection)
+}
+
+/// The medals of a player, `None` if it has no medal.
+pub fn player_medals(
+    connection: &MysqlConnection,
+    player_id: &str,
+) -> QueryResult<Option<MedalCount>> {
+    let query = format!(
+        "{}
+WHERE records.medal IS NOT NULL AND records.player_id = ?
+GROUP BY records.player_id, players.nickname;",
+        MEDAL_COUNTS
+    );
+
+    sql_query(query)
+        .bind::<VarChar, _>(player_id)
+        .get_result(connection)
+        .optional()
+}
+
+/// Deletes the record of a player on a map and re-ranks the map.
+pub fn delete_record(
+    connection: &MysqlConnection,
+    map_id: &str,
+    player_id: &str,
+) -> QueryResult<usize> {
+    use crate::schema::records;
+
+    connection.transaction(|| {
+        let deleted =
+            diesel::delete(records::table.find((map_id, player_id))).execute(connection)?;
+        if deleted > 0 {
+            update_ranks(connection, map_id)?;
+            PlayerStats::invalidate(connection, player_id)?;
+        }
+        Ok(deleted)
+    })
+}
+
+/// Merges the player `from` into the player `into`, for players who used
+/// several logins.
+///
+/// The records of `from` are moved to `into`, keeping the better time when
+/// both have a record on a map, the maps made by `from` are given to `into`,
+/// as well as its nickname history, then `from` is deleted. Everything
+/// happens in one transaction. Returns the ids of the re-ranked maps.
+pub fn merge_players(
+    connection: &MysqlConnection,
+    from: &str,
+    into: &str,
+) -> QueryResult<Vec<String>> {
+    use crate::schema::{finish_keys, maps, nickname_history, players, records, world_records};
+
+    // Logins are compared without case, like the database does, a player
+    // merged into itself would lose every record
+    if from.to_lowercase() == into.to_lowercase() {
+        return Err(diesel::result::Error::QueryBuilderError(
+            format!("can't merge player {} into itself", from).into(),
+        ));
+    }
+
+    connection.transaction(|| {
+        // Both players must exist
+        let _: Player = players::table.find(from).get_result(connection)?;
+        let into_player: Player = players::table.find(into).get_result(connection)?;
+
+        let from_records: Vec<Record> = records::table
+            .filter(records::player_id.eq(from))
+            .load(connection)?;
+
+        let mut affected_maps = Vec::with_capacity(from_records.len());
+
+        for from_record in from_records {
+            let into_record: Option<Record> = records::table
+                .find((&from_record.map_id, into))
+                .get_result(connection)
+                .optional()?;
+
+            match into_record {
+                Some(into_record) => {
+                    let try_count = into_record.try_count + from_record.try_count;
+                    let created_at = into_record.created_at.min(from_record.created_at);
+                    // The first time of the record finished first
+                    let first_time = if from_record.created_at < into_record.created_at {
+                        from_record.first_time.or(into_record.first_time)
+                    } else {
+                        into_record.first_time.or(from_record.first_time)
+                    };
+
+                    if from_record.time < into_record.time {
+                        diesel::update(&into_record)
+                            .set((
+                                records::time.eq(from_record.time),
+                                records::respawn_count.eq(from_record.respawn_count),
+                                records::try_count.eq(try_count),
+                                records::created_at.eq(created_at),
+                                records::updated_at.eq(from_record.updated_at),
+                                records::medal.eq(&from_record.medal),
+                                records::first_time.eq(first_time),
+                            ))
+                            .execute(connection)?;
+                    } else {
+                        diesel::update(&into_record)
+                            .set((
+                                records::try_count.eq(try_count),
+                                records::created_at.eq(created_at),
+                                records::first_time.eq(first_time),
+                            ))
+                            .execute(connection)?;
+                    }
+
+                    diesel::delete(&from_record).execute(connection)?;
+                }
+                None => {
+                    diesel::update(&from_record)
+                        .set(records::player_id.eq(into))
+                        .execute(connection)?;
+                }
+            }
+
+            affected_maps.push(from_record.map_id);
+        }
+
+        diesel::update(maps::table.filter(maps::player_id.eq(from)))
+            .set(maps::player_id.eq(into))
+            .execute(connection)?;
+
+        diesel::update(world_records::table.filter(world_records::player_id.eq(from)))
+            .set(world_records::player_id.eq(into))
+            .execute(connection)?;
+
+        let into_renamed_at: Option<NaiveDateTime> = nickname_history::table
+            .filter(nickname_history::player_id.eq(into))
+            .order_by(nickname_history::id.desc())
+            .select(nickname_history::changed_at)
+            .first(connection)
+            .optional()?
+            .flatten();
+
+        let moved_nicknames =
+            diesel::update(nickname_history::table.filter(nickname_history::player_id.eq(from)))
+                .set(nickname_history::player_id.eq(into))
+                .execute(connection)?;
+
+        // The history is ordered by id, the current nickname must stay the
+        // latest one
+        if moved_nicknames > 0 {
+            diesel::insert_into(nickname_history::table)
+                .values((
+                    nickname_history::player_id.eq(into),
+                    nickname_history::nickname.eq(&into_player.nickname),
+                    nickname_history::changed_at.eq(into_renamed_at),
+                ))
+                .execute(connection)?;
+        }
+
+        // A finish replayed under the merged login must not be answered with
+        // the outcome of a record which moved
+        diesel::delete(finish_keys::table.filter(finish_keys::player_id.eq(from)))
+            .execute(connection)?;
+
+        diesel::delete(players::table.find(from)).execute(connection)?;
+        search::remove_name(connection, search::Kind::Player, from)?;
+
+        for map_id in &affected_maps {
+            update_ranks(connection, map_id)?;
+        }
+
+        Ok(affected_maps)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use quickcheck::{quickcheck, TestResult};
+
+    const LAYOUT: OverviewLayout = OverviewLayout {
+        rows: 15,
+        top: 3,
+        around: 6,
+    };
+
+    #[test]
+    fn test_overview_window_short_board() {
+        assert_eq!(overview_window(0, None, LAYOUT), Vec::<usize>::new());
+        assert_eq!(overview_window(2, Some(1), LAYOUT), vec![0, 1]);
+        assert_eq!(
+            overview_window(20, Some(14), LAYOUT),
+            (0..15).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_overview_window_without_record() {
+        // The last row is left for the player
+        assert_eq!(
+            overview_window(30, None, LAYOUT),
+            (0..14).collect::<Vec<_>>()
+        );
+        assert_eq!(overview_window(5, None, LAYOUT), (0..5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_overview_window_around_player() {
+        let mut expected = vec![0, 1, 2];
+        expected.extend(44..56);
+        assert_eq!(overview_window(100, Some(50), LAYOUT), expected);
+
+        // The last player sees the end of the board
+        let mut expected = vec![0, 1, 2];
+        expected.extend(88..100);
+        assert_eq!(overview_window(100, Some(99), LAYOUT), expected);
+
+        // Just below the best rows
+        let mut expected = vec![0, 1, 2];
+        expected.extend(9..21);
+        assert_eq!(overview_window(100, Some(15), LAYOUT), expected);
+    }
+
+    #[test]
+    fn test_overview_window_small_layout() {
+        let side_panel = OverviewLayout {
+            rows: 8,
+            top: 1,
+            around: 2,
+        };
+        assert_eq!(
+            overview_window(100, Some(50), side_panel),
+            vec![0, 48, 49, 50, 51, 52]
+        );
+        assert_eq!(
+            overview_window(100, Some(5), side_panel),
+            (0..8).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            overview_window(100, Some(99), side_panel),
+            vec![0, 95, 96, 97, 98, 99]
+        );
+    }
+
+    fn layout(rows: u8, top: u8, around: u8) -> OverviewLayout {
+        OverviewLayout {
+            rows: usize::from(rows % 32),
+            top: usize::from(top % 32),
+            around: usize::from(around % 32),
+        }
+    }
+
+    quickcheck! {
+        fn prop_overview_window_is_sorted_and_in_bounds(
+            len: u16,
+            player_idx: Option<u16>,
+            rows: u8,
+            top: u8,
+            around: u8
+        ) -> bool {
+            let len = usize::from(len % 500);
+            let player_idx = player_idx.map(usize::from);
+            let layout = layout(rows, top, around);
+            let window = overview_window(len, player_idx, layout);
+
+            window.windows(2).all(|pair| pair[0] < pair[1])
+                && window.iter().all(|&idx| idx < len)
+                && window.len() <= layout.rows
+        }
+
+        fn prop_overview_window_fills_the_rows(
+            len: u16,
+            player_idx: u16,
+            rows: u8,
+            top: u8
+        ) -> TestResult {
+            let len = usize::from(len % 500);
+            let player_idx = usize::from(player_idx);
+            if player_idx >= len {
+                return TestResult::discard();
+            }
+            // Enough records around the player to fill the rows
+            let layout = layout(rows, top, 31);
+
+            let window = overview_window(len, Some(player_idx), layout);
+            let without_record = overview_window(len, None, layout);
+
+            TestResult::from_bool(
+                window.len() == len.min(layout.rows)
+                    && without_record.len() == len.min(layout.rows.saturating_sub(1)),
+            )
+        }
+
+        fn prop_overview_window_shows_the_player_and_the_best(
+            len: u16,
+            player_idx: u16,
+            rows: u8,
+            top: u8,
+            around: u8
+        ) -> TestResult {
+            let len = usize::from(len % 500);
+            let player_idx = usize::from(player_idx);
+            let layout = layout(rows, top, around);
+            if player_idx >= len || layout.rows == 0 {
+                return TestResult::discard();
+            }
+
+            let window = overview_window(len, Some(player_idx), layout);
+            let best = layout.top.min(layout.rows - 1).min(len);
+
+            TestResult::from_bool(
+                window.contains(&player_idx) && (0..best).all(|idx| window.contains(&idx)),
+            )
+        }
+
+        fn prop_overview_finds_the_player_among_ties(times: Vec<u8>, player: usize) -> TestResult {
+            if times.is_empty() {
+                return TestResult::discard();
+            }
+
+            // Few distinct times so most of them tie
+            let mut times: Vec<(usize, i32)> = times
+                .into_iter()
+                .map(|time| i32::from(time % 4))
+                .enumerate()
+                .collect();
+            times.sort_by_key(|&(_, time)| time);
+            let records: Vec<RankedRecord> = times
+                .iter()
+                .map(|&(login, time)| RankedRecord {
+                    rank: times.iter().filter(|&&(_, other)| other < time).count() as u32 + 1,
+                    player_id: format!("player{}", login),
+                    nickname: format!("player{}", login),
+                    time,
+                })
+                .collect();
+            let player_id = format!("player{}", player % records.len());
+
+            let overview = overview_of(&records, &player_id, LAYOUT);
+            let flagged: Vec<&OverviewRecord> = overview
+                .records
+                .iter()
+                .filter(|record| record.is_player)
+                .collect();
+
+            TestResult::from_bool(
+                overview.total == records.len()
+                    && flagged.len() == 1
+                    && flagged[0].player_id == player_id,
+            )
+        }
+    }
+
+    #[test]
+    fn test_improved_at_never_goes_backwards() {
+        let date = |day| NaiveDate::from_ymd(2026, 10, day).and_hms(12, 0, 0);
+        let record = Record {
+            time: 60000,
+            respawn_count: 0,
+            try_count: 2,
+            created_at: date(10),
+            updated_at: date(12),
+            player_id: String::from("smokegun"),
+            map_id: String::from("NullId"),
+            rank: 1,
+            medal: None,
+            first_time: Some(61000),
+        };
+
+        assert_eq!(improved_at(&record, date(15)), date(15));
+        assert_eq!(improved_at(&record, date(11)), date(12));
+        assert_eq!(improved_at(&record, date(8)), date(12));
+    }
+
+    #[test]
+    fn test_page_ranks() {
+        assert_eq!(page_ranks(&[], 0, 1), Vec::<u32>::new());
+        assert_eq!(page_ranks(&[10, 20, 20, 30], 0, 1), vec![1, 2, 2, 4]);
+        // The first time of the page is tied with the previous page
+        assert_eq!(page_ranks(&[20, 20, 30], 5, 4), vec![4, 4, 8]);
+    }
+}
@@ -0,0 +1,658 @@
+//! Versioned JSON API.
+//!
+//! Read-only routes mirroring what the game routes and GraphQL expose, under
+//! `/v1`. Lists are paginated with the `offset` and `limit` query parameters
+//! and answered as `{"data": [...], "pagination": {"offset", "limit"}}`.
+//! Errors use the JSON body of `error::ApiError`. The routes are described
+//! by the OpenAPI document served on `/v1/openapi.json`.
+//!
+//! Names are stored HTML-escaped for the game routes, they are answered as
+//! the game sent them: unescaped, with their formatting codes.
+
+use crate::app_state::AppState;
+use crate::error::ApiError;
+use crate::escape;
+use crate::export;
+use crate::metrics;
+use crate::models::map::Map;
+use crate::models::medal::MedalCount;
+use crate::models::nickname::NicknameChange;
+use crate::models::player::{self, Player};
+use crate::models::record::{OverviewRecord, Record};
+use crate::models::stats::{ImprovedMap, PlayerStats};
+use crate::records_api::{self, Overview};
+use crate::schema;
+use crate::search;
+use actix_web::{error, web, Error, HttpResponse};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use futures::Future;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub const OPENAPI: &str = include_str!("openapi.json");
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct PaginationQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Pagination {
+    pub offset: i64,
+    pub limit: i64,
+}
+
+impl PaginationQuery {
+    pub fn validate(&self) -> Result<Pagination, ApiError> {
+        let offset = self.offset.unwrap_or(0);
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+
+        if offset < 0 {
+            return Err(ApiError::validation("offset must not be negative"));
+        }
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(ApiError::validation(format!(
+                "limit must be between 1 and {}",
+                MAX_LIMIT
+            )));
+        }
+
+        Ok(Pagination { offset, limit })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl SearchQuery {
+    pub fn pagination(&self) -> Result<Pagination, ApiError> {
+        let pagination = PaginationQuery {
+            offset: self.offset,
+            limit: self.limit,
+        }
+        .validate()?;
+
+        if pagination.offset >= search::MAX_CANDIDATES as i64 {
+            return Err(ApiError::validation(format!(
+                "offset must be below {}, the number of results of a search",
+                search::MAX_CANDIDATES
+            )));
+        }
+        Ok(pagination)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+    /// Only keeps the players of this zone and its subzones
+    pub zone: Option<String>,
+}
+
+impl LeaderboardQuery {
+    pub fn pagination(&self) -> Result<Pagination, ApiError> {
+        PaginationQuery {
+            offset: self.offset,
+            limit: self.limit,
+        }
+        .validate()
+    }
+
+    pub fn zone(&self) -> Result<Option<&str>, ApiError> {
+        match self.zone {
+            Some(ref zone) if !player::is_valid_zone_path(zone) => {
+                Err(ApiError::validation(format!("Invalid zone: {:?}", zone)))
+            }
+            ref zone => Ok(zone.as_deref()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub pagination: Pagination,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerJson {
+    pub login: String,
+    pub nickname: String,
+    pub zone_path: Option<String>,
+    pub language: Option<String>,
+    pub avatar: Option<String>,
+}
+
+impl From<Player> for PlayerJson {
+    fn from(player: Player) -> Self {
+        PlayerJson {
+            login: player.login,
+            nickname: escape::unescape(&player.nickname),
+            zone_path: player.zone_path,
+            language: player.language,
+            avatar: player.avatar,
+        }
+    }
+}
+
+/// Number of most improved maps shown on a profile.
+const PROFILE_IMPROVED_MAPS: usize = 5;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerProfileJson {
+    #[serde(flatten)]
+    pub player: PlayerJson,
+    pub stats: PlayerStats,
+    pub most_improved_maps: Vec<ImprovedMap>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapJson {
+    pub id: String,
+    pub name: String,
+    pub author_login: String,
+}
+
+impl From<Map> for MapJson {
+    fn from(map: Map) -> Self {
+        MapJson {
+            id: map.maniaplanet_map_id,
+            name: escape::unescape(&map.name),
+            author_login: map.player_id,
+        }
+    }
+}
+
+/// The overview of the game routes answered in JSON, with the names
+/// unescaped like on `/v1`.
+#[derive(Serialize)]
+pub struct OverviewJson {
+    pub records: Vec<OverviewRecord>,
+    pub total: usize,
+}
+
+impl From<Overview> for OverviewJson {
+    fn from(overview: Overview) -> Self {
+        OverviewJson {
+            records: overview
+                .records
+                .into_iter()
+                .map(|record| OverviewRecord {
+                    nickname: escape::unescape(&record.nickname),
+                    ..record
+                })
+                .collect(),
+            total: overview.total,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapDetailsJson {
+    #[serde(flatten)]
+    pub map: MapJson,
+    pub author: PlayerJson,
+    pub author_time: Option<i32>,
+    pub gold_time: Option<i32>,
+    pub silver_time: Option<i32>,
+    pub bronze_time: Option<i32>,
+    pub environment: Option<String>,
+    pub title_pack: Option<String>,
+    pub checkpoint_count: Option<i32>,
+    pub map_type: Option<String>,
+    pub file_hash: Option<String>,
+    pub uploaded_at: Option<NaiveDateTime>,
+}
+
+impl MapDetailsJson {
+    fn new(map: Map, author: Player) -> Self {
+        MapDetailsJson {
+            author: author.into(),
+            author_time: map.author_time,
+            gold_time: map.gold_time,
+            silver_time: map.silver_time,
+            bronze_time: map.bronze_time,
+            environment: map.environment.clone(),
+            title_pack: map.title_pack.clone(),
+            checkpoint_count: map.checkpoint_count,
+            map_type: map.map_type.clone(),
+            file_hash: map.file_hash.clone(),
+            uploaded_at: map.uploaded_at,
+            map: map.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordJson {
+    pub rank: u32,
+    pub time: i32,
+    pub respawn_count: i32,
+    pub try_count: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub medal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player: Option<PlayerJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub map: Option<MapJson>,
+}
+
+impl RecordJson {
+    fn new(record: Record, player: Option<Player>, map: Option<Map>) -> Self {
+        RecordJson {
+            rank: record.rank,
+            time: record.time,
+            respawn_count: record.respawn_count,
+            try_count: record.try_count,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+            medal: record.medal,
+            player: player.map(PlayerJson::from),
+            map: map.map(MapJson::from),
+        }
+    }
+}
+
+/// Query extractor configuration answering malformed queries with a JSON error.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _req| {
+        let err = ApiError::validation(format!("Invalid query: {}", err));
+        error::InternalError::from_response(err.to_string(), err.json_response()).into()
+    })
+}
+
+fn json_response<T: serde::Serialize>(
+    res: Result<T, error::BlockingError<ApiError>>,
+) -> Result<HttpResponse, Error> {
+    match res {
+        Ok(body) => Ok(HttpResponse::Ok().json(body)),
+        Err(e) => Ok(ApiError::from(e).json_response()),
+    }
+}
+
+pub fn map_route(
+    map_id: web::Path<String>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<MapDetailsJson, ApiError> {
+        let conn = state.pool.get()?;
+        let map: Map = schema::maps::table
+            .find(&*map_id)
+            .get_result(&conn)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(format!("Unknown map {}", map_id)))?;
+        let author: Player = schema::players::table
+            .find(&map.player_id)
+            .get_result(&conn)?;
+
+        Ok(MapDetailsJson::new(map, author))
+    })
+    .then(json_response)
+}
+
+pub fn map_leaderboard_route(
+    map_id: web::Path<String>,
+    query: web::Query<LeaderboardQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<RecordJson>, ApiError> {
+        let pagination = query.pagination()?;
+        let zone = query.zone()?;
+        let conn = state.pool.get()?;
+        let (_, _, records) =
+            records_api::map_records(&conn, pagination.offset, pagination.limit, &map_id, zone)?
+                .ok_or_else(|| ApiError::not_found(format!("Unknown map {}", map_id)))?;
+
+        Ok(Page {
+            data: records
+                .into_iter()
+                .map(|(record, player)| RecordJson::new(record, Some(player), None))
+                .collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn player_route(
+    login: web::Path<String>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<PlayerJson, ApiError> {
+        let conn = state.pool.get()?;
+        let player: Player = schema::players::table
+            .find(&*login)
+            .get_result(&conn)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(format!("Unknown player {}", login)))?;
+
+        Ok(player.into())
+    })
+    .then(json_response)
+}
+
+pub fn player_profile_route(
+    login: web::Path<String>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<PlayerProfileJson, ApiError> {
+        let conn = state.pool.get()?;
+        let player: Player = schema::players::table
+            .find(&*login)
+            .get_result(&conn)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(format!("Unknown player {}", login)))?;
+
+        Ok(PlayerProfileJson {
+            stats: PlayerStats::of(&conn, &player.login)?,
+            most_improved_maps: ImprovedMap::of_player(
+                &conn,
+                &player.login,
+                PROFILE_IMPROVED_MAPS,
+            )?,
+            player: player.into(),
+        })
+    })
+    .then(json_response)
+}
+
+pub fn player_records_route(
+    login: web::Path<String>,
+    query: web::Query<PaginationQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<RecordJson>, ApiError> {
+        let pagination = query.validate()?;
+        let conn = state.pool.get()?;
+        let (_, records) =
+            records_api::player_records(&conn, pagination.offset, pagination.limit, &login)?
+                .ok_or_else(|| ApiError::not_found(format!("Unknown player {}", login)))?;
+
+        Ok(Page {
+            data: records
+                .into_iter()
+                .map(|(record, map)| RecordJson::new(record, None, Some(map)))
+                .collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn player_nicknames_route(
+    login: web::Path<String>,
+    query: web::Query<PaginationQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<NicknameChange>, ApiError> {
+        let pagination = query.validate()?;
+        let conn = state.pool.get()?;
+        schema::players::table
+            .find(&*login)
+            .select(schema::players::login)
+            .get_result::<String>(&conn)
+            .optional()?
+            .ok_or_else(|| ApiError::not_found(format!("Unknown player {}", login)))?;
+
+        let history = NicknameChange::history(&conn, &login, pagination.offset, pagination.limit)?;
+
+        Ok(Page {
+            data: history
+                .into_iter()
+                .map(|change| NicknameChange {
+                    nickname: escape::unescape(&change.nickname),
+                    ..change
+                })
+                .collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn latest_records_route(
+    query: web::Query<PaginationQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<RecordJson>, ApiError> {
+        let pagination = query.validate()?;
+        let conn = state.pool.get()?;
+        let records = records_api::latest_records(&conn, pagination.offset, pagination.limit)?;
+
+        Ok(Page {
+            data: records
+                .into_iter()
+                .map(|(record, player, map)| RecordJson::new(record, Some(player), Some(map)))
+                .collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn search_players_route(
+    query: web::Query<SearchQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<PlayerJson>, ApiError> {
+        let pagination = query.pagination()?;
+        let conn = state.pool.get()?;
+        let players = search::search_players(&conn, &query.q, pagination.offset, pagination.limit)?;
+
+        Ok(Page {
+            data: players.into_iter().map(PlayerJson::from).collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn search_maps_route(
+    query: web::Query<SearchQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<MapJson>, ApiError> {
+        let pagination = query.pagination()?;
+        let conn = state.pool.get()?;
+        let maps = search::search_maps(&conn, &query.q, pagination.offset, pagination.limit)?;
+
+        Ok(Page {
+            data: maps.into_iter().map(MapJson::from).collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn medal_leaderboard_route(
+    query: web::Query<LeaderboardQuery>,
+    state: web::Data<Arc<AppState>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    web::block(move || -> Result<Page<MedalCount>, ApiError> {
+        let pagination = query.pagination()?;
+        let zone = query.zone()?;
+        let conn = state.pool.get()?;
+        let counts =
+            records_api::medal_leaderboard(&conn, pagination.offset, pagination.limit, zone)?;
+
+        Ok(Page {
+            data: counts
+                .into_iter()
+                .map(|count| MedalCount {
+                    nickname: escape::unescape(&count.nickname),
+                    ..count
+                })
+                .collect(),
+            pagination,
+        })
+    })
+    .then(json_response)
+}
+
+pub fn openapi_route() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(OPENAPI)
+}
+
+/// Registers the `/v1` routes.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    let resource = |path| metrics::scoped_resource("/v1", path).data(query_config());
+
+    cfg.service(
+        web::scope("/v1")
+            .service(resource("/openapi.json").route(web::get().to(openapi_route)))
+            .service(resource("/maps/{id}").route(web::get().to_async(map_route)))
+            .service(
+                resource("/maps/{id}/leaderboard")
+                    .route(web::get().to_async(map_leaderboard_route)),
+            )
+            .service(resource("/players/{login}").route(web::get().to_async(player_route)))
+            .service(
+                resource("/players/{login}/records")
+                    .route(web::get().to_async(player_records_route)),
+            )
+            .service(
+                resource("/players/{login}/profile")
+                    .route(web::get().to_async(player_profile_route)),
+            )
+            .service(
+                resource("/players/{login}/nicknames")
+                    .route(web::get().to_async(player_nicknames_route)),
+            )
+            .service(resource("/records/latest").route(web::get().to_async(latest_records_route)))
+            .service(resource("/search/players").route(web::get().to_async(search_players_route)))
+            .service(resource("/search/maps").route(web::get().to_async(search_maps_route)))
+            .service(
+                resource("/medals/leaderboard").route(web::get().to_async(medal_leaderboard_route)),
+            )
+            .service(resource("/export/records").route(web::get().to(export::records_export_route)))
+            .service(resource("/export/players").route(web::get().to(export::players_export_route)))
+            .service(resource("/export/maps").route(web::get().to(export::maps_export_route))),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pagination_defaults() {
+        let query = PaginationQuery {
+            offset: None,
+            limit: None,
+        };
+        assert_eq!(
+            query.validate().unwrap(),
+            Pagination {
+                offset: 0,
+                limit: DEFAULT_LIMIT
+            }
+        );
+    }
+
+    #[test]
+    fn test_pagination_bounds() {
+        let negative_offset = PaginationQuery {
+            offset: Some(-1),
+            limit: None,
+        };
+        assert!(negative_offset.validate().is_err());
+
+        let too_large = PaginationQuery {
+            offset: None,
+            limit: Some(MAX_LIMIT + 1),
+        };
+        assert!(too_large.validate().is_err());
+
+        let empty = PaginationQuery {
+            offset: None,
+            limit: Some(0),
+        };
+        assert!(empty.validate().is_err());
+    }
+
+    #[test]
+    fn test_search_offset_bound() {
+        let search = |offset| SearchQuery {
+            q: String::from("smoke"),
+            offset: Some(offset),
+            limit: None,
+        };
+        assert!(search(search::MAX_CANDIDATES as i64 - 1)
+            .pagination()
+            .is_ok());
+        assert!(search(search::MAX_CANDIDATES as i64).pagination().is_err());
+    }
+
+    #[test]
+    fn test_names_are_unescaped() {
+        let player = Player {
+            login: String::from("smokegun"),
+            nickname: String::from("$f00Smoke&lt;Gun&gt; &amp; co"),
+            ..Player::default()
+        };
+        assert_eq!(PlayerJson::from(player).nickname, "$f00Smoke<Gun> & co");
+
+        let map = Map {
+            name: String::from("&quot;Null&quot; &#39;map&#39;"),
+            ..Map::default()
+        };
+        assert_eq!(MapJson::from(map).name, "\"Null\" 'map'");
+
+        let overview = Overview {
+            records: vec![OverviewRecord {
+                rank: 1,
+                player_id: String::from("smokegun"),
+                nickname: String::from("&lt;b&gt;Smoke&lt;/b&gt;"),
+                time: 61000,
+                is_player: true,
+            }],
+            total: 1,
+        };
+        let json = serde_json::to_value(OverviewJson::from(overview)).unwrap();
+        assert_eq!(json["records"][0]["nickname"], "<b>Smoke</b>");
+        assert_eq!(json["records"][0]["isPlayer"], true);
+        assert_eq!(json["total"], 1);
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        let doc: serde_json::Value = serde_json::from_str(OPENAPI).unwrap();
+        let paths = doc["paths"].as_object().unwrap();
+
+        for path in &[
+            "/v1/maps/{id}",
+            "/v1/maps/{id}/leaderboard",
+            "/v1/players/{login}",
+            "/v1/players/{login}/records",
+            "/v1/players/{login}/nicknames",
+            "/v1/players/{login}/profile",
+            "/v1/records/latest",
+            "/v1/search/players",
+            "/v1/search/maps",
+            "/v1/medals/leaderboard",
+            "/v1/export/records",
+            "/v1/export/players",
+            "/v1/export/maps",
+        ] {
+            assert!(paths.contains_key(*path), "{} is not documented", path);
+        }
+    }
+}
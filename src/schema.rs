@@ -1,8 +1,64 @@
+table! {
+    finish_keys (map_id, player_id, idempotency_key) {
+        map_id -> Varchar,
+        player_id -> Varchar,
+        idempotency_key -> Varchar,
+        is_new_best -> Bool,
+        old_time -> Integer,
+        new_time -> Integer,
+        medal -> Nullable<Varchar>,
+        created_at -> Datetime,
+    }
+}
+
 table! {
     maps (maniaplanet_map_id) {
         maniaplanet_map_id -> Varchar,
         name -> Varchar,
         player_id -> Varchar,
+        name_plain -> Varchar,
+        author_time -> Nullable<Integer>,
+        gold_time -> Nullable<Integer>,
+        silver_time -> Nullable<Integer>,
+        bronze_time -> Nullable<Integer>,
+        environment -> Nullable<Varchar>,
+        title_pack -> Nullable<Varchar>,
+        checkpoint_count -> Nullable<Integer>,
+        map_type -> Nullable<Varchar>,
+        file_hash -> Nullable<Varchar>,
+        uploaded_at -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    name_trigrams (kind, id, trigram) {
+        kind -> Varchar,
+        id -> Varchar,
+        trigram -> Varchar,
+    }
+}
+
+table! {
+    nickname_history (id) {
+        id -> Integer,
+        player_id -> Varchar,
+        nickname -> Varchar,
+        changed_at -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    player_stats (player_id) {
+        player_id -> Varchar,
+        record_count -> Integer,
+        world_record_count -> Integer,
+        top_ten_count -> Integer,
+        average_rank -> Nullable<Double>,
+        median_rank -> Nullable<Double>,
+        total_tries -> BigInt,
+        first_activity -> Nullable<Datetime>,
+        last_activity -> Nullable<Datetime>,
+        computed_at -> Datetime,
     }
 }
 
@@ -10,6 +66,11 @@ table! {
     players (login) {
         login -> Varchar,
         nickname -> Varchar,
+        banned -> Bool,
+        nickname_plain -> Varchar,
+        zone_path -> Nullable<Varchar>,
+        language -> Nullable<Varchar>,
+        avatar -> Nullable<Varchar>,
     }
 }
 
@@ -23,11 +84,66 @@ table! {
         updated_at -> Datetime,
         player_id -> Varchar,
         map_id -> Varchar,
+        medal -> Nullable<Varchar>,
+        first_time -> Nullable<Integer>,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Integer,
+        webhook_id -> Integer,
+        event -> Varchar,
+        payload -> Text,
+        attempts -> Integer,
+        next_attempt_at -> Nullable<Datetime>,
+        delivered_at -> Nullable<Datetime>,
+        last_error -> Nullable<Varchar>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Integer,
+        url -> Varchar,
+        events -> Varchar,
+        template -> Nullable<Text>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    world_records (id) {
+        id -> Integer,
+        map_id -> Varchar,
+        player_id -> Varchar,
+        time -> Integer,
+        respawn_count -> Integer,
+        try_count -> Integer,
+        medal -> Nullable<Varchar>,
+        set_at -> Datetime,
     }
 }
 
 joinable!(maps -> players (player_id));
+joinable!(nickname_history -> players (player_id));
+joinable!(player_stats -> players (player_id));
 joinable!(records -> maps (map_id));
 joinable!(records -> players (player_id));
+joinable!(webhook_deliveries -> webhooks (webhook_id));
+joinable!(world_records -> maps (map_id));
+joinable!(world_records -> players (player_id));
 
-allow_tables_to_appear_in_same_query!(maps, players, records,);
+allow_tables_to_appear_in_same_query!(
+    finish_keys,
+    maps,
+    name_trigrams,
+    nickname_history,
+    player_stats,
+    players,
+    records,
+    webhook_deliveries,
+    webhooks,
+    world_records,
+);
@@ -0,0 +1,322 @@
+//! Search of players and maps by name.
+//!
+//! Names are compared without their formatting codes and case. A query
+//! matches a name exactly, as a prefix, as a substring, or fuzzily when they
+//! share enough trigrams. The trigrams of every name are kept in the
+//! `name_trigrams` table, updated whenever a name is saved.
+
+use crate::models::map::Map;
+use crate::models::player::Player;
+use crate::mp_text;
+use crate::schema::{maps, name_trigrams, players};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+/// Most names scored for one query, so a search has at most this many
+/// results and the pages must start before.
+pub const MAX_CANDIDATES: usize = 200;
+
+/// Least trigram similarity of a fuzzy match.
+const MIN_SIMILARITY: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Player,
+    Map,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Player => "player",
+            Kind::Map => "map",
+        }
+    }
+}
+
+/// The form of a plain name used for matching: lowercase, with single
+/// spaces.
+pub fn normalize(plain_name: &str) -> String {
+    plain_name
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The trigrams of a normalized name, padded so short names and the start of
+/// words have some.
+fn trigrams(normalized: &str) -> BTreeSet<String> {
+    let mut trigrams = BTreeSet::new();
+
+    for word in normalized.split(' ').filter(|w| !w.is_empty()) {
+        let padded: Vec<char> = format!("  {} ", word).chars().collect();
+        for window in padded.windows(3) {
+            trigrams.insert(window.iter().collect());
+        }
+    }
+
+    trigrams
+}
+
+/// Scores how well a name matches the query, both normalized. Exact matches
+/// come first, then prefixes, substrings and fuzzy matches.
+pub fn score(query: &str, name: &str) -> Option<f32> {
+    if query.is_empty() {
+        return None;
+    }
+
+    let coverage = query.chars().count() as f32 / name.chars().count().max(1) as f32;
+
+    if name == query {
+        Some(4.0)
+    } else if name.starts_with(query) {
+        Some(3.0 + coverage)
+    } else if name.contains(query) {
+        Some(2.0 + coverage)
+    } else {
+        let query_trigrams = trigrams(query);
+        let name_trigrams = trigrams(name);
+        let shared = query_trigrams.intersection(&name_trigrams).count();
+        let total = query_trigrams.union(&name_trigrams).count();
+        if total == 0 {
+            return None;
+        }
+
+        let similarity = shared as f32 / total as f32;
+        if similarity >= MIN_SIMILARITY {
+            Some(similarity)
+        } else {
+            None
+        }
+    }
+}
+
+/// Replaces the trigrams of a name.
+pub fn index_name(
+    conn: &MysqlConnection,
+    kind: Kind,
+    id: &str,
+    plain_name: &str,
+) -> QueryResult<()> {
+    remove_name(conn, kind, id)?;
+
+    let rows: Vec<_> = trigrams(&normalize(plain_name))
+        .into_iter()
+        .map(|trigram| {
+            (
+                name_trigrams::kind.eq(kind.as_str()),
+                name_trigrams::id.eq(id),
+                name_trigrams::trigram.eq(trigram),
+            )
+        })
+        .collect();
+
+    if !rows.is_empty() {
+        diesel::insert_into(name_trigrams::table)
+            .values(&rows)
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn remove_name(conn: &MysqlConnection, kind: Kind, id: &str) -> QueryResult<usize> {
+    diesel::delete(
+        name_trigrams::table
+            .filter(name_trigrams::kind.eq(kind.as_str()))
+            .filter(name_trigrams::id.eq(id)),
+    )
+    .execute(conn)
+}
+
+/// Rebuilds the trigrams of every player and map, returns the number of
+/// names indexed.
+pub fn reindex(conn: &MysqlConnection) -> QueryResult<usize> {
+    conn.transaction(|| {
+        diesel::delete(name_trigrams::table).execute(conn)?;
+
+        let players: Vec<(String, String)> = players::table
+            .select((players::login, players::nickname_plain))
+            .load(conn)?;
+        let maps: Vec<(String, String)> = maps::table
+            .select((maps::maniaplanet_map_id, maps::name_plain))
+            .load(conn)?;
+
+        for (login, plain) in &players {
+            index_name(conn, Kind::Player, login, plain)?;
+        }
+        for (map_id, plain) in &maps {
+            index_name(conn, Kind::Map, map_id, plain)?;
+        }
+
+        Ok(players.len() + maps.len())
+    })
+}
+
+pub fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// The ids worth scoring: the names starting with the query, then the names
+/// sharing the most trigrams with it.
+fn candidates(
+    conn: &MysqlConnection,
+    kind: Kind,
+    prefix_matches: Vec<String>,
+    query: &str,
+) -> QueryResult<Vec<String>> {
+    let mut ids = prefix_matches;
+
+    let query_trigrams: Vec<String> = trigrams(query).into_iter().collect();
+    if !query_trigrams.is_empty() {
+        // The names sharing the most trigrams first, counted by the database
+        // since a common trigram can match most of the names
+        let matches: Vec<String> = name_trigrams::table
+            .filter(name_trigrams::kind.eq(kind.as_str()))
+            .filter(name_trigrams::trigram.eq_any(&query_trigrams))
+            .group_by(name_trigrams::id)
+            .select(name_trigrams::id)
+            .order_by((sql::<BigInt>("COUNT(*)").desc(), name_trigrams::id))
+            .limit(MAX_CANDIDATES as i64)
+            .load(conn)?;
+
+        ids.extend(matches);
+    }
+
+    let mut seen = BTreeSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+    ids.truncate(MAX_CANDIDATES);
+    Ok(ids)
+}
+
+/// Sorts the scored items, best first, and keeps the requested page.
+fn rank<T>(mut scored: Vec<(f32, String, T)>, offset: i64, limit: i64) -> Vec<T> {
+    scored.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.1.cmp(&b.1))
+    });
+
+    scored
+        .into_iter()
+        .skip(offset.max(0) as usize)
+        .take(limit.max(0) as usize)
+        .map(|(_, _, item)| item)
+        .collect()
+}
+
+pub fn search_players(
+    conn: &MysqlConnection,
+    query: &str,
+    offset: i64,
+    limit: i64,
+) -> QueryResult<Vec<Player>> {
+    let query = normalize(&mp_text::plain_text(query));
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let prefix_matches = players::table
+        .filter(players::nickname_plain.like(format!("{}%", escape_like(&query))))
+        .select(players::login)
+        .limit(MAX_CANDIDATES as i64)
+        .load(conn)?;
+    let ids = candidates(conn, Kind::Player, prefix_matches, &query)?;
+
+    let players: Vec<Player> = players::table
+        .filter(players::login.eq_any(&ids))
+        .load(conn)?;
+    let scored = players
+        .into_iter()
+        .filter_map(|player| {
+            let name = normalize(&player.nickname_plain);
+            score(&query, &name).map(|score| (score, name, player))
+        })
+        .collect();
+
+    Ok(rank(scored, offset, limit))
+}
+
+pub fn search_maps(
+    conn: &MysqlConnection,
+    query: &str,
+    offset: i64,
+    limit: i64,
+) -> QueryResult<Vec<Map>> {
+    let query = normalize(&mp_text::plain_text(query));
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let prefix_matches = maps::table
+        .filter(maps::name_plain.like(format!("{}%", escape_like(&query))))
+        .select(maps::maniaplanet_map_id)
+        .limit(MAX_CANDIDATES as i64)
+        .load(conn)?;
+    let ids = candidates(conn, Kind::Map, prefix_matches, &query)?;
+
+    let maps: Vec<Map> = maps::table
+        .filter(maps::maniaplanet_map_id.eq_any(&ids))
+        .load(conn)?;
+    let scored = maps
+        .into_iter()
+        .filter_map(|map| {
+            let name = normalize(&map.name_plain);
+            score(&query, &name).map(|score| (score, name, map))
+        })
+        .collect();
+
+    Ok(rank(scored, offset, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(" Smoke  GUN "), "smoke gun");
+        assert_eq!(normalize("5$ Map"), "5$ map");
+    }
+
+    #[test]
+    fn test_trigrams() {
+        let expected: BTreeSet<String> = ["  a", " ab", "ab "]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(trigrams("ab"), expected);
+        assert!(trigrams("").is_empty());
+    }
+
+    #[test]
+    fn test_score_order() {
+        let exact = score("smoke", "smoke").unwrap();
+        let prefix = score("smoke", "smokegun").unwrap();
+        let substring = score("smoke", "the smoke").unwrap();
+        let fuzzy = score("smoek", "smoke").unwrap();
+
+        assert!(exact > prefix);
+        assert!(prefix > substring);
+        assert!(substring > fuzzy);
+        assert_eq!(score("smoke", "gotatang"), None);
+        assert_eq!(score("", "smoke"), None);
+    }
+
+    #[test]
+    fn test_rank() {
+        let scored = vec![
+            (1.0, String::from("b"), "b"),
+            (3.0, String::from("c"), "c"),
+            (1.0, String::from("a"), "a"),
+        ];
+        assert_eq!(rank(scored.clone(), 0, 10), vec!["c", "a", "b"]);
+        assert_eq!(rank(scored, 1, 1), vec!["a"]);
+    }
+}
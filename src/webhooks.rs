@@ -0,0 +1,573 @@
+//! Outgoing webhooks.
+//!
+//! An event is queued in `webhook_deliveries` for every webhook wanting it,
+//! inside the transaction saving what it is about, so a rolled back change is
+//! never announced. A worker thread of the server posts the queued bodies and
+//! retries the failed calls with an exponential backoff, the queue surviving
+//! restarts. `https://` URLs are called over TLS with OpenSSL.
+
+use crate::app_state::Pool;
+use crate::metrics;
+use crate::models::record::time_text;
+use crate::models::webhook::{Webhook, WebhookDelivery};
+use actix_rt::SystemRunner;
+use actix_web::client::{Client, Connector};
+use actix_web::http::header;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use futures::future::{self, Future};
+use openssl::ssl::{SslConnector, SslMethod};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::thread;
+
+/// How often the worker looks for calls to make.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How long a webhook may take to answer.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// How long the calls taken by a worker are hidden from the other workers.
+const LEASE_SECONDS: i64 = 300;
+/// Number of calls taken at once by a worker.
+const BATCH_SIZE: i64 = 20;
+/// Number of attempts before a call is given up.
+pub const MAX_ATTEMPTS: i32 = 10;
+
+/// The kinds of events a webhook can ask for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    WorldRecord,
+    TopTen,
+    NewMap,
+    Ban,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [
+        EventKind::WorldRecord,
+        EventKind::TopTen,
+        EventKind::NewMap,
+        EventKind::Ban,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::WorldRecord => "world_record",
+            EventKind::TopTen => "top_ten",
+            EventKind::NewMap => "new_map",
+            EventKind::Ban => "ban",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<EventKind> {
+        EventKind::ALL.iter().cloned().find(|k| k.as_str() == kind)
+    }
+}
+
+/// Something worth telling the webhooks. The names and nicknames are the
+/// plain ones, without formatting codes.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A player took the first place of a map, alone
+    #[serde(rename_all = "camelCase")]
+    WorldRecord {
+        map_id: String,
+        map_name: String,
+        player_id: String,
+        nickname: String,
+        time: i32,
+        /// The time as `m:ss.mmm`
+        time_text: String,
+    },
+    /// A player entered the top 10 of a map, without taking the first place
+    #[serde(rename_all = "camelCase")]
+    TopTen {
+        map_id: String,
+        map_name: String,
+        player_id: String,
+        nickname: String,
+        time: i32,
+        time_text: String,
+        rank: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    NewMap {
+        map_id: String,
+        map_name: String,
+        /// The author of the map
+        player_id: String,
+        nickname: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Ban { player_id: String, nickname: String },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::WorldRecord { .. } => EventKind::WorldRecord,
+            Event::TopTen { .. } => EventKind::TopTen,
+            Event::NewMap { .. } => EventKind::NewMap,
+            Event::Ban { .. } => EventKind::Ban,
+        }
+    }
+
+    /// An event of the kind with made up values, to try a webhook.
+    pub fn example(kind: EventKind) -> Event {
+        let map_id = String::from("ExampleMapId");
+        let map_name = String::from("Example map");
+        let player_id = String::from("example");
+        let nickname = String::from("Example player");
+        match kind {
+            EventKind::WorldRecord => Event::WorldRecord {
+                map_id,
+                map_name,
+                player_id,
+                nickname,
+                time: 42195,
+                time_text: time_text(42195),
+            },
+            EventKind::TopTen => Event::TopTen {
+                map_id,
+                map_name,
+                player_id,
+                nickname,
+                time: 42195,
+                time_text: time_text(42195),
+                rank: 7,
+            },
+            EventKind::NewMap => Event::NewMap {
+                map_id,
+                map_name,
+                player_id,
+                nickname,
+            },
+            EventKind::Ban => Event::Ban {
+                player_id,
+                nickname,
+            },
+        }
+    }
+
+    fn fields(&self) -> serde_json::Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(fields)) => fields,
+            _ => unreachable!("an event is serialized as an object"),
+        }
+    }
+}
+
+/// The body posted for an event: the event as JSON without a template, else
+/// the template with its `{{field}}` placeholders replaced by the fields of
+/// the event. The values are escaped to fit in a JSON string, the unknown
+/// fields are left empty.
+pub fn render(template: Option<&str>, event: &Event) -> String {
+    let fields = event.fields();
+    let template = match template {
+        Some(template) => template,
+        None => return Value::Object(fields).to_string(),
+    };
+
+    let mut body = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        body.push_str(&rest[..start]);
+        match fields.get(after[..end].trim()) {
+            Some(Value::String(value)) => {
+                let quoted = Value::String(value.clone()).to_string();
+                body.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(Value::Null) | None => {}
+            Some(value) => body.push_str(&value.to_string()),
+        }
+        rest = &after[end + 2..];
+    }
+    body.push_str(rest);
+    body
+}
+
+/// The placeholders of a template which are not fields of any of the events.
+pub fn unknown_placeholders(template: &str, kinds: &[EventKind]) -> Vec<String> {
+    let known: Vec<_> = kinds
+        .iter()
+        .map(|&kind| Event::example(kind).fields())
+        .collect();
+
+    let mut unknown = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        let name = after[..end].trim();
+        if !known.iter().any(|fields| fields.contains_key(name)) {
+            unknown.push(name.to_string());
+        }
+        rest = &after[end + 2..];
+    }
+    unknown
+}
+
+/// Queues the event for every webhook wanting it, returns the number of
+/// calls queued.
+pub fn enqueue(conn: &MysqlConnection, event: &Event) -> QueryResult<usize> {
+    let kind = event.kind().as_str();
+    let mut queued = 0;
+    for webhook in Webhook::subscribed(conn, kind)? {
+        let payload = render(webhook.template.as_deref(), event);
+        queued += WebhookDelivery::enqueue(conn, webhook.id, kind, &payload)?;
+    }
+    Ok(queued)
+}
+
+/// How long to wait after a failed attempt, doubling from 30 seconds up to
+/// 6 hours.
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = (attempts.max(1) - 1).min(20) as u32;
+    Duration::seconds((30 * 2i64.pow(exponent)).min(6 * 3600))
+}
+
+/// The TLS settings of the calls to `https://` URLs, trusting the
+/// certificates of the system.
+pub fn ssl_connector() -> Result<SslConnector, String> {
+    let mut ssl = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    ssl.set_alpn_protos(b"\x08http/1.1")
+        .map_err(|e| e.to_string())?;
+    Ok(ssl.build())
+}
+
+/// Posts a body to an URL, waiting for the answer. Fails unless the answer
+/// has a success status.
+pub fn post(
+    system: &mut SystemRunner,
+    ssl: &SslConnector,
+    url: &str,
+    body: String,
+) -> Result<(), String> {
+    let url = url.to_string();
+    let ssl = ssl.clone();
+    system.block_on(future::lazy(move || {
+        Client::build()
+            .connector(Connector::new().ssl(ssl).timeout(REQUEST_TIMEOUT).finish())
+            .finish()
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .timeout(REQUEST_TIMEOUT)
+            .send_body(body)
+            .map_err(|e| e.to_string())
+            .and_then(|response| {
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("HTTP status {}", response.status()))
+                }
+            })
+    }))
+}
+
+/// Makes the calls due now, returns the number of calls attempted.
+pub fn deliver_due(
+    conn: &MysqlConnection,
+    system: &mut SystemRunner,
+    ssl: &SslConnector,
+) -> QueryResult<usize> {
+    let due = WebhookDelivery::claim_due(conn, BATCH_SIZE, Duration::seconds(LEASE_SECONDS))?;
+
+    for delivery in &due {
+        match post(system, ssl, &delivery.url, delivery.payload.clone()) {
+            Ok(()) => {
+                delivery.delivered(conn)?;
+                metrics::WEBHOOK_DELIVERIES
+                    .with_label_values(&["delivered"])
+                    .inc();
+            }
+            Err(error) => {
+                let attempts = delivery.attempts + 1;
+                let next_attempt_at = if attempts < MAX_ATTEMPTS {
+                    Some(Utc::now().naive_utc() + retry_delay(attempts))
+                } else {
+                    None
+                };
+                let result = if next_attempt_at.is_some() {
+                    "retried"
+                } else {
+                    "given_up"
+                };
+                delivery.failed(conn, &error, next_attempt_at)?;
+                metrics::WEBHOOK_DELIVERIES
+                    .with_label_values(&[result])
+                    .inc();
+            }
+        }
+    }
+
+    Ok(due.len())
+}
+
+/// Starts the thread making the queued calls.
+pub fn spawn_worker(pool: Pool) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name(String::from("webhooks"))
+        .spawn(move || {
+            let mut system = actix_rt::System::new("webhooks");
+            let ssl = ssl_connector().expect("Failed to set up TLS for the webhooks");
+            loop {
+                let result = pool.get().map_err(|e| e.to_string()).and_then(|conn| {
+                    deliver_due(&conn, &mut system, &ssl).map_err(|e| e.to_string())
+                });
+                if let Err(e) = result {
+                    eprintln!("Error while calling the webhooks: {}", e);
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        })
+        .expect("Failed to start the webhooks thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::SslAcceptor;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// A local HTTP server answering every request with the status, which
+    /// sends the bodies it receives.
+    fn stand_in(status: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                answer(stream.unwrap(), status, &sender);
+            }
+        });
+
+        (url, receiver)
+    }
+
+    /// A self-signed certificate for 127.0.0.1 and its key.
+    fn certificate() -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "127.0.0.1").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    /// The same as `stand_in` over TLS, with the certificate to trust.
+    fn tls_stand_in(status: &'static str) -> (String, X509, mpsc::Receiver<String>) {
+        let (cert, key) = certificate();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = acceptor.accept(stream.unwrap()) {
+                    answer(stream, status, &sender);
+                }
+            }
+        });
+
+        (url, cert, receiver)
+    }
+
+    /// Reads a request, answers with the status and sends its body.
+    fn answer<S: Read + Write>(mut stream: S, status: &str, sender: &mpsc::Sender<String>) {
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        let body = loop {
+            let read = stream.read(&mut buffer).unwrap();
+            if read == 0 {
+                break None;
+            }
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(2, ':');
+                        match (parts.next(), parts.next()) {
+                            (Some(name), Some(value))
+                                if name.eq_ignore_ascii_case("content-length") =>
+                            {
+                                value.trim().parse::<usize>().ok()
+                            }
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .unwrap_or(0);
+                if text.len() >= end + 4 + length {
+                    break Some(text[end + 4..end + 4 + length].to_string());
+                }
+            }
+        };
+        let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+        stream.write_all(response.as_bytes()).unwrap();
+        stream.flush().unwrap();
+        if let Some(body) = body {
+            sender.send(body).unwrap();
+        }
+    }
+
+    fn world_record() -> Event {
+        Event::WorldRecord {
+            map_id: String::from("NullId"),
+            map_name: String::from("The \"Null\" map"),
+            player_id: String::from("smokegun"),
+            nickname: String::from("SmokeGun"),
+            time: 72345,
+            time_text: time_text(72345),
+        }
+    }
+
+    #[test]
+    fn test_event_kinds() {
+        for kind in EventKind::ALL.iter() {
+            assert_eq!(EventKind::parse(kind.as_str()), Some(*kind));
+            assert_eq!(Event::example(*kind).kind(), *kind);
+        }
+        assert_eq!(EventKind::parse("new_record"), None);
+    }
+
+    #[test]
+    fn test_render_without_template() {
+        let body: Value = serde_json::from_str(&render(None, &world_record())).unwrap();
+        assert_eq!(body["event"], "world_record");
+        assert_eq!(body["mapName"], "The \"Null\" map");
+        assert_eq!(body["timeText"], "1:12.345");
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template = r#"{"content": "WR on {{mapName}} by {{ nickname }} in {{timeText}} ({{time}} ms){{rank}}"}"#;
+        let body = render(Some(template), &world_record());
+        assert_eq!(
+            body,
+            r#"{"content": "WR on The \"Null\" map by SmokeGun in 1:12.345 (72345 ms)"}"#
+        );
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert!(body["content"].is_string());
+
+        assert_eq!(
+            render(Some("{{event}} {{unclosed"), &world_record()),
+            "world_record {{unclosed"
+        );
+    }
+
+    #[test]
+    fn test_unknown_placeholders() {
+        let kinds = [EventKind::WorldRecord, EventKind::Ban];
+        assert!(unknown_placeholders("{{nickname}} {{mapName}}", &kinds).is_empty());
+        assert_eq!(
+            unknown_placeholders("{{nickname}} {{rank}} {{score}}", &kinds),
+            vec!["rank", "score"]
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(5), Duration::seconds(480));
+        assert_eq!(retry_delay(MAX_ATTEMPTS), Duration::seconds(15360));
+        assert_eq!(retry_delay(MAX_ATTEMPTS + 1), Duration::hours(6));
+        assert_eq!(retry_delay(1000), Duration::hours(6));
+    }
+
+    #[test]
+    fn test_post_to_stand_in() {
+        let mut system = actix_rt::System::new("test");
+
+        let (url, bodies) = stand_in("204 No Content");
+        let body = render(None, &world_record());
+        assert_eq!(
+            post(&mut system, &ssl_connector().unwrap(), &url, body.clone()),
+            Ok(())
+        );
+        assert_eq!(bodies.recv().unwrap(), body);
+
+        let (url, bodies) = stand_in("503 Service Unavailable");
+        let error = post(
+            &mut system,
+            &ssl_connector().unwrap(),
+            &url,
+            String::from("{}"),
+        )
+        .unwrap_err();
+        assert!(error.contains("503"), "{}", error);
+        assert_eq!(bodies.recv().unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_post_over_tls() {
+        let mut system = actix_rt::System::new("test");
+        let (url, cert, bodies) = tls_stand_in("200 OK");
+        let body = render(None, &world_record());
+
+        // The self-signed certificate is refused by default
+        assert!(post(&mut system, &ssl_connector().unwrap(), &url, body.clone()).is_err());
+
+        let mut ssl = SslConnector::builder(SslMethod::tls()).unwrap();
+        ssl.cert_store_mut().add_cert(cert).unwrap();
+        assert_eq!(post(&mut system, &ssl.build(), &url, body.clone()), Ok(()));
+        assert_eq!(bodies.recv().unwrap(), body);
+    }
+
+    #[test]
+    fn test_post_unreachable() {
+        let mut system = actix_rt::System::new("test");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(post(
+            &mut system,
+            &ssl_connector().unwrap(),
+            &url,
+            String::from("{}")
+        )
+        .is_err());
+    }
+}
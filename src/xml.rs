@@ -1,26 +1,149 @@
+//! XML writer for the responses of the game routes.
+//!
+//! Every response is a `<response>` element holding one element per value,
+//! written element by element to an `io::Write`:
+//!
+//! ```xml
+//! <?xml version="1.0" encoding="UTF-8"?><response><records>...</records><records>...</records></response>
+//! ```
+//!
+//! Values are mapped with serde:
+//!
+//! - a struct is an element named after the struct (`#[serde(rename)]`), or
+//!   after the field holding it, with one child element per field
+//! - fields renamed with a leading `@` are written as attributes, they must
+//!   come before the other fields
+//! - a field renamed `$value` is written as the text of the element
+//! - a sequence field renamed `$items` is written as its elements, without
+//!   an element holding them
+//! - a sequence field is an element holding one element per item, named
+//!   after the item type (`value` for plain values)
+//! - `None` fields are left out
+//!
+//! The responses of the game routes are small, an overview has at most 50
+//! rows and a bulk submission 200 finishes, so they are written to a buffer
+//! and sent at once. The large exports are streamed by `export`.
+//!
+//! Text and attributes are escaped like `escape::Escape` does, except that
+//! the entities already present are kept: nicknames and map names are stored
+//! escaped, and the game mode expects them as they are.
+
 use actix_web::HttpResponse;
-use serde::Serialize;
+use serde::ser::{self, Impossible, Serialize};
+use std::fmt;
+use std::io::{self, Write};
 
-pub fn to_string<T>(elements: Vec<T>) -> String
-where
-    T: Serialize,
-{
-    let mut result = String::with_capacity(59);
-    result.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
-    result.push_str("<response>");
+const DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
 
-    if !elements.is_empty() {
-        let first_serialized = serde_xml_rs::to_string(elements.first().unwrap()).unwrap();
-        // Reserve the right size in advance, the parameter is additional len so we add to add the </response> too
-        result.reserve(first_serialized.len() * elements.len() + 11);
+/// Name of the elements of values which are not structs.
+const VALUE_ELEMENT: &str = "value";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Unsupported(&'static str),
+    Custom(String),
+}
 
-        for record in elements {
-            result.push_str(&serde_xml_rs::to_string(&record).unwrap());
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "could not write xml: {}", e),
+            Error::Unsupported(what) => write!(f, "{} cannot be written as xml", what),
+            Error::Custom(msg) => f.write_str(msg),
         }
     }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Writes a `<response>` document element by element.
+pub struct Writer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the declaration and opens the `<response>` element.
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(DECLARATION.as_bytes())?;
+        out.write_all(b"<response>")?;
+        Ok(Writer { out })
+    }
+
+    pub fn write<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        write_element(&mut self.out, value)
+    }
+
+    /// Writes a value as an element with the given name instead of the one of
+    /// its type.
+    pub fn write_named<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<()> {
+        value.serialize(ElementSerializer {
+            out: &mut self.out,
+            name: Some(name),
+        })
+    }
+
+    /// Closes the `<response>` element and gives the output back.
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(b"</response>")?;
+        Ok(self.out)
+    }
+}
+
+/// Writes a whole response holding the elements.
+pub fn to_writer<W, I>(out: W, elements: I) -> Result<W>
+where
+    W: Write,
+    I: IntoIterator,
+    I::Item: Serialize,
+{
+    let mut writer = Writer::new(out)?;
+    for element in elements {
+        writer.write(&element)?;
+    }
+    writer.finish()
+}
+
+pub fn to_string<I>(elements: I) -> Result<String>
+where
+    I: IntoIterator,
+    I::Item: Serialize,
+{
+    let bytes = to_writer(Vec::new(), elements)?;
+    Ok(String::from_utf8(bytes).expect("the xml writer only writes utf-8"))
+}
+
+/// Writes a single value as an element, without declaration.
+pub fn write_element<W: Write, T: Serialize + ?Sized>(out: &mut W, value: &T) -> Result<()> {
+    value.serialize(ElementSerializer { out, name: None })
+}
 
-    result.push_str("</response>");
-    result
+pub fn element_to_string<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut bytes = Vec::new();
+    write_element(&mut bytes, value)?;
+    Ok(String::from_utf8(bytes).expect("the xml writer only writes utf-8"))
+}
+
+/// Writes a whole document whose root element is the value, for the
+/// documents which are not responses of the game routes.
+pub fn document_to_string<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut bytes = DECLARATION.as_bytes().to_vec();
+    write_element(&mut bytes, value)?;
+    Ok(String::from_utf8(bytes).expect("the xml writer only writes utf-8"))
 }
 
 pub fn xml_response(body: String) -> HttpResponse {
@@ -28,3 +151,886 @@ pub fn xml_response(body: String) -> HttpResponse {
         .content_type("application/xml; charset=utf-8")
         .body(&body)
 }
+
+/// Writes the text escaped, keeping the entities already there.
+fn write_escaped<W: Write>(out: &mut W, text: &str) -> Result<()> {
+    let bytes = text.as_bytes();
+    let mut last = 0;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escaped: &[u8] = match byte {
+            b'<' => b"&lt;",
+            b'>' => b"&gt;",
+            b'\'' => b"&#39;",
+            b'"' => b"&quot;",
+            b'&' if !starts_with_entity(&text[i..]) => b"&amp;",
+            _ => continue,
+        };
+        out.write_all(&bytes[last..i])?;
+        out.write_all(escaped)?;
+        last = i + 1;
+    }
+
+    out.write_all(&bytes[last..])?;
+    Ok(())
+}
+
+/// Whether the text starts with a named or numeric character reference.
+fn starts_with_entity(text: &str) -> bool {
+    let end = match text.find(';') {
+        Some(end) => end,
+        None => return false,
+    };
+    let name = &text[1..end];
+
+    match name.as_bytes().first() {
+        Some(b'#') => {
+            let digits = &name[1..];
+            match digits
+                .strip_prefix('x')
+                .or_else(|| digits.strip_prefix('X'))
+            {
+                Some(hex) => !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()),
+                None => !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()),
+            }
+        }
+        Some(_) => matches!(name, "amp" | "lt" | "gt" | "quot" | "apos"),
+        None => false,
+    }
+}
+
+/// Serializes a value as an element named `name`, or after the type of the
+/// value when there is no name.
+struct ElementSerializer<'a, W: Write> {
+    out: &'a mut W,
+    name: Option<&'a str>,
+}
+
+impl<'a, W: Write> ElementSerializer<'a, W> {
+    fn write_text(self, text: &str) -> Result<()> {
+        let name = self.name.unwrap_or(VALUE_ELEMENT);
+        write!(self.out, "<{}>", name)?;
+        write_escaped(self.out, text)?;
+        write!(self.out, "</{}>", name)?;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::Serializer for ElementSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = SeqSerializer<'a, W>;
+    type SerializeTuple = SeqSerializer<'a, W>;
+    type SerializeTupleStruct = SeqSerializer<'a, W>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = MapSerializer<'a, W>;
+    type SerializeStruct = StructSerializer<'a, W>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.write_text(if v { "true" } else { "false" })
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.write_text(&v.to_string())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.write_text(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_text(v)
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<()> {
+        Err(Error::Unsupported("bytes"))
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.write_text("")
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<()> {
+        let name = self.name.unwrap_or(name);
+        write!(self.out, "<{}></{}>", name, name)?;
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.write_text(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()> {
+        Err(Error::Unsupported("enum variant with data"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        if let Some(name) = self.name {
+            write!(self.out, "<{}>", name)?;
+        }
+        Ok(SeqSerializer {
+            out: self.out,
+            name: self.name,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::Unsupported("enum variant with data"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        let name = self.name.ok_or(Error::Unsupported("map without a name"))?;
+        write!(self.out, "<{}>", name)?;
+        Ok(MapSerializer {
+            out: self.out,
+            name,
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        let name = self.name.unwrap_or(name);
+        write!(self.out, "<{}", name)?;
+        Ok(StructSerializer {
+            out: self.out,
+            name,
+            start_tag_open: true,
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::Unsupported("enum variant with data"))
+    }
+}
+
+struct SeqSerializer<'a, W: Write> {
+    out: &'a mut W,
+    name: Option<&'a str>,
+}
+
+impl<'a, W: Write> SeqSerializer<'a, W> {
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(ElementSerializer {
+            out: &mut *self.out,
+            name: None,
+        })
+    }
+
+    fn close(self) -> Result<()> {
+        if let Some(name) = self.name {
+            write!(self.out, "</{}>", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::SerializeSeq for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTuple for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTupleStruct for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.close()
+    }
+}
+
+struct MapSerializer<'a, W: Write> {
+    out: &'a mut W,
+    name: &'a str,
+    key: Option<String>,
+}
+
+impl<'a, W: Write> ser::SerializeMap for MapSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(
+            key.serialize(TextSerializer)?
+                .ok_or(Error::Unsupported("empty map key"))?,
+        );
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .key
+            .take()
+            .ok_or(Error::Unsupported("map value without key"))?;
+        value.serialize(ElementSerializer {
+            out: &mut *self.out,
+            name: Some(&key),
+        })
+    }
+
+    fn end(self) -> Result<()> {
+        write!(self.out, "</{}>", self.name)?;
+        Ok(())
+    }
+}
+
+struct StructSerializer<'a, W: Write> {
+    out: &'a mut W,
+    name: &'a str,
+    /// The attributes can still be written
+    start_tag_open: bool,
+}
+
+impl<'a, W: Write> StructSerializer<'a, W> {
+    fn close_start_tag(&mut self) -> Result<()> {
+        if self.start_tag_open {
+            self.out.write_all(b">")?;
+            self.start_tag_open = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::SerializeStruct for StructSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        if let Some(attribute) = key.strip_prefix('@') {
+            if !self.start_tag_open {
+                return Err(Error::Custom(format!(
+                    "attribute {} of {} comes after its elements",
+                    attribute, self.name
+                )));
+            }
+            if let Some(text) = value.serialize(TextSerializer)? {
+                write!(self.out, " {}=\"", attribute)?;
+                write_escaped(self.out, &text)?;
+                self.out.write_all(b"\"")?;
+            }
+            return Ok(());
+        }
+
+        self.close_start_tag()?;
+
+        if key == "$value" {
+            if let Some(text) = value.serialize(TextSerializer)? {
+                write_escaped(self.out, &text)?;
+            }
+            Ok(())
+        } else if key == "$items" {
+            value.serialize(ElementSerializer {
+                out: &mut *self.out,
+                name: None,
+            })
+        } else {
+            value.serialize(ElementSerializer {
+                out: &mut *self.out,
+                name: Some(key),
+            })
+        }
+    }
+
+    fn skip_field(&mut self, _key: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<()> {
+        self.close_start_tag()?;
+        write!(self.out, "</{}>", self.name)?;
+        Ok(())
+    }
+}
+
+/// Serializes plain values as text, for attributes and text content.
+/// `None` gives no text.
+struct TextSerializer;
+
+impl TextSerializer {
+    fn text<T: ToString>(v: T) -> Result<Option<String>> {
+        Ok(Some(v.to_string()))
+    }
+}
+
+impl ser::Serializer for TextSerializer {
+    type Ok = Option<String>;
+    type Error = Error;
+    type SerializeSeq = Impossible<Option<String>, Error>;
+    type SerializeTuple = Impossible<Option<String>, Error>;
+    type SerializeTupleStruct = Impossible<Option<String>, Error>;
+    type SerializeTupleVariant = Impossible<Option<String>, Error>;
+    type SerializeMap = Impossible<Option<String>, Error>;
+    type SerializeStruct = Impossible<Option<String>, Error>;
+    type SerializeStructVariant = Impossible<Option<String>, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Option<String>> {
+        Self::text(v)
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Option<String>> {
+        Err(Error::Unsupported("bytes"))
+    }
+
+    fn serialize_none(self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Option<String>> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Option<String>> {
+        Self::text("")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Option<String>> {
+        Self::text("")
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Option<String>> {
+        Self::text(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Option<String>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Option<String>> {
+        Err(Error::Unsupported("enum variant with data in text"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::Unsupported("sequence in text"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(Error::Unsupported("tuple in text"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(Error::Unsupported("tuple struct in text"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::Unsupported("enum variant with data in text"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::Unsupported("map in text"))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(Error::Unsupported("struct in text"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::Unsupported("enum variant with data in text"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use crate::game::{self, HasFinishedResult};
+    use crate::models::medal::Medal;
+    use crate::models::record::{OverviewRecord, RankedRecord};
+    use crate::records_api::Overview;
+    use serde_derive::Serialize;
+
+    fn ranked_records() -> Vec<RankedRecord> {
+        vec![
+            RankedRecord {
+                rank: 1,
+                player_id: String::from("smokegun"),
+                nickname: String::from("$f00Smoke &amp; $fffGun"),
+                time: 61234,
+            },
+            RankedRecord {
+                rank: 2,
+                player_id: String::from("encht"),
+                nickname: String::from("&lt;encht&gt;"),
+                time: 61500,
+            },
+            RankedRecord {
+                rank: 2,
+                player_id: String::from("xxel94toonzxx"),
+                nickname: String::from("toon"),
+                time: 61500,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_golden_overview() {
+        assert_eq!(
+            to_string(ranked_records()).unwrap(),
+            include_str!("../tests/golden/overview.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_overview_empty() {
+        assert_eq!(
+            to_string(Vec::<RankedRecord>::new()).unwrap(),
+            include_str!("../tests/golden/overview_empty.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_overview_with_total() {
+        let records = ranked_records()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, record)| OverviewRecord::new(record, idx == 1))
+            .collect();
+        let overview = Overview { records, total: 42 };
+        assert_eq!(
+            game::overview_to_xml(&overview).unwrap(),
+            include_str!("../tests/golden/overview_total.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_has_finished() {
+        let result = HasFinishedResult {
+            is_new_best: true,
+            login: String::from("smokegun"),
+            old: 62000,
+            new: 61234,
+            medal: None,
+            zone: None,
+            zone_rank: None,
+            replayed: false,
+        };
+        assert_eq!(
+            element_to_string(&result).unwrap(),
+            include_str!("../tests/golden/has_finished.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_has_finished_medal() {
+        let result = HasFinishedResult {
+            is_new_best: true,
+            login: String::from("smokegun"),
+            old: 62000,
+            new: 31234,
+            medal: Some(Medal::Gold),
+            zone: None,
+            zone_rank: None,
+            replayed: false,
+        };
+        assert_eq!(
+            element_to_string(&result).unwrap(),
+            include_str!("../tests/golden/has_finished_medal.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_has_finished_zone() {
+        let result = HasFinishedResult {
+            is_new_best: false,
+            login: String::from("smokegun"),
+            old: 61234,
+            new: 62000,
+            medal: None,
+            zone: Some(String::from("World|Europe|France")),
+            zone_rank: Some(3),
+            replayed: false,
+        };
+        assert_eq!(
+            element_to_string(&result).unwrap(),
+            include_str!("../tests/golden/has_finished_zone.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_finishes() {
+        let results = vec![
+            HasFinishedResult {
+                is_new_best: true,
+                login: String::from("smokegun"),
+                old: 62000,
+                new: 61234,
+                medal: None,
+                zone: None,
+                zone_rank: None,
+                replayed: false,
+            },
+            HasFinishedResult {
+                is_new_best: false,
+                login: String::from("encht"),
+                old: 61500,
+                new: 63000,
+                medal: None,
+                zone: None,
+                zone_rank: None,
+                replayed: false,
+            },
+        ];
+        assert_eq!(
+            game::finishes_to_xml(&results).unwrap(),
+            include_str!("../tests/golden/finishes.xml")
+        );
+    }
+
+    #[test]
+    fn test_golden_error() {
+        let err = ApiError::invalid_map("Invalid map id: \"\"");
+        assert_eq!(err.to_xml(), include_str!("../tests/golden/error.xml"));
+    }
+
+    #[test]
+    fn test_golden_ok() {
+        #[derive(Serialize)]
+        #[serde(rename = "response")]
+        struct OkResult {
+            id: &'static str,
+        }
+
+        assert_eq!(
+            element_to_string(&OkResult { id: "ok" }).unwrap(),
+            include_str!("../tests/golden/ok.xml")
+        );
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for record in ranked_records() {
+            writer.write(&record).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            include_str!("../tests/golden/overview.xml")
+        );
+    }
+
+    #[test]
+    fn test_escaping() {
+        #[derive(Serialize)]
+        #[serde(rename = "map")]
+        struct Map {
+            name: &'static str,
+        }
+
+        assert_eq!(
+            element_to_string(&Map {
+                name: "<Ice> & \"Snow\" &amp; &#36;&#x24; &nope; &",
+            })
+            .unwrap(),
+            "<map><name>&lt;Ice&gt; &amp; &quot;Snow&quot; &amp; &#36;&#x24; &amp;nope; &amp;</name></map>"
+        );
+    }
+
+    #[test]
+    fn test_attributes_and_text() {
+        #[derive(Serialize)]
+        #[serde(rename = "medal")]
+        struct Medal {
+            #[serde(rename = "@kind")]
+            kind: &'static str,
+            #[serde(rename = "@rank")]
+            rank: Option<u32>,
+            #[serde(rename = "$value")]
+            time: i32,
+        }
+
+        assert_eq!(
+            element_to_string(&Medal {
+                kind: "gold\"",
+                rank: None,
+                time: 42000,
+            })
+            .unwrap(),
+            "<medal kind=\"gold&quot;\">42000</medal>"
+        );
+    }
+
+    #[test]
+    fn test_nested_collections() {
+        #[derive(Serialize)]
+        #[serde(rename = "player")]
+        struct Player {
+            login: &'static str,
+        }
+
+        #[derive(Serialize)]
+        #[serde(rename = "map")]
+        struct Map {
+            #[serde(rename = "@id")]
+            id: &'static str,
+            players: Vec<Player>,
+            times: Vec<i32>,
+            author: Option<Player>,
+        }
+
+        let map = Map {
+            id: "abc",
+            players: vec![Player { login: "a" }, Player { login: "b" }],
+            times: vec![1, 2],
+            author: Some(Player { login: "c" }),
+        };
+        assert_eq!(
+            element_to_string(&map).unwrap(),
+            "<map id=\"abc\"><players><player><login>a</login></player>\
+             <player><login>b</login></player></players>\
+             <times><value>1</value><value>2</value></times>\
+             <author><login>c</login></author></map>"
+        );
+    }
+
+    #[test]
+    fn test_items_without_wrapper() {
+        #[derive(Serialize)]
+        #[serde(rename = "player")]
+        struct Player {
+            login: &'static str,
+        }
+
+        #[derive(Serialize)]
+        #[serde(rename = "team")]
+        struct Team {
+            #[serde(rename = "@xmlns")]
+            xmlns: &'static str,
+            name: &'static str,
+            #[serde(rename = "$items")]
+            players: Vec<Player>,
+        }
+
+        let team = Team {
+            xmlns: "urn:team",
+            name: "a & b",
+            players: vec![Player { login: "a" }, Player { login: "b" }],
+        };
+        assert_eq!(
+            document_to_string(&team).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><team xmlns=\"urn:team\">\
+             <name>a &amp; b</name><player><login>a</login></player>\
+             <player><login>b</login></player></team>"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        #[derive(Serialize)]
+        #[serde(rename = "late")]
+        struct Late {
+            a: i32,
+            #[serde(rename = "@b")]
+            b: i32,
+        }
+
+        assert!(element_to_string(&Late { a: 1, b: 2 }).is_err());
+        assert!(element_to_string(&[1u8, 2, 3][..]).is_ok());
+        assert!(element_to_string(&Bytes).is_err());
+    }
+
+    struct Bytes;
+
+    impl Serialize for Bytes {
+        fn serialize<S: ser::Serializer>(
+            &self,
+            serializer: S,
+        ) -> std::result::Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(b"raw")
+        }
+    }
+}